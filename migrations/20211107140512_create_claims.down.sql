@@ -0,0 +1,3 @@
+-- Add down migration script here
+DROP TABLE resources;
+DROP TABLE user_claims
\ No newline at end of file
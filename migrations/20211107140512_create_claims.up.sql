@@ -0,0 +1,13 @@
+-- Add up migration script here
+CREATE TABLE user_claims (
+   user_hex TEXT NOT NULL REFERENCES user_auth(user_hex) ON DELETE CASCADE,
+   origin TEXT NOT NULL CHECK(LENGTH(origin) < 1000),
+   anphd_id TEXT NOT NULL CHECK(LENGTH(anphd_id) < 1000),
+   uuid TEXT NOT NULL CHECK(LENGTH(uuid) < 1000),
+   permission INTEGER NOT NULL CHECK(permission >= 0 AND permission <= 65535),
+   PRIMARY KEY (user_hex, origin, anphd_id)
+);
+
+CREATE TABLE resources (
+   resource_id TEXT PRIMARY KEY NOT NULL CHECK(LENGTH(resource_id) < 2000)
+)
\ No newline at end of file
@@ -0,0 +1,8 @@
+-- Add up migration script here
+CREATE TABLE signing_keys (
+   kid TEXT PRIMARY KEY NOT NULL CHECK(LENGTH(kid) < 100),
+   secret_hex TEXT NOT NULL CHECK(LENGTH(secret_hex) == 64),
+   public_hex TEXT NOT NULL CHECK(LENGTH(public_hex) == 64),
+   created INTEGER NOT NULL,
+   expires INTEGER
+)
@@ -0,0 +1,25 @@
+-- Add down migration script here
+-- Only possible if every password hash is still a client hash.
+CREATE TABLE user_auth_old (
+   user_hex TEXT PRIMARY KEY NOT NULL CHECK(LENGTH(user_hex) < 1000),
+   password_hash_hex TEXT NOT NULL CHECK(LENGTH(password_hash_hex) == 64),
+   salt_hex TEXT NOT NULL CHECK(LENGTH(salt_hex) == 32),
+   secret_hex TEXT NOT NULL CHECK(LENGTH(secret_hex) < 1000),
+   public_hex TEXT NOT NULL CHECK(LENGTH(public_hex) == 64)
+);
+INSERT INTO user_auth_old SELECT user_hex, password_hash_hex, salt_hex, secret_hex, public_hex FROM user_auth;
+
+CREATE TABLE user_claims_old (
+   user_hex TEXT NOT NULL REFERENCES user_auth_old(user_hex) ON DELETE CASCADE,
+   origin TEXT NOT NULL CHECK(LENGTH(origin) < 1000),
+   anphd_id TEXT NOT NULL CHECK(LENGTH(anphd_id) < 1000),
+   uuid TEXT NOT NULL CHECK(LENGTH(uuid) < 1000),
+   permission INTEGER NOT NULL CHECK(permission >= 0 AND permission <= 65535),
+   PRIMARY KEY (user_hex, origin, anphd_id)
+);
+INSERT INTO user_claims_old SELECT user_hex, origin, anphd_id, uuid, permission FROM user_claims ORDER BY rowid;
+
+DROP TABLE user_claims;
+DROP TABLE user_auth;
+ALTER TABLE user_auth_old RENAME TO user_auth;
+ALTER TABLE user_claims_old RENAME TO user_claims
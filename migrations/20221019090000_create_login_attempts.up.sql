@@ -0,0 +1,7 @@
+-- Add up migration script here
+CREATE TABLE login_attempts (
+   attempt_key TEXT PRIMARY KEY NOT NULL CHECK(LENGTH(attempt_key) < 1100),
+   failures INTEGER NOT NULL CHECK(failures >= 0),
+   last_failure INTEGER NOT NULL,
+   locked_until INTEGER NOT NULL
+)
@@ -0,0 +1,10 @@
+-- Add up migration script here
+CREATE TABLE refresh_tokens (
+   token_hash TEXT PRIMARY KEY NOT NULL CHECK(LENGTH(token_hash) == 64),
+   family_id TEXT NOT NULL CHECK(LENGTH(family_id) < 100),
+   user_hex TEXT NOT NULL CHECK(LENGTH(user_hex) < 1000),
+   issued INTEGER NOT NULL,
+   expires INTEGER NOT NULL,
+   used INTEGER NOT NULL CHECK(used IN (0, 1))
+);
+CREATE INDEX refresh_tokens_family ON refresh_tokens (family_id)
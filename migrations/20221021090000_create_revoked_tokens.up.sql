@@ -0,0 +1,5 @@
+-- Add up migration script here
+CREATE TABLE revoked_tokens (
+   jti TEXT PRIMARY KEY NOT NULL CHECK(LENGTH(jti) < 1000),
+   expires INTEGER NOT NULL
+)
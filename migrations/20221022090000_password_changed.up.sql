@@ -0,0 +1,3 @@
+-- Add up migration script here
+ALTER TABLE user_auth ADD COLUMN password_changed INTEGER;
+ALTER TABLE user_auth ADD COLUMN tokens_not_before INTEGER
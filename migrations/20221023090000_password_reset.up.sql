@@ -0,0 +1,12 @@
+-- Add up migration script here
+ALTER TABLE user_auth ADD COLUMN contact TEXT CHECK(LENGTH(contact) < 1000);
+ALTER TABLE user_auth ADD COLUMN contact_verified INTEGER NOT NULL DEFAULT 0 CHECK(contact_verified IN (0, 1));
+
+CREATE TABLE mail_tokens (
+   token_hash TEXT PRIMARY KEY NOT NULL CHECK(LENGTH(token_hash) == 64),
+   user_hex TEXT NOT NULL CHECK(LENGTH(user_hex) < 1000),
+   purpose TEXT NOT NULL CHECK(purpose IN ('verify_contact', 'password_reset')),
+   contact TEXT NOT NULL CHECK(LENGTH(contact) < 1000),
+   issued INTEGER NOT NULL,
+   expires INTEGER NOT NULL
+)
@@ -0,0 +1,2 @@
+-- Add down migration script here
+DROP TABLE salt_secret
\ No newline at end of file
@@ -0,0 +1,5 @@
+-- Add up migration script here
+CREATE TABLE salt_secret (
+   id INTEGER PRIMARY KEY NOT NULL CHECK(id == 1),
+   secret_hex TEXT NOT NULL CHECK(LENGTH(secret_hex) < 1000)
+)
\ No newline at end of file
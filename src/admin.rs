@@ -0,0 +1,93 @@
+//! The admin token, which authorizes administrative endpoints such as `/revoke`. It is sent as
+//! `Authorization: Bearer <admin token>`. Without a configured admin token these endpoints
+//! reject every request.
+
+use std::env;
+use std::sync::Arc;
+
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use warp::Filter;
+
+use crate::config::Config;
+use crate::error::{Error, ErrorExt, ErrorReject, Errors, RejectTypes};
+use crate::reject;
+
+/// Environment variable holding the admin token, it takes precedence over `admin_token_file`.
+pub const ADMIN_TOKEN_VAR: &str = "TIAUTH_ADMIN_TOKEN";
+
+/// Shortest admin token that is accepted.
+const MIN_TOKEN_LEN: usize = 32;
+
+pub struct AdminToken {
+    hash: [u8; 32],
+}
+
+impl AdminToken {
+    pub fn new(token: &str) -> Result<Self, Error> {
+        let token = token.trim();
+        if token.len() < MIN_TOKEN_LEN {
+            return Err(Error {
+                message: "Admin token must be at least 32 characters!",
+                error_type: Errors::DecodeInternal,
+                e: format!("length: {}", token.len())
+            })
+        }
+
+        Ok(AdminToken { hash: Sha256::digest(token.as_bytes()).into() })
+    }
+
+    pub fn from_file(path: &std::path::Path) -> Result<Self, Error> {
+        let token = std::fs::read_to_string(path)
+            .repl("Error reading admin token file!")?;
+
+        AdminToken::new(&token)
+    }
+
+    /// Loads the admin token from `TIAUTH_ADMIN_TOKEN` or else from the configured file, if any.
+    pub fn from_config(config: &Config) -> Result<Option<Self>, Error> {
+        if let Some(token) = env::var(ADMIN_TOKEN_VAR).ok().filter(|v| !v.is_empty()) {
+            return AdminToken::new(&token).map(Some)
+        }
+
+        config.admin_token_file.as_deref().map(AdminToken::from_file).transpose()
+    }
+
+    /// Compares in constant time, by hash so that the length of the token does not leak either.
+    pub fn matches(&self, token: &str) -> bool {
+        let hash = Sha256::digest(token.as_bytes());
+
+        self.hash.ct_eq(hash.as_slice()).into()
+    }
+}
+
+pub type AdminRef = Arc<Option<AdminToken>>;
+
+fn bearer_token(authorization: &str) -> Option<&str> {
+    let (scheme, token) = authorization.split_once(' ')?;
+
+    if scheme.eq_ignore_ascii_case("bearer") { Some(token.trim()) } else { None }
+}
+
+/// Filter that rejects requests that do not carry the admin token.
+pub fn require_admin(admin: AdminRef) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and_then(move |authorization: Option<String>| {
+            let admin = admin.clone();
+            async move {
+                let authorized = match (admin.as_ref(), authorization.as_deref().and_then(bearer_token)) {
+                    (Some(admin_token), Some(token)) => admin_token.matches(token),
+                    _ => false
+                };
+                if authorized {
+                    Ok(())
+                }
+                else {
+                    Err(reject(ErrorReject { rt: RejectTypes::Permission,
+                        msg: "Admin token is missing or incorrect!",
+                        e: "".to_owned() }))
+                }
+            }
+        })
+        .untuple_one()
+}
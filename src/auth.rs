@@ -1,13 +1,69 @@
-use crate::files;
+use std::time::SystemTime;
+
+use crate::store::{store_reject, StoreErrors, StoreRef};
+use crate::config::Config;
+use crate::defs::SaveUserJson;
 use crate::error::{ErrorReject, RejectTypes};
+use crate::jwt::{self, JwtClaims, JwtHeader};
+use crate::keys::Keyring;
 use ed25519_dalek::{PublicKey, Verifier};
 use ed25519_dalek::ed25519::signature::Signature;
 use crate::reject;
 
-pub async fn verify_jwt(user_hex: &str, jwt: &str) -> Result<(), warp::Rejection> {
-    let save_user = files::read_user(user_hex, false).await
-        .map_err(|e| { files::io_nonexistent_reject(e, "Error reading user data (verify jwt)",
-                                                    "User does not exist! (verify jwt)") })?;
+/// What a token is checked against besides its signature.
+pub struct Validation<'a> {
+    /// If set, the `iss` claim must be equal to it.
+    pub issuer: Option<&'a str>,
+    /// Seconds of clock difference tolerated when checking `exp` and `nbf`.
+    pub leeway: u64,
+    /// Whether tokens signed with the key of the user itself are accepted.
+    pub user_keys: bool,
+}
+
+impl<'a> Validation<'a> {
+    pub fn from_config(config: &'a Config) -> Self {
+        Validation {
+            issuer: Some(&config.issuer),
+            leeway: config.clock_skew,
+            user_keys: config.accept_user_keys || config.sign_with_user_keys
+        }
+    }
+}
+
+/// The time to date tokens of the user that are issued at `now`. Tokens dated at or before
+/// `tokens_not_before` are rejected, so tokens issued in the same second as the user ended its
+/// sessions are dated just after it.
+pub fn issue_time(save_user: &SaveUserJson, now: u64) -> u64 {
+    match save_user.tokens_not_before {
+        Some(not_before) => now.max(not_before.saturating_add(1)),
+        None => now
+    }
+}
+
+/// Current unix time in seconds.
+pub fn unix_now() -> Result<u64, warp::Rejection> {
+    Ok(SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)
+        .map_err(|e| { reject(ErrorReject { rt: RejectTypes::Internal,
+            msg: "Error calculating system time",
+            e: e.to_string()
+        }) })?.as_secs())
+}
+
+fn decode_segment<T: serde::de::DeserializeOwned>(segment: &str, msg: &'static str) -> Result<T, warp::Rejection> {
+    let bytes = base64_url::decode(segment)
+        .map_err(|e| { reject(ErrorReject { rt: RejectTypes::DecodeExternal, msg,
+            e: e.to_string()}) })?;
+
+    serde_json::from_slice(&bytes)
+        .map_err(|e| { reject(ErrorReject { rt: RejectTypes::DecodeExternal, msg,
+            e: e.to_string()}) })
+}
+
+/// The public key of `user_hex`, if its key ID is `kid`.
+async fn user_public_key(store: &StoreRef, user_hex: &str, kid: &str) -> Result<PublicKey, warp::Rejection> {
+    let save_user = store.read_user(user_hex, false).await
+        .map_err(|e| { store_reject(e, "Error reading user data (verify jwt)",
+                                    "User does not exist! (verify jwt)") })?;
     let public_key = hex::decode(&save_user.public_hex)
         .map_err(|e| { reject(ErrorReject { rt: RejectTypes::DecodeInternal,
             msg: "Error decoding public key hex! (verify jwt)",
@@ -17,11 +73,48 @@ pub async fn verify_jwt(user_hex: &str, jwt: &str) -> Result<(), warp::Rejection
             msg: "Error creating public key object from bytes! (verify jwt)",
             e: e.to_string()}) })?;
 
-    let jwt_msg: String = jwt.split('.').take(2).collect::<Vec<&str>>().join(".");
+    if jwt::key_id(&public_key) != kid {
+        return Err(reject(ErrorReject { rt: RejectTypes::Tampered,
+            msg: "Rejected verification: jwt was signed by an unknown key!",
+            e: format!("kid: {}", kid) }))
+    }
+
+    Ok(public_key)
+}
+
+/// Verifies that `jwt` is an EdDSA token about `user_hex`, signed with a key of the keyring (or
+/// with the key of the user, if `validation` allows it), that it is valid at this moment
+/// according to its `exp` and `nbf` claims, that it matches `validation`, that it was not
+/// revoked and that its user still exists. Returns the claims of the token.
+pub async fn verify_jwt(store: &StoreRef, keyring: &Keyring, user_hex: &str, jwt: &str,
+                        validation: &Validation<'_>) -> Result<JwtClaims, warp::Rejection> {
+    let (header_b64url, payload_b64url, signature_b64url) = jwt::split(jwt)
+        .ok_or_else(|| { reject(ErrorReject { rt: RejectTypes::DecodeExternal,
+            msg: "Malformed jwt: expected three segments! (verify jwt)",
+            e: "".to_owned()}) })?;
 
-    let signature_b64url = jwt.split('.').skip(2).collect::<String>();
+    let header: JwtHeader = decode_segment(header_b64url, "Error decoding jwt header (verify jwt)")?;
+    if header.alg != jwt::ALG_EDDSA {
+        let appendix = format!("@@@alg: {}@@@", header.alg);
+        return Err(reject(ErrorReject { rt: RejectTypes::Tampered,
+            msg: "Rejected verification: unsupported jwt algorithm!",
+            e: appendix }))
+    }
 
-    let signature_dec = base64_url::decode(&signature_b64url)
+    let kid = header.kid.as_deref()
+        .ok_or_else(|| { reject(ErrorReject { rt: RejectTypes::Tampered,
+            msg: "Rejected verification: jwt has no key ID!",
+            e: "".to_owned()}) })?;
+    let now = unix_now()?;
+    let public_key = match keyring.verification_key(kid, now) {
+        Some(public_key) => public_key,
+        None if validation.user_keys => user_public_key(store, user_hex, kid).await?,
+        None => return Err(reject(ErrorReject { rt: RejectTypes::Tampered,
+            msg: "Rejected verification: jwt was signed by an unknown key!",
+            e: format!("kid: {}", kid) }))
+    };
+
+    let signature_dec = base64_url::decode(signature_b64url)
         .map_err(|e| { reject(ErrorReject { rt: RejectTypes::DecodeExternal,
             msg: "Error decoding jwt signature (verify jwt)",
             e: e.to_string()}) })?;
@@ -30,8 +123,72 @@ pub async fn verify_jwt(user_hex: &str, jwt: &str) -> Result<(), warp::Rejection
             msg: "Error creating signature object from jwt bytes! (verify jwt)",
             e: e.to_string()}) })?;
 
-    Ok(public_key.verify(jwt_msg.as_bytes(), &signature)
+    let jwt_msg = &jwt[..header_b64url.len() + 1 + payload_b64url.len()];
+    public_key.verify(jwt_msg.as_bytes(), &signature)
         .map_err(|e| { reject(ErrorReject { rt: RejectTypes::Tampered,
             msg: "Rejected verification: tampered or malformed jwt!",
-            e: e.to_string()}) })?)
-}
\ No newline at end of file
+            e: e.to_string()}) })?;
+
+    let claims: JwtClaims = decode_segment(payload_b64url, "Error decoding jwt payload (verify jwt)")?;
+    verify_claims(&claims, user_hex, validation, now)?;
+
+    let revoked = store.is_token_revoked(&claims.jti).await
+        .map_err(|e| { store_reject(e, "Error reading revoked tokens (verify jwt)",
+                                    "Error reading revoked tokens (verify jwt)") })?;
+    if revoked {
+        return Err(reject(ErrorReject { rt: RejectTypes::Permission,
+            msg: "Rejected verification: jwt was revoked!",
+            e: format!("jti: {}", claims.jti) }))
+    }
+
+    // Tokens of users that were removed, or issued before the user ended its sessions, e.g. by
+    // changing its password.
+    let not_before = match store.read_user(user_hex, false).await {
+        Ok(save_user) => save_user.tokens_not_before,
+        Err(e) if matches!(e.error_type, StoreErrors::NonExistent) => {
+            return Err(reject(ErrorReject { rt: RejectTypes::Permission,
+                msg: "Rejected verification: jwt is about a user that does not exist!",
+                e: format!("sub: {}", claims.sub) }))
+        }
+        Err(e) => return Err(reject(store_reject(e, "Error reading user data (verify jwt)",
+                                                 "User does not exist! (verify jwt)")))
+    };
+    if let Some(not_before) = not_before.filter(|not_before| claims.iat <= *not_before) {
+        return Err(reject(ErrorReject { rt: RejectTypes::Permission,
+            msg: "Rejected verification: jwt was revoked!",
+            e: format!("iat: {}, not before: {}", claims.iat, not_before) }))
+    }
+
+    Ok(claims)
+}
+
+fn verify_claims(claims: &JwtClaims, user_hex: &str, validation: &Validation,
+                 now: u64) -> Result<(), warp::Rejection> {
+    if now > claims.exp.saturating_add(validation.leeway) {
+        return Err(reject(ErrorReject { rt: RejectTypes::Expired,
+            msg: "Rejected verification: jwt has expired!",
+            e: format!("exp: {}, now: {}", claims.exp, now) }))
+    }
+    if now.saturating_add(validation.leeway) < claims.nbf {
+        return Err(reject(ErrorReject { rt: RejectTypes::Expired,
+            msg: "Rejected verification: jwt is not valid yet!",
+            e: format!("nbf: {}, now: {}", claims.nbf, now) }))
+    }
+
+    if let Some(expected_issuer) = validation.issuer {
+        if claims.iss != expected_issuer {
+            let appendix = format!("@@@iss: {}@@@", claims.iss);
+            return Err(reject(ErrorReject { rt: RejectTypes::Permission,
+                msg: "Rejected verification: jwt was issued by another issuer!",
+                e: appendix }))
+        }
+    }
+
+    if claims.sub != user_hex {
+        return Err(reject(ErrorReject { rt: RejectTypes::Permission,
+            msg: "Rejected verification: jwt is about another user!",
+            e: format!("sub: {}", claims.sub) }))
+    }
+
+    Ok(())
+}
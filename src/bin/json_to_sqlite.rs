@@ -0,0 +1,295 @@
+//! Imports a JSON resource directory into a SQLite database in a single transaction.
+//!
+//! Usage: `json_to_sqlite <resources dir> <sqlite url>`, e.g.
+//! `json_to_sqlite resources sqlite://db/tidb.sqlite`.
+//!
+//! Besides users, their claims and the resources, the server signing keys, the salt secret,
+//! revoked tokens, refresh tokens, failed login counters and mailed tokens are imported, so that
+//! issued tokens, sessions and the salts of unknown users stay the same after the switch.
+//!
+//! Every record is checked against the constraints of the SQLite schema before it is inserted,
+//! and users also against the rules for user hexes the server enforces. Records that do not pass,
+//! or that the database rejects with a constraint violation, are left out and listed in the report
+//! printed at the end. Any other database error aborts the import and nothing is committed.
+
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::process::exit;
+
+use tiauth::db::{self, SqliteStore};
+use tiauth::defs;
+use tiauth::files::FileStore;
+use tiauth::store::{Store, StoreError, StoreErrors};
+
+struct Rejected {
+    kind: &'static str,
+    id: String,
+    reason: String,
+}
+
+#[derive(Default)]
+struct Report {
+    users: usize,
+    claims: usize,
+    resources: usize,
+    signing_keys: usize,
+    salt_secrets: usize,
+    revoked_tokens: usize,
+    refresh_tokens: usize,
+    login_attempts: usize,
+    mail_tokens: usize,
+    rejected: Vec<Rejected>,
+}
+
+impl Report {
+    fn reject(&mut self, kind: &'static str, id: &str, reason: String) {
+        self.rejected.push(Rejected {
+            kind,
+            id: id.to_owned(),
+            reason
+        })
+    }
+
+    fn print(&self) {
+        println!("Imported {} users, {} claims and {} resources.", self.users, self.claims, self.resources);
+        println!("Imported {} signing keys, {} salt secrets, {} revoked tokens, {} refresh tokens, {} login counters and {} mail tokens.",
+                 self.signing_keys, self.salt_secrets, self.revoked_tokens, self.refresh_tokens, self.login_attempts, self.mail_tokens);
+        if self.rejected.is_empty() {
+            println!("No rows were rejected.");
+        }
+        else {
+            println!("Rejected {} rows:", self.rejected.len());
+            for r in &self.rejected {
+                println!("  {} {}: {}", r.kind, r.id, r.reason);
+            }
+        }
+    }
+}
+
+/// The reason a row was rejected if `e` is a constraint violation, which only leaves out that
+/// row. Any other error is returned, so that the import is aborted.
+fn constraint_violation(e: StoreError) -> Result<String, StoreError> {
+    match e.error_type {
+        StoreErrors::AlreadyExists | StoreErrors::Invalid | StoreErrors::NonExistent => Ok(e.to_string()),
+        _ => Err(e)
+    }
+}
+
+/// Checks the user hex like the server does and mirrors the CHECK constraints on `user_auth`.
+fn validate_user(save_user: &defs::SaveUserJson, user_hex: &str) -> Result<(), String> {
+    if save_user.user_hex != user_hex {
+        return Err(format!("user_hex '{}' does not match file name", save_user.user_hex))
+    }
+    defs::UserId::try_from(save_user.user_hex.clone())?;
+    let checks = [
+        ("password_hash_hex", save_user.password_hash_hex.len() < 1000, "be shorter than 1000"),
+        ("salt_hex", save_user.salt_hex.len() == 32, "have length 32"),
+        ("secret_hex", save_user.secret_hex.len() < 1000, "be shorter than 1000"),
+        ("public_hex", save_user.public_hex.len() == 64, "have length 64"),
+    ];
+    for (field, ok, requirement) in checks.iter() {
+        if !ok {
+            return Err(format!("{} must {}", field, requirement))
+        }
+    }
+
+    Ok(())
+}
+
+/// Mirrors the CHECK constraints on `user_claims`.
+fn validate_claim(claim: &defs::UserClaim) -> Result<(), String> {
+    let checks = [
+        ("origin", &claim.origin),
+        ("anphd_id", &claim.anphd_id),
+        ("uuid", &claim.uuid),
+    ];
+    for (field, value) in checks.iter() {
+        if value.len() >= 1000 {
+            return Err(format!("{} must be shorter than 1000", field))
+        }
+    }
+
+    Ok(())
+}
+
+async fn import(files: &FileStore, sqlite: &SqliteStore, report: &mut Report) -> Result<(), StoreError> {
+    // A database that already has signing keys would end up with two active keys.
+    let has_signing_keys = !sqlite.read_signing_keys().await?.is_empty();
+    let mut tx = sqlite.pool().begin().await?;
+    let mut imported_users = HashSet::new();
+
+    for user_hex in files.user_hexes().await? {
+        let save_user = match files.read_user(&user_hex, true).await {
+            Ok(save_user) => save_user,
+            Err(e) => {
+                report.reject("user", &user_hex, e.to_string());
+                continue
+            }
+        };
+        if let Err(reason) = validate_user(&save_user, &user_hex) {
+            report.reject("user", &user_hex, reason);
+            continue
+        }
+        match db::insert_user(&mut tx, &save_user).await {
+            Ok(()) => {
+                imported_users.insert(user_hex);
+                report.users += 1;
+            }
+            Err(e) => report.reject("user", &user_hex, constraint_violation(e)?),
+        }
+    }
+
+    for user_hex in files.claims_user_hexes().await? {
+        if !imported_users.contains(&user_hex) {
+            report.reject("claims", &user_hex, "no imported user for these claims".to_owned());
+            continue
+        }
+        let user_claims = match files.read_user_claims(&user_hex).await {
+            Ok(user_claims) => user_claims,
+            Err(e) => {
+                report.reject("claims", &user_hex, e.to_string());
+                continue
+            }
+        };
+        for claim in &user_claims.claims {
+            let id = format!("{} ({}:{})", user_hex, claim.origin, claim.anphd_id);
+            if let Err(reason) = validate_claim(claim) {
+                report.reject("claim", &id, reason);
+                continue
+            }
+            match db::insert_claim(&mut tx, &user_hex, claim).await {
+                Ok(()) => report.claims += 1,
+                Err(e) => report.reject("claim", &id, constraint_violation(e)?),
+            }
+        }
+    }
+
+    match files.read_resources().await {
+        Ok(resources) => {
+            for resource_id in &resources.resources {
+                if resource_id.len() >= 2000 {
+                    report.reject("resource", resource_id, "resource_id must be shorter than 2000".to_owned());
+                    continue
+                }
+                match db::insert_resource(&mut tx, resource_id).await {
+                    Ok(()) => report.resources += 1,
+                    Err(e) => report.reject("resource", resource_id, constraint_violation(e)?),
+                }
+            }
+        }
+        Err(e) => report.reject("resources", "resources.json", e.to_string()),
+    }
+
+    match files.read_signing_keys().await {
+        Ok(keys) => {
+            for key in &keys {
+                if has_signing_keys {
+                    report.reject("signing key", &key.kid, "the database already has signing keys".to_owned());
+                    continue
+                }
+                match db::insert_signing_key(&mut tx, key).await {
+                    Ok(()) => report.signing_keys += 1,
+                    Err(e) => report.reject("signing key", &key.kid, constraint_violation(e)?),
+                }
+            }
+        }
+        Err(e) => report.reject("signing keys", "signing_keys.json", e.to_string()),
+    }
+
+    match files.read_salt_secret().await {
+        Ok(Some(secret_hex)) => match db::insert_salt_secret(&mut tx, &secret_hex).await {
+            Ok(()) => report.salt_secrets += 1,
+            Err(e) => report.reject("salt secret", "salt_secret.json", constraint_violation(e)?),
+        },
+        Ok(None) => {}
+        Err(e) => report.reject("salt secret", "salt_secret.json", e.to_string()),
+    }
+
+    match files.read_revoked_tokens().await {
+        Ok(revoked) => {
+            for token in &revoked {
+                match db::insert_revoked_token(&mut tx, token).await {
+                    Ok(()) => report.revoked_tokens += 1,
+                    Err(e) => report.reject("revoked token", &token.jti, constraint_violation(e)?),
+                }
+            }
+        }
+        Err(e) => report.reject("revoked tokens", "revoked_tokens.json", e.to_string()),
+    }
+
+    for token_hash in files.refresh_token_hashes().await? {
+        let token = match files.read_refresh_token(&token_hash).await {
+            Ok(token) => token,
+            Err(e) => {
+                report.reject("refresh token", &token_hash, e.to_string());
+                continue
+            }
+        };
+        match db::insert_refresh_token(&mut tx, &token).await {
+            Ok(()) => report.refresh_tokens += 1,
+            Err(e) => report.reject("refresh token", &token_hash, constraint_violation(e)?),
+        }
+    }
+
+    for key in files.login_attempt_keys().await? {
+        let attempts = match files.read_login_attempts(&key).await {
+            Ok(attempts) => attempts,
+            Err(e) => {
+                report.reject("login counter", &key, e.to_string());
+                continue
+            }
+        };
+        match db::insert_login_attempts(&mut tx, &key, &attempts).await {
+            Ok(()) => report.login_attempts += 1,
+            Err(e) => report.reject("login counter", &key, constraint_violation(e)?),
+        }
+    }
+
+    for token_hash in files.mail_token_hashes().await? {
+        let token = match files.read_mail_token(&token_hash).await {
+            Ok(token) => token,
+            Err(e) => {
+                report.reject("mail token", &token_hash, e.to_string());
+                continue
+            }
+        };
+        match db::insert_mail_token(&mut tx, &token).await {
+            Ok(()) => report.mail_tokens += 1,
+            Err(e) => report.reject("mail token", &token_hash, constraint_violation(e)?),
+        }
+    }
+
+    tx.commit().await?;
+
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() != 3 {
+        eprintln!("Usage: {} <resources dir> <sqlite url>", args[0]);
+        exit(2);
+    }
+
+    let files = FileStore::new(&args[1]);
+    let sqlite = match SqliteStore::connect(&args[2]).await {
+        Ok(sqlite) => sqlite,
+        Err(e) => {
+            eprintln!("Failed to connect to database: {}", e);
+            exit(1);
+        }
+    };
+    if let Err(e) = sqlite.prepare().await {
+        eprintln!("Failed to apply migrations: {}", e);
+        exit(1);
+    }
+
+    let mut report = Report::default();
+    if let Err(e) = import(&files, &sqlite, &mut report).await {
+        eprintln!("Import failed, nothing was written: {}", e);
+        exit(1);
+    }
+
+    report.print();
+}
@@ -0,0 +1,52 @@
+//! Re-encrypts the stored secret keys with a new master key.
+//!
+//! Usage: `rotate_master_key <new master key file>`, with the current master key (if any) and the
+//! store configured as for the server. Secrets that are not encrypted yet are encrypted with the
+//! new key. Stop the server first and configure the new key before starting it again. An
+//! interrupted rotation can be completed by running the command again.
+//!
+//! `rotate_master_key --generate` prints a new random master key.
+
+use std::path::Path;
+use std::process::exit;
+
+use tiauth::config::Config;
+use tiauth::secrets::{self, MasterKey};
+
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() != 2 {
+        eprintln!("Usage: {} <new master key file> | --generate", args[0]);
+        exit(2);
+    }
+    if args[1] == "--generate" {
+        println!("{}", MasterKey::generate_hex());
+        return
+    }
+
+    let config = Config::load().unwrap_or_else(|e| {
+        eprintln!("Failed to load config: {}", e);
+        exit(1);
+    });
+    let old_key = MasterKey::from_config(&config).unwrap_or_else(|e| {
+        eprintln!("Failed to load current master key: {}", e);
+        exit(1);
+    });
+    let new_key = MasterKey::from_file(Path::new(&args[1])).unwrap_or_else(|e| {
+        eprintln!("Failed to load new master key: {}", e);
+        exit(1);
+    });
+
+    let store = tiauth::open_store(&config).await.unwrap_or_else(|e| {
+        eprintln!("Failed to open store: {}", e);
+        exit(1);
+    });
+    match secrets::rotate_master_key(&store, old_key.as_ref(), &new_key).await {
+        Ok(rewritten) => println!("Encrypted {} secret keys with master key {}.", rewritten, new_key.id()),
+        Err(e) => {
+            eprintln!("Rotation failed, run it again to complete it: {}", e);
+            exit(1);
+        }
+    }
+}
@@ -0,0 +1,46 @@
+//! Lifts the login lockout of a user or a client address.
+//!
+//! Usage: `unlock_login <user_hex>` or `unlock_login --ip <address>`, with the store configured as
+//! for the server.
+
+use std::net::IpAddr;
+use std::process::exit;
+
+use tiauth::config::Config;
+use tiauth::throttle;
+
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let key = match args.as_slice() {
+        [_, user_hex] if user_hex != "--ip" => throttle::user_key(user_hex),
+        [_, flag, ip] if flag == "--ip" => match ip.parse::<IpAddr>() {
+            Ok(ip) => throttle::ip_key(ip),
+            Err(e) => {
+                eprintln!("Invalid address {}: {}", ip, e);
+                exit(2);
+            }
+        },
+        _ => {
+            eprintln!("Usage: {} <user_hex> | --ip <address>", args[0]);
+            exit(2);
+        }
+    };
+
+    let config = Config::load().unwrap_or_else(|e| {
+        eprintln!("Failed to load config: {}", e);
+        exit(1);
+    });
+
+    let store = tiauth::open_store(&config).await.unwrap_or_else(|e| {
+        eprintln!("Failed to open store: {}", e);
+        exit(1);
+    });
+    match throttle::unlock(&store, &key).await {
+        Ok(()) => println!("Unlocked {}.", key),
+        Err(e) => {
+            eprintln!("Failed to unlock {}: {}", key, e);
+            exit(1);
+        }
+    }
+}
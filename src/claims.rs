@@ -1,164 +1,323 @@
-use crate::{files, defs, auth};
+use crate::{defs, auth};
+use crate::defs::UserId;
 use crate::error::{ErrorReject, RejectTypes};
 use crate::{Deserialize, Serialize};
 use crate::reject;
+use crate::store::{store_reject, StoreError, StoreErrors, StoreRef};
+use crate::config::ConfigRef;
+use crate::keys::KeyringRef;
 use log::debug;
+use std::collections::BTreeMap;
 
 #[derive(Deserialize, Serialize)]
 struct InvalidTargetsResponse {
     invalid_targets: Vec<(String, String)>,
 }
 
+#[derive(Deserialize, Serialize)]
+struct TargetOutcome {
+    target_user_hex: String,
+    written: bool,
+    reason: Option<String>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct AllOrNothingResponse {
+    committed: bool,
+    targets: Vec<TargetOutcome>,
+}
+
 #[derive(Deserialize, Serialize)]
 pub struct NewUserClaim {
-    origin: String,
+    origin: UserId,
     anphd_id: String,
     uuid: String,
     writer_permission: u16,
-    target_user_hex: String,
+    target_user_hex: UserId,
     target_permission: u16,
     jwt: String,
 }
 
 #[derive(Deserialize, Serialize)]
 struct ClaimTarget {
-    target_user_hex: String,
+    target_user_hex: UserId,
     target_permission: u16,
 }
 
 #[derive(Deserialize, Serialize)]
 pub struct UserClaimWrite {
-    origin: String,
+    origin: UserId,
     anphd_id: String,
-    writer: String,
+    writer: UserId,
     uuid: String,
     targets: Vec<ClaimTarget>,
     jwt: String,
+    #[serde(default)]
+    all_or_nothing: bool,
 }
 
 pub async fn new_user_claim(
-    new_user_claim: NewUserClaim) -> Result<impl warp::Reply, warp::Rejection> {
+    new_user_claim: NewUserClaim, store: StoreRef, config: ConfigRef,
+    keyring: KeyringRef) -> Result<impl warp::Reply, warp::Rejection> {
 
-    auth::verify_jwt(&new_user_claim.origin, &new_user_claim.jwt).await?;
+    auth::verify_jwt(&store, &keyring, &new_user_claim.origin, &new_user_claim.jwt, &auth::Validation::from_config(&config)).await?;
 
-    let resources = files::open_resources().await
-        .map_err(|e| { reject(ErrorReject { rt: RejectTypes::IO, msg: "Error reading resources (new user claim)", e: e.to_string()}) })?;
-    let mut resources_arr = resources.resources;
+    let id = format!("{}:{}", new_user_claim.origin, new_user_claim.anphd_id);
+    let _resource_guard = store.lock_resource(&id).await;
 
-    let id = new_user_claim.origin.clone() + ":" + &new_user_claim.anphd_id.clone();
+    let resources = store.read_resources().await
+        .map_err(|e| { store_reject(e, "Error reading resources (new user claim)",
+                                    "Error reading resources (new user claim)") })?;
+    let resources_arr = resources.resources;
 
     if resources_arr.contains(&id) {
         let appendix = format!("@@@resource_id: {}@@@", &id);
-        Err(reject(ErrorReject { rt: RejectTypes::AlreadyExists, msg: "Resource already exists! (new user claim):", e: appendix}))
+        return Err(reject(ErrorReject { rt: RejectTypes::AlreadyExists, msg: "Resource already exists! (new user claim):", e: appendix}))
     }
-    else {
-        resources_arr.push(id);
 
-        modify_user_claim(new_user_claim, true).await?;
+    let _claims_guard = store.lock_user_claims(&new_user_claim.target_user_hex).await;
+    let mut target_claims = store.read_user_claims(&new_user_claim.target_user_hex).await
+        .map_err(|e| { store_reject(e, "Error reading user target claims (new user claim)",
+                                    "Target user claims do not exist! (new user claim)") })?;
 
-        files::write_new_user_resource(resources_arr).await
-        .map_err(|e| { reject(ErrorReject { rt: RejectTypes::IO, msg: "Error writing new resource (new user claim)", e: e.to_string()}) })?;
+    // Everything is checked before anything is written, so a rejected request changes nothing
+    let target_user_hex = new_user_claim.target_user_hex.clone();
+    if apply_user_claim(&mut target_claims, new_user_claim) {
+        let appendix = format!("@@@resource_id: {}@@@", &id);
+        return Err(reject(ErrorReject { rt: RejectTypes::AlreadyExists, msg: "Claim already exists, cannot write new (new user claim)", e: appendix}))
+    }
 
-        Ok(warp::reply())
+    store.add_resource(&id).await
+        .map_err(|e| { store_reject(e, "Error writing new resource (new user claim)",
+                                    "Error writing new resource (new user claim)") })?;
+
+    // The resource is registered first, so that a failed claims write can be undone by removing it
+    if let Err(e) = store.write_user_claims(&target_user_hex, &target_claims).await {
+        if let Err(remove_e) = store.remove_resource(&id).await {
+            log::error!("Failed to remove resource {} after its claims could not be written: {}", id, remove_e);
+        }
+        return Err(reject(store_reject(e, "Error writing claim to target (new user claim)",
+                                       "Error writing claim to target (new user claim)")))
     }
+
+    Ok(warp::reply())
 }
 
 /// Writes a resource user claim to one or more targets.
+///
+/// By default every valid target is written on its own and the invalid targets are returned. If
+/// `all_or_nothing` is set, all targets are validated first and written together, or none are
+/// written if any target is invalid or a write fails.
 pub async fn modify_user_claims(
-    user_claim_write: UserClaimWrite) -> Result<impl warp::Reply, warp::Rejection> {
+    user_claim_write: UserClaimWrite, store: StoreRef, config: ConfigRef,
+    keyring: KeyringRef) -> Result<impl warp::Reply, warp::Rejection> {
+
+    auth::verify_jwt(&store, &keyring, &user_claim_write.writer, &user_claim_write.jwt, &auth::Validation::from_config(&config)).await?;
 
-    auth::verify_jwt(&user_claim_write.writer, &user_claim_write.jwt).await?;
+    let id = format!("{}:{}", user_claim_write.origin, user_claim_write.anphd_id);
+    let _resource_guard = store.lock_resource(&id).await;
 
-    let writer_claims = files::read_user_claims(&user_claim_write.writer).await
-        .map_err(|e| { files::io_nonexistent_reject(e, "Error reading user writer claims (modify user claims)",
-                                                    "Writer user claims do not exist! (modify user claims") })?;
+    let writer_claims = store.read_user_claims(&user_claim_write.writer).await
+        .map_err(|e| { store_reject(e, "Error reading user writer claims (modify user claims)",
+                                    "Writer user claims do not exist! (modify user claims") })?;
 
     // Check if claim exists and if writer has actual write access
-    // Then checks if the write operation is valid, so if writer has enough permission
-    let new_claims = writer_claims.claims.iter().find(|claim| {
-        claim.origin == user_claim_write.origin && claim.anphd_id == user_claim_write.anphd_id
+    let writer_claim = writer_claims.claims.iter().find(|claim| {
+        *claim.origin == *user_claim_write.origin && claim.anphd_id == user_claim_write.anphd_id
     })
-        .map_or_else(|| {
-            Err(reject(ErrorReject { rt: RejectTypes::NonExistent, msg: "Writer claim not found (modify user claims)", e: "".to_owned() }))
-        },
-         |claim: &defs::UserClaim| {
-             if claim.permission >= 3000 {
-                 Err(reject(ErrorReject { rt: RejectTypes::Permission, msg: "No write access to claim (modify user claims)", e: "".to_owned() }))
-             }
-             else {
-                 let mut valid_claims: Vec<NewUserClaim> = Vec::new();
-                 let mut invalid_claim_targets: Vec<(String, String)> = Vec::new();
-
-                 for target in user_claim_write.targets {
-                     if target.target_permission >= claim.permission {
-                         valid_claims.push(NewUserClaim {
-                             origin: user_claim_write.origin.clone(),
-                             anphd_id: user_claim_write.anphd_id.clone(),
-                             uuid: user_claim_write.uuid.clone(),
-                             writer_permission: claim.permission,
-                             target_user_hex: target.target_user_hex.clone(),
-                             target_permission: target.target_permission,
-                             jwt: "".to_owned()
-                         });
-                     }
-                     else {
-                         invalid_claim_targets.push((target.target_user_hex.clone(), "Target has better permission than writer".to_owned()));
-                     }
-                 }
-
-                 Ok((valid_claims, invalid_claim_targets))
-             }
-         });
-
-    if new_claims.is_ok() {
-        let (valid_claims, mut invalid_claim_targets) = new_claims.unwrap();
-        let mut some_valid = false;
-        for new_claim in valid_claims {
-            let target_claims = files::read_user_claims(&new_claim.target_user_hex).await
-                .map_err(|e| { files::io_nonexistent_reject(e, "Error reading user target claims (modify user claims)",
-                                                            "Target user claims do not exist! (modify user claims") })?;
-
-            let found_target_claim = target_claims.claims.iter().find(|claim| {
-                claim.origin == new_claim.origin && claim.anphd_id == new_claim.anphd_id
+        .ok_or_else(|| {
+            reject(ErrorReject { rt: RejectTypes::NonExistent, msg: "Writer claim not found (modify user claims)", e: "".to_owned() })
+        })?;
+    if writer_claim.permission >= 3000 {
+        return Err(reject(ErrorReject { rt: RejectTypes::Permission, msg: "No write access to claim (modify user claims)", e: "".to_owned() }))
+    }
+    let writer_permission = writer_claim.permission;
+
+    if user_claim_write.all_or_nothing {
+        return modify_user_claims_all_or_nothing(&store, user_claim_write, writer_permission).await
+    }
+
+    // Then checks if the write operation is valid, so if writer has enough permission
+    let mut valid_claims: Vec<NewUserClaim> = Vec::new();
+    let mut invalid_claim_targets: Vec<(String, String)> = Vec::new();
+
+    for target in user_claim_write.targets {
+        if target.target_permission >= writer_permission {
+            valid_claims.push(NewUserClaim {
+                origin: user_claim_write.origin.clone(),
+                anphd_id: user_claim_write.anphd_id.clone(),
+                uuid: user_claim_write.uuid.clone(),
+                writer_permission,
+                target_user_hex: target.target_user_hex.clone(),
+                target_permission: target.target_permission,
+                jwt: "".to_owned()
             });
+        }
+        else {
+            invalid_claim_targets.push((target.target_user_hex.to_string(), "Target has better permission than writer".to_owned()));
+        }
+    }
 
-            let target_valid = match found_target_claim {
-                Some(target_claim) => {
-                    new_claim.writer_permission == 0 || new_claim.writer_permission < target_claim.permission
-                }
-                None => true
-            };
+    let mut some_valid = false;
+    for new_claim in valid_claims {
+        let _claims_guard = store.lock_user_claims(&new_claim.target_user_hex).await;
+        let target_claims = store.read_user_claims(&new_claim.target_user_hex).await
+            .map_err(|e| { store_reject(e, "Error reading user target claims (modify user claims)",
+                                        "Target user claims do not exist! (modify user claims") })?;
 
-            if target_valid {
-                modify_user_claim(new_claim, false).await?;
-                some_valid = true;
-            }
-            else {
-                invalid_claim_targets.push((new_claim.target_user_hex.clone(), "Target has better permission in claims found or no claims found in target.".to_owned()));
-            }
+        if target_writable(&target_claims, &new_claim) {
+            modify_user_claim(&store, new_claim).await?;
+            some_valid = true;
+        }
+        else {
+            invalid_claim_targets.push((new_claim.target_user_hex.to_string(), "Target has better permission in claims found or no claims found in target.".to_owned()));
+        }
+    }
+    let invalid_targets_response = InvalidTargetsResponse {
+        invalid_targets: invalid_claim_targets
+    };
+    let j = serde_json::to_string(&invalid_targets_response)
+        .map_err(|e| { reject(ErrorReject { rt: RejectTypes::DecodeInternal, msg: "Error converting invalid targets to JSON (modify user claims)", e: e.to_string()}) })?;
+
+    if !some_valid {
+        Err(reject(ErrorReject { rt: RejectTypes::Incorrect, msg: "Incorrect claim modifications, no valid claims (modify user claims)", e: format!("@@@{}@@@", j)}))
+    }
+    else {
+        Ok(warp::reply::json(&j))
+    }
+}
+
+/// Checks whether a writer may overwrite the claim the target might already have.
+fn target_writable(target_claims: &defs::Tiauth, new_claim: &NewUserClaim) -> bool {
+    let found_target_claim = target_claims.claims.iter().find(|claim| {
+        *claim.origin == *new_claim.origin && claim.anphd_id == new_claim.anphd_id
+    });
+
+    match found_target_claim {
+        Some(target_claim) => {
+            new_claim.writer_permission == 0 || new_claim.writer_permission < target_claim.permission
         }
-        let invalid_targets_response = InvalidTargetsResponse {
-            invalid_targets: invalid_claim_targets
+        None => true
+    }
+}
+
+/// The all-or-nothing mode of [`modify_user_claims`]. Every target is validated while the claims
+/// of all targets are locked, after which they are written in a single store operation.
+async fn modify_user_claims_all_or_nothing(
+    store: &StoreRef, user_claim_write: UserClaimWrite, writer_permission: u16) -> Result<warp::reply::Json, warp::Rejection> {
+
+    let mut target_hexes: Vec<&UserId> = user_claim_write.targets.iter().map(|t| &t.target_user_hex).collect();
+    target_hexes.sort();
+    target_hexes.dedup();
+    // Locks are always taken in the same order, so two requests cannot wait on each other
+    let mut _claims_guards = Vec::new();
+    for target_hex in &target_hexes {
+        _claims_guards.push(store.lock_user_claims(target_hex).await);
+    }
+
+    let mut targets_claims: BTreeMap<String, defs::Tiauth> = BTreeMap::new();
+    let mut outcomes: Vec<TargetOutcome> = Vec::new();
+    let mut all_valid = true;
+
+    for target in &user_claim_write.targets {
+        let new_claim = NewUserClaim {
+            origin: user_claim_write.origin.clone(),
+            anphd_id: user_claim_write.anphd_id.clone(),
+            uuid: user_claim_write.uuid.clone(),
+            writer_permission,
+            target_user_hex: target.target_user_hex.clone(),
+            target_permission: target.target_permission,
+            jwt: "".to_owned()
         };
-        let j = serde_json::to_string(&invalid_targets_response)
-            .map_err(|e| { reject(ErrorReject { rt: RejectTypes::DecodeInternal, msg: "Error converting invalid targets to JSON (modify user claims)", e: e.to_string()}) })?;
 
-        if !some_valid {
-            Err(reject(ErrorReject { rt: RejectTypes::Incorrect, msg: "Incorrect claim modifications, no valid claims (modify user claims)", e: format!("@@@{}@@@", j)}))
+        let reason = if target.target_permission < writer_permission {
+            Some("Target has better permission than writer")
         }
         else {
-            Ok(warp::reply::json(&j))
+            if !targets_claims.contains_key(&*target.target_user_hex) {
+                match store.read_user_claims(&target.target_user_hex).await {
+                    Ok(target_claims) => {
+                        targets_claims.insert(target.target_user_hex.to_string(), target_claims);
+                    }
+                    Err(StoreError { error_type: StoreErrors::NonExistent, .. }) => {}
+                    Err(e) => return Err(reject(store_reject(e, "Error reading user target claims (modify user claims)",
+                                                             "Target user claims do not exist! (modify user claims"))),
+                }
+            }
+
+            match targets_claims.get_mut(&*target.target_user_hex) {
+                None => Some("Target user claims do not exist"),
+                Some(target_claims) if !target_writable(target_claims, &new_claim) => {
+                    Some("Target has better permission in claims found")
+                }
+                Some(target_claims) => {
+                    apply_user_claim(target_claims, new_claim);
+                    None
+                }
+            }
+        };
+
+        all_valid &= reason.is_none();
+        outcomes.push(TargetOutcome {
+            target_user_hex: target.target_user_hex.to_string(),
+            written: false,
+            reason: reason.map(|r| r.to_owned())
+        });
+    }
+
+    if !all_valid {
+        for outcome in outcomes.iter_mut().filter(|o| o.reason.is_none()) {
+            outcome.reason = Some("Not written, because another target is invalid".to_owned());
         }
+        let j = serde_json::to_string(&AllOrNothingResponse { committed: false, targets: outcomes })
+            .map_err(|e| { reject(ErrorReject { rt: RejectTypes::DecodeInternal, msg: "Error converting target outcomes to JSON (modify user claims)", e: e.to_string()}) })?;
+
+        return Err(reject(ErrorReject { rt: RejectTypes::Incorrect, msg: "Incorrect claim modifications, nothing was written (modify user claims)", e: format!("@@@{}@@@", j)}))
     }
-    else {
-        Err(new_claims.err().unwrap())
+
+    let users_claims: Vec<(String, defs::Tiauth)> = targets_claims.into_iter().collect();
+    store.write_many_user_claims(&users_claims).await
+        .map_err(|e| { store_reject(e, "Error writing claims to targets, nothing was written (modify user claims)",
+                                    "Error writing claims to targets, nothing was written (modify user claims)") })?;
+
+    for outcome in outcomes.iter_mut() {
+        outcome.written = true;
+    }
+
+    Ok(warp::reply::json(&AllOrNothingResponse { committed: true, targets: outcomes }))
+}
+
+/// Adds the claim in new_user_claim to the target claims, or replaces it if the target already
+/// has a claim to the same resource. Returns whether such a claim already existed.
+fn apply_user_claim(target_claims: &mut defs::Tiauth, new_user_claim: NewUserClaim) -> bool {
+    let found = target_claims.claims.iter_mut().find(|claim| {
+        *claim.origin == *new_user_claim.origin && claim.anphd_id == new_user_claim.anphd_id
+    });
+    debug!("fnd {:?}", found);
+
+    let new_target_claim = defs::UserClaim {
+        origin: new_user_claim.origin.into(),
+        anphd_id: new_user_claim.anphd_id,
+        uuid: new_user_claim.uuid,
+        permission: new_user_claim.target_permission
+    };
+    match found {
+        Some(found) => {
+            *found = new_target_claim;
+            true
+        }
+        None => {
+            target_claims.claims.push(new_target_claim);
+            false
+        }
     }
 }
 
 /// Writes a resource user claim to the target in new_user_claim
 ///
 /// This functions assumes the caller has verified the writer as having ownership
-/// of the resource. It performs no additional checks of its own.
+/// of the resource. It performs no additional checks of its own. The caller must hold the
+/// [`crate::store::Store::lock_user_claims`] lock of the target.
 ///
 /// Permission is a [`u16`] with various standard levels.
 /// - 0 is full ownership and allows removing other owners.
@@ -171,49 +330,21 @@ pub async fn modify_user_claims(
 /// - 5000: read-only
 ///
 async fn modify_user_claim(
-    new_user_claim: NewUserClaim, require_empty: bool) -> Result<impl warp::Reply, warp::Rejection> {
+    store: &StoreRef, new_user_claim: NewUserClaim) -> Result<(), warp::Rejection> {
 
-    let mut target_claims = files::read_user_claims(&new_user_claim.target_user_hex).await
-        .map_err(|e| { files::io_nonexistent_reject(e, "Error reading user target claims (modify user claim)",
-                                                    "Target user claims do not exist! (modify user claim") })?;
+    let mut target_claims = store.read_user_claims(&new_user_claim.target_user_hex).await
+        .map_err(|e| { store_reject(e, "Error reading user target claims (modify user claim)",
+                                    "Target user claims do not exist! (modify user claim") })?;
 
     debug!("clms {:?}", target_claims.claims);
-    let found = target_claims.claims.iter_mut().find(|claim| {
-        claim.origin == new_user_claim.origin && claim.anphd_id == new_user_claim.anphd_id
-    });
-    let exists = found.is_some();
-
-    debug!("fnd {:?}", found);
-    let mut new_target_claim = defs::UserClaim {
-        origin: new_user_claim.origin.clone(),
-        anphd_id: new_user_claim.anphd_id.clone(),
-        uuid: new_user_claim.uuid.clone(),
-        permission: new_user_claim.target_permission
-    };
-    let mut found = found.unwrap_or(&mut new_target_claim);
-    if exists {
-        found.origin = new_user_claim.origin;
-        found.anphd_id = new_user_claim.anphd_id;
-        found.uuid = new_user_claim.uuid;
-        found.permission = new_user_claim.target_permission;
-    }
-    else {
-        target_claims.claims.push(new_target_claim);
-    }
+    let target_user_hex = new_user_claim.target_user_hex.clone();
+    apply_user_claim(&mut target_claims, new_user_claim);
 
     debug!("{:?}", target_claims.claims);
 
-    files::write_user_claims(&new_user_claim.target_user_hex, &target_claims).await
-        .map_err(|e| { reject(ErrorReject{ rt: RejectTypes::IO,
-            msg: "Error writing claim to target (modify user claim)", e: e.to_string() }) })?;
+    store.write_user_claims(&target_user_hex, &target_claims).await
+        .map_err(|e| { store_reject(e, "Error writing claim to target (modify user claim)",
+                                    "Error writing claim to target (modify user claim)") })?;
 
-    if exists && require_empty {
-        Err(reject(ErrorReject{ rt: RejectTypes::Incorrect,
-            msg: "Claim already exists, cannot write new (modify user claim)",
-            e: "".to_string()
-        }))
-    }
-    else {
-        Ok(warp::reply())
-    }
+    Ok(())
 }
\ No newline at end of file
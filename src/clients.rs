@@ -0,0 +1,110 @@
+//! Client credentials of the resource servers that may use `/introspect`.
+//!
+//! The clients are listed in the TOML file at `clients_file`, each with the SHA-256 hash of its
+//! secret as hex:
+//!
+//! ```toml
+//! [[clients]]
+//! client_id = "resource-server"
+//! secret_sha256 = "..."
+//! ```
+//!
+//! Clients authenticate with HTTP Basic authentication (`Authorization: Basic` followed by the
+//! base64 of `<client_id>:<secret>`).
+
+use std::sync::Arc;
+
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use warp::Filter;
+
+use crate::Deserialize;
+use crate::config::Config;
+use crate::error::{Error, ErrorExt, ErrorReject, Errors, RejectTypes};
+use crate::reject;
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Client {
+    pub client_id: String,
+    pub secret_sha256: String,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Clients {
+    pub clients: Vec<Client>,
+}
+
+impl Clients {
+    pub fn from_file(path: &std::path::Path) -> Result<Self, Error> {
+        let contents = std::fs::read_to_string(path)
+            .repl("Error reading clients file!")?;
+        let clients: Clients = toml::from_str(&contents)
+            .repl("Error parsing clients file!")?;
+        for client in &clients.clients {
+            let hash = hex::decode(&client.secret_sha256)
+                .repl("Client secret hash is not valid hex!")?;
+            if hash.len() != 32 {
+                return Err(Error {
+                    message: "Client secret hash must be a SHA-256 hash!",
+                    error_type: Errors::DecodeInternal,
+                    e: format!("client_id: {}", client.client_id)
+                })
+            }
+        }
+
+        Ok(clients)
+    }
+
+    /// Loads the clients from the configured file, there are none if it is not set.
+    pub fn from_config(config: &Config) -> Result<Self, Error> {
+        match &config.clients_file {
+            Some(path) => Clients::from_file(path),
+            None => Ok(Clients::default())
+        }
+    }
+
+    /// Whether `secret` is the secret of the client with ID `client_id`.
+    pub fn authenticate(&self, client_id: &str, secret: &str) -> bool {
+        let hash = Sha256::digest(secret.as_bytes());
+
+        self.clients.iter()
+            .filter(|client| client.client_id == client_id)
+            .any(|client| {
+                let expected = hex::decode(&client.secret_sha256).unwrap_or_default();
+                bool::from(expected.ct_eq(hash.as_slice()))
+            })
+    }
+}
+
+pub type ClientsRef = Arc<Clients>;
+
+/// The client ID and secret of a `Basic` authorization header.
+fn basic_credentials(authorization: &str) -> Option<(String, String)> {
+    let (scheme, encoded) = authorization.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None
+    }
+    let decoded = String::from_utf8(base64::decode(encoded.trim()).ok()?).ok()?;
+    let (client_id, secret) = decoded.split_once(':')?;
+
+    Some((client_id.to_owned(), secret.to_owned()))
+}
+
+/// Filter that rejects requests without the credentials of a known client, and otherwise
+/// extracts the client ID.
+pub fn require_client(clients: ClientsRef) -> impl Filter<Extract = (String,), Error = warp::Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and_then(move |authorization: Option<String>| {
+            let clients = clients.clone();
+            async move {
+                match authorization.as_deref().and_then(basic_credentials) {
+                    Some((client_id, secret)) if clients.authenticate(&client_id, &secret) => Ok(client_id),
+                    _ => Err(reject(ErrorReject { rt: RejectTypes::Permission,
+                        msg: "Client credentials are missing or incorrect!",
+                        e: "".to_owned() }))
+                }
+            }
+        })
+}
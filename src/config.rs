@@ -0,0 +1,287 @@
+use std::convert::Infallible;
+use std::env;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use warp::Filter;
+
+use crate::Deserialize;
+use crate::error::{Error, ErrorExt};
+
+/// Environment variable pointing at an optional TOML configuration file.
+pub const CONFIG_FILE_VAR: &str = "TIAUTH_CONFIG";
+
+/// Server configuration. Values are read from the TOML file at `TIAUTH_CONFIG` if it is set,
+/// after which the environment variables listed at each field override them.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Directory of the JSON file store (`TIAUTH_DATA_DIR`).
+    pub data_dir: PathBuf,
+    /// SQLite database URL. If set, it is used instead of the JSON files (`TIAUTH_DATABASE`).
+    pub database: Option<String>,
+    /// File holding the hex master key that encrypts the stored secret keys
+    /// (`TIAUTH_MASTER_KEY_FILE`). The key itself can also be given in `TIAUTH_MASTER_KEY`.
+    pub master_key_file: Option<PathBuf>,
+    /// File holding the hex secret that the salts handed out for unknown users are derived from
+    /// (`TIAUTH_SALT_SECRET_FILE`). The secret itself can also be given in `TIAUTH_SALT_SECRET`.
+    /// Without either, one is generated on the first start and kept in the store.
+    pub salt_secret_file: Option<PathBuf>,
+    /// File holding the token that authorizes the admin endpoints (`TIAUTH_ADMIN_TOKEN_FILE`). The
+    /// token itself can also be given in `TIAUTH_ADMIN_TOKEN`.
+    pub admin_token_file: Option<PathBuf>,
+    /// TOML file listing the clients that may introspect tokens (`TIAUTH_CLIENTS_FILE`).
+    pub clients_file: Option<PathBuf>,
+    /// Address the server binds to (`TIAUTH_BIND_ADDRESS`).
+    pub bind_address: IpAddr,
+    /// Port the server listens on (`TIAUTH_PORT`).
+    pub port: u16,
+    /// Issuer put in every token, tokens with another issuer are rejected (`TIAUTH_ISSUER`).
+    pub issuer: String,
+    /// Seconds an access token stays valid after it is issued (`TIAUTH_TOKEN_LIFETIME`).
+    pub token_lifetime: u64,
+    /// Seconds a refresh token stays valid after it is issued (`TIAUTH_REFRESH_TOKEN_LIFETIME`).
+    pub refresh_token_lifetime: u64,
+    /// Seconds of clock difference tolerated when checking `exp` and `nbf` (`TIAUTH_CLOCK_SKEW`).
+    pub clock_skew: u64,
+    /// Seconds after which the server signing key is replaced by a new one (`TIAUTH_KEY_ROTATION`).
+    pub key_rotation: u64,
+    /// Compatibility mode for the migration away from per-user keys: tokens are signed with the
+    /// key of the user instead of the server keys (`TIAUTH_SIGN_WITH_USER_KEYS`). Implies
+    /// `accept_user_keys`.
+    pub sign_with_user_keys: bool,
+    /// Tokens signed with the key of the user are accepted on verification, besides those signed
+    /// with the server keys (`TIAUTH_ACCEPT_USER_KEYS`).
+    pub accept_user_keys: bool,
+    /// Argon2id memory cost in KiB used to hash passwords (`TIAUTH_ARGON2_MEMORY`).
+    pub argon2_memory_kib: u32,
+    /// Argon2id number of iterations (`TIAUTH_ARGON2_ITERATIONS`).
+    pub argon2_iterations: u32,
+    /// Argon2id degree of parallelism (`TIAUTH_ARGON2_PARALLELISM`).
+    pub argon2_parallelism: u32,
+    /// Failed logins of a user after which it is locked out, 0 disables the limit
+    /// (`TIAUTH_LOGIN_MAX_FAILURES`).
+    pub login_max_failures: u32,
+    /// Failed logins from a client address after which it is locked out, 0 disables the limit
+    /// (`TIAUTH_LOGIN_MAX_FAILURES_IP`). Behind a reverse proxy every client has its address.
+    pub login_max_failures_ip: u32,
+    /// Seconds to wait after the first failed login, doubled after every further failure
+    /// (`TIAUTH_LOGIN_BACKOFF`).
+    pub login_backoff: u64,
+    /// Seconds a lockout lasts, failures older than this are forgotten (`TIAUTH_LOGIN_LOCKOUT`).
+    pub login_lockout: u64,
+    /// Seconds a token mailed to verify a contact address or to reset a password stays valid
+    /// (`TIAUTH_MAIL_TOKEN_LIFETIME`).
+    pub mail_token_lifetime: u64,
+    /// Sender address of the mails to users (`TIAUTH_MAIL_FROM`).
+    pub mail_from: String,
+    /// SMTP server that mails are sent through, with STARTTLS (`TIAUTH_SMTP_HOST`). Without it,
+    /// mails are written to `mail_dir` or else only logged if `mail_log` is set. Without any of
+    /// them, contact addresses cannot be set and password resets cannot be requested.
+    pub smtp_host: Option<String>,
+    /// Port of the SMTP server (`TIAUTH_SMTP_PORT`).
+    pub smtp_port: u16,
+    /// User name to log in to the SMTP server with, if it requires it (`TIAUTH_SMTP_USERNAME`).
+    pub smtp_username: Option<String>,
+    /// File holding the SMTP password (`TIAUTH_SMTP_PASSWORD_FILE`). The password itself can also
+    /// be given in `TIAUTH_SMTP_PASSWORD`.
+    pub smtp_password_file: Option<PathBuf>,
+    /// Directory that mails are written to as JSON files instead of being sent, for local
+    /// development and tests (`TIAUTH_MAIL_DIR`).
+    pub mail_dir: Option<PathBuf>,
+    /// Only log mails, including the tokens in them, if neither `smtp_host` nor `mail_dir` is
+    /// set. Only meant for local development (`TIAUTH_MAIL_LOG`).
+    pub mail_log: bool,
+    /// Log filter in `env_logger` syntax, e.g. `info` or `tiauth=debug` (`TIAUTH_LOG_LEVEL`,
+    /// or the older `MY_LOG_LEVEL` if that is not set).
+    pub log_level: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            data_dir: PathBuf::from("resources"),
+            database: None,
+            master_key_file: None,
+            salt_secret_file: None,
+            admin_token_file: None,
+            clients_file: None,
+            bind_address: IpAddr::from([0, 0, 0, 0]),
+            port: 3031,
+            issuer: "auth.tipten.nl".to_owned(),
+            token_lifetime: 900,
+            refresh_token_lifetime: 30 * 24 * 3600,
+            clock_skew: 60,
+            key_rotation: 30 * 24 * 3600,
+            sign_with_user_keys: false,
+            accept_user_keys: false,
+            argon2_memory_kib: 19 * 1024,
+            argon2_iterations: 2,
+            argon2_parallelism: 1,
+            login_max_failures: 5,
+            login_max_failures_ip: 50,
+            login_backoff: 1,
+            login_lockout: 900,
+            mail_token_lifetime: 3600,
+            mail_from: "tiauth@localhost".to_owned(),
+            smtp_host: None,
+            smtp_port: 587,
+            smtp_username: None,
+            smtp_password_file: None,
+            mail_dir: None,
+            mail_log: false,
+            log_level: "debug".to_owned(),
+        }
+    }
+}
+
+fn env_var(name: &str) -> Option<String> {
+    env::var(name).ok().filter(|v| !v.is_empty())
+}
+
+impl Config {
+    /// Loads the configuration from the TOML file at `TIAUTH_CONFIG` (if set) and the environment.
+    pub fn load() -> Result<Config, Error> {
+        let mut config = match env_var(CONFIG_FILE_VAR) {
+            Some(path) => Config::from_file(&path)?,
+            None => Config::default()
+        };
+        config.apply_env()?;
+
+        Ok(config)
+    }
+
+    pub fn from_file(path: &str) -> Result<Config, Error> {
+        let contents = std::fs::read_to_string(path)
+            .repl("Error reading config file!")?;
+
+        toml::from_str(&contents)
+            .repl("Error parsing config file!")
+    }
+
+    /// Overrides values with those set in the environment.
+    pub fn apply_env(&mut self) -> Result<(), Error> {
+        if let Some(data_dir) = env_var("TIAUTH_DATA_DIR") {
+            self.data_dir = PathBuf::from(data_dir);
+        }
+        if let Some(database) = env_var("TIAUTH_DATABASE") {
+            self.database = Some(database);
+        }
+        if let Some(master_key_file) = env_var("TIAUTH_MASTER_KEY_FILE") {
+            self.master_key_file = Some(PathBuf::from(master_key_file));
+        }
+        if let Some(salt_secret_file) = env_var("TIAUTH_SALT_SECRET_FILE") {
+            self.salt_secret_file = Some(PathBuf::from(salt_secret_file));
+        }
+        if let Some(admin_token_file) = env_var("TIAUTH_ADMIN_TOKEN_FILE") {
+            self.admin_token_file = Some(PathBuf::from(admin_token_file));
+        }
+        if let Some(clients_file) = env_var("TIAUTH_CLIENTS_FILE") {
+            self.clients_file = Some(PathBuf::from(clients_file));
+        }
+        if let Some(bind_address) = env_var("TIAUTH_BIND_ADDRESS") {
+            self.bind_address = bind_address.parse()
+                .repl("Invalid TIAUTH_BIND_ADDRESS!")?;
+        }
+        if let Some(port) = env_var("TIAUTH_PORT") {
+            self.port = port.parse()
+                .repl("Invalid TIAUTH_PORT!")?;
+        }
+        if let Some(issuer) = env_var("TIAUTH_ISSUER") {
+            self.issuer = issuer;
+        }
+        if let Some(token_lifetime) = env_var("TIAUTH_TOKEN_LIFETIME") {
+            self.token_lifetime = token_lifetime.parse()
+                .repl("Invalid TIAUTH_TOKEN_LIFETIME!")?;
+        }
+        if let Some(refresh_token_lifetime) = env_var("TIAUTH_REFRESH_TOKEN_LIFETIME") {
+            self.refresh_token_lifetime = refresh_token_lifetime.parse()
+                .repl("Invalid TIAUTH_REFRESH_TOKEN_LIFETIME!")?;
+        }
+        if let Some(clock_skew) = env_var("TIAUTH_CLOCK_SKEW") {
+            self.clock_skew = clock_skew.parse()
+                .repl("Invalid TIAUTH_CLOCK_SKEW!")?;
+        }
+        if let Some(key_rotation) = env_var("TIAUTH_KEY_ROTATION") {
+            self.key_rotation = key_rotation.parse()
+                .repl("Invalid TIAUTH_KEY_ROTATION!")?;
+        }
+        if let Some(sign_with_user_keys) = env_var("TIAUTH_SIGN_WITH_USER_KEYS") {
+            self.sign_with_user_keys = sign_with_user_keys.parse()
+                .repl("Invalid TIAUTH_SIGN_WITH_USER_KEYS!")?;
+        }
+        if let Some(accept_user_keys) = env_var("TIAUTH_ACCEPT_USER_KEYS") {
+            self.accept_user_keys = accept_user_keys.parse()
+                .repl("Invalid TIAUTH_ACCEPT_USER_KEYS!")?;
+        }
+        if let Some(argon2_memory_kib) = env_var("TIAUTH_ARGON2_MEMORY") {
+            self.argon2_memory_kib = argon2_memory_kib.parse()
+                .repl("Invalid TIAUTH_ARGON2_MEMORY!")?;
+        }
+        if let Some(argon2_iterations) = env_var("TIAUTH_ARGON2_ITERATIONS") {
+            self.argon2_iterations = argon2_iterations.parse()
+                .repl("Invalid TIAUTH_ARGON2_ITERATIONS!")?;
+        }
+        if let Some(argon2_parallelism) = env_var("TIAUTH_ARGON2_PARALLELISM") {
+            self.argon2_parallelism = argon2_parallelism.parse()
+                .repl("Invalid TIAUTH_ARGON2_PARALLELISM!")?;
+        }
+        if let Some(login_max_failures) = env_var("TIAUTH_LOGIN_MAX_FAILURES") {
+            self.login_max_failures = login_max_failures.parse()
+                .repl("Invalid TIAUTH_LOGIN_MAX_FAILURES!")?;
+        }
+        if let Some(login_max_failures_ip) = env_var("TIAUTH_LOGIN_MAX_FAILURES_IP") {
+            self.login_max_failures_ip = login_max_failures_ip.parse()
+                .repl("Invalid TIAUTH_LOGIN_MAX_FAILURES_IP!")?;
+        }
+        if let Some(login_backoff) = env_var("TIAUTH_LOGIN_BACKOFF") {
+            self.login_backoff = login_backoff.parse()
+                .repl("Invalid TIAUTH_LOGIN_BACKOFF!")?;
+        }
+        if let Some(login_lockout) = env_var("TIAUTH_LOGIN_LOCKOUT") {
+            self.login_lockout = login_lockout.parse()
+                .repl("Invalid TIAUTH_LOGIN_LOCKOUT!")?;
+        }
+        if let Some(mail_token_lifetime) = env_var("TIAUTH_MAIL_TOKEN_LIFETIME") {
+            self.mail_token_lifetime = mail_token_lifetime.parse()
+                .repl("Invalid TIAUTH_MAIL_TOKEN_LIFETIME!")?;
+        }
+        if let Some(mail_from) = env_var("TIAUTH_MAIL_FROM") {
+            self.mail_from = mail_from;
+        }
+        if let Some(smtp_host) = env_var("TIAUTH_SMTP_HOST") {
+            self.smtp_host = Some(smtp_host);
+        }
+        if let Some(smtp_port) = env_var("TIAUTH_SMTP_PORT") {
+            self.smtp_port = smtp_port.parse()
+                .repl("Invalid TIAUTH_SMTP_PORT!")?;
+        }
+        if let Some(smtp_username) = env_var("TIAUTH_SMTP_USERNAME") {
+            self.smtp_username = Some(smtp_username);
+        }
+        if let Some(smtp_password_file) = env_var("TIAUTH_SMTP_PASSWORD_FILE") {
+            self.smtp_password_file = Some(PathBuf::from(smtp_password_file));
+        }
+        if let Some(mail_dir) = env_var("TIAUTH_MAIL_DIR") {
+            self.mail_dir = Some(PathBuf::from(mail_dir));
+        }
+        if let Some(mail_log) = env_var("TIAUTH_MAIL_LOG") {
+            self.mail_log = mail_log.parse()
+                .repl("Invalid TIAUTH_MAIL_LOG!")?;
+        }
+        // MY_LOG_LEVEL is what the log level was set with before TIAUTH_LOG_LEVEL.
+        if let Some(log_level) = env_var("TIAUTH_LOG_LEVEL").or_else(|| env_var("MY_LOG_LEVEL")) {
+            self.log_level = log_level;
+        }
+
+        Ok(())
+    }
+}
+
+pub type ConfigRef = Arc<Config>;
+
+/// Filter that hands a reference to the config to a handler.
+pub fn with_config(config: ConfigRef) -> impl Filter<Extract = (ConfigRef,), Error = Infallible> + Clone {
+    warp::any().map(move || config.clone())
+}
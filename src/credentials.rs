@@ -0,0 +1,79 @@
+//! Changing the password of a user, who proves who they are with the current password or with a
+//! valid access token.
+//!
+//! Sessions can be ended along with the change: the tokens of the user issued before it are then
+//! rejected by [`crate::auth::verify_jwt`] and by `/token/refresh`. Token times are in seconds, so
+//! a token issued in the same second as the change is rejected as well, and tokens issued after
+//! it in that second are dated a second later, see [`crate::auth::issue_time`].
+
+use std::net::SocketAddr;
+
+use crate::{Deserialize, Serialize};
+use crate::auth::{self, unix_now};
+use crate::config::ConfigRef;
+use crate::defs::UserId;
+use crate::error::{ErrorReject, RejectTypes};
+use crate::keys::KeyringRef;
+use crate::login;
+use crate::password;
+use crate::register;
+use crate::reject;
+use crate::store::{store_reject, StoreRef};
+use crate::throttle;
+
+#[derive(Deserialize, Serialize)]
+pub struct ChangePassword {
+    user_hex: UserId,
+    /// The current password hash, as sent to `/login`. If it is given, `jwt` is ignored.
+    #[serde(default)]
+    password_hash_hex: Option<String>,
+    /// An access token of the user, instead of the current password.
+    #[serde(default)]
+    jwt: Option<String>,
+    new_password_hash_hex: String,
+    new_salt_hex: String,
+    /// Whether the tokens issued before the change are rejected from now on.
+    #[serde(default)]
+    revoke_sessions: bool,
+}
+
+/// Replaces the password hash and salt of the user, and ends its sessions if `revoke_sessions`.
+/// Both happen in one write, so the password never changes while the old sessions stay valid.
+pub(crate) async fn replace_password(store: &StoreRef, config: &ConfigRef, user_hex: &str, new_password_hash_hex: &str,
+                                     new_salt_hex: &str, revoke_sessions: bool, now: u64) -> Result<(), warp::Rejection> {
+    register::check_salt_hex(new_salt_hex)?;
+    let password_hash = password::hash(config, new_password_hash_hex).await?;
+    store.write_user_credentials(user_hex, &password_hash, new_salt_hex, now, revoke_sessions).await
+        .map_err(|e| { store_reject(e, "Error writing new password (replace password)",
+                                    "User does not exist! (replace password)") })?;
+
+    Ok(())
+}
+
+pub async fn change_password(
+    request: ChangePassword, remote: Option<SocketAddr>, store: StoreRef, config: ConfigRef,
+    keyring: KeyringRef) -> Result<impl warp::Reply, warp::Rejection> {
+    let user_hex = &request.user_hex;
+    let now = unix_now()?;
+
+    // Held until the new hash is written, so a concurrent login cannot upgrade the old one over it.
+    let _attempts_guard = throttle::lock_user(&store, user_hex).await;
+    match (&request.password_hash_hex, &request.jwt) {
+        (Some(password_hash_hex), _) => {
+            let ip = remote.map(|remote| remote.ip());
+            login::check_password(&store, &config, user_hex, password_hash_hex, ip, now).await?;
+        }
+        (None, Some(jwt)) => {
+            auth::verify_jwt(&store, &keyring, user_hex, jwt, &auth::Validation::from_config(&config)).await?;
+        }
+        (None, None) => return Err(reject(ErrorReject { rt: RejectTypes::DecodeExternal,
+            msg: "Current password or access token is required!",
+            e: "".to_owned() }))
+    }
+
+    replace_password(&store, &config, user_hex, &request.new_password_hash_hex, &request.new_salt_hex,
+                     request.revoke_sessions, now).await?;
+    log::info!("Changed the password of {}.", user_hex);
+
+    Ok(warp::reply())
+}
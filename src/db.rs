@@ -1,45 +1,779 @@
-// use sqlx::sqlite::SqlitePoolOptions;
-// use sqlx::prelude::*;
-// use crate::error::{Error, ErrorExt};
-//
-// static AUTH_TABLE: &'static str = "user_auth";
-//
-// static USER_HEX: &'static str = "user_hex";
-// static PASSWORD_HASH_HEX: &'static str = "password_hash_hex";
-// static SALT_HEX: &'static str = "salt_hex";
-// static SECRET_HEX: &'static str = "secret_hex";
-// static PUBLIC_HEX: &'static str = "public_hex";
-//
-// #[derive(FromRow, Debug)]
-// struct UserAuth {
-//     user_hex: String,
-//     password_hash_hex: String,
-//     salt_hex: String,
-//     secret_hex: String,
-//     public_hex: String,
-// }
-//
-// async fn connect_test() -> Result<(), Error> {
-//     let pool = SqlitePoolOptions::new()
-//         .max_connections(2)
-//         .connect("sqlite:/tmp/tidb.sqlite").await.repl("Failed DB connection.")?;
-//
-//     // let columns: String;
-//     // let secret = true;
-//     // if !secret {
-//     //     columns = format!("{}, {}", SALT_HEX, PUBLIC_HEX);
-//     // } else {
-//     //     columns = format!("{}, {}, {}, {}", PASSWORD_HASH_HEX, SALT_HEX, SECRET_HEX, PUBLIC_HEX);
-//     // }
-//     // let sql_string = format!("SELECT DISTINCT {columns} FROM {table} WHERE {uh_name}='{uh}'",
-//     //                          columns=columns, table=AUTH_TABLE, uh_name=USER_HEX, uh=user_hex);
-//
-//     let user_auths = sqlx::query_as::<_, UserAuth>(
-//         "\
-// SELECT * FROM user_auth
-//     \
-//     ").fetch_all(&pool).await.repl("db")?;
-//     println!("{:?}", user_auths);
-//
-//     Ok(())
-// }
\ No newline at end of file
+use std::convert::TryFrom;
+use std::str::FromStr;
+
+use async_trait::async_trait;
+use sqlx::prelude::*;
+use sqlx::sqlite::{SqliteConnectOptions, SqliteConnection, SqlitePool, SqlitePoolOptions};
+
+use crate::defs;
+use crate::store::{Store, StoreError, StoreErrors, StoreLocks, StoreResult};
+
+/// SQLite extended result codes that point at a problem with the stored values themselves.
+const SQLITE_CONSTRAINT_CHECK: &str = "275";
+const SQLITE_CONSTRAINT_FOREIGNKEY: &str = "787";
+const SQLITE_CONSTRAINT_PRIMARYKEY: &str = "1555";
+const SQLITE_CONSTRAINT_UNIQUE: &str = "2067";
+
+impl From<sqlx::Error> for StoreError {
+    fn from(e: sqlx::Error) -> Self {
+        let error_type = match &e {
+            sqlx::Error::RowNotFound => StoreErrors::NonExistent,
+            sqlx::Error::ColumnDecode { .. } | sqlx::Error::Decode(_) => StoreErrors::Decode,
+            sqlx::Error::Database(db_e) => match db_e.code().as_deref() {
+                Some(SQLITE_CONSTRAINT_PRIMARYKEY) | Some(SQLITE_CONSTRAINT_UNIQUE) => StoreErrors::AlreadyExists,
+                Some(SQLITE_CONSTRAINT_CHECK) => StoreErrors::Invalid,
+                Some(SQLITE_CONSTRAINT_FOREIGNKEY) => StoreErrors::NonExistent,
+                _ => StoreErrors::IO,
+            },
+            _ => StoreErrors::IO,
+        };
+
+        StoreError {
+            error_type,
+            e: e.to_string()
+        }
+    }
+}
+
+impl From<sqlx::migrate::MigrateError> for StoreError {
+    fn from(e: sqlx::migrate::MigrateError) -> Self {
+        StoreError {
+            error_type: StoreErrors::IO,
+            e: e.to_string()
+        }
+    }
+}
+
+#[derive(FromRow)]
+struct UserRow {
+    user_hex: String,
+    password_hash_hex: String,
+    salt_hex: String,
+    secret_hex: String,
+    public_hex: String,
+    password_changed: Option<i64>,
+    tokens_not_before: Option<i64>,
+    contact: Option<String>,
+    contact_verified: bool,
+}
+
+#[derive(FromRow)]
+struct ClaimRow {
+    origin: String,
+    anphd_id: String,
+    uuid: String,
+    permission: i64,
+}
+
+impl TryFrom<ClaimRow> for defs::UserClaim {
+    type Error = StoreError;
+
+    fn try_from(row: ClaimRow) -> Result<Self, Self::Error> {
+        let permission = u16::try_from(row.permission)
+            .map_err(|e| StoreError { error_type: StoreErrors::Decode, e: e.to_string() })?;
+
+        Ok(defs::UserClaim {
+            origin: row.origin,
+            anphd_id: row.anphd_id,
+            uuid: row.uuid,
+            permission
+        })
+    }
+}
+
+#[derive(FromRow)]
+struct SigningKeyRow {
+    kid: String,
+    secret_hex: String,
+    public_hex: String,
+    created: i64,
+    expires: Option<i64>,
+}
+
+fn decode_time(time: i64) -> StoreResult<u64> {
+    u64::try_from(time).map_err(|e| StoreError { error_type: StoreErrors::Decode, e: e.to_string() })
+}
+
+fn encode_time(time: u64) -> StoreResult<i64> {
+    i64::try_from(time).map_err(|e| StoreError { error_type: StoreErrors::Invalid, e: e.to_string() })
+}
+
+impl TryFrom<UserRow> for defs::SaveUserJson {
+    type Error = StoreError;
+
+    fn try_from(row: UserRow) -> Result<Self, Self::Error> {
+        Ok(defs::SaveUserJson {
+            user_hex: row.user_hex,
+            password_hash_hex: row.password_hash_hex,
+            salt_hex: row.salt_hex,
+            secret_hex: row.secret_hex,
+            public_hex: row.public_hex,
+            password_changed: row.password_changed.map(decode_time).transpose()?,
+            tokens_not_before: row.tokens_not_before.map(decode_time).transpose()?,
+            contact: row.contact,
+            contact_verified: row.contact_verified
+        })
+    }
+}
+
+impl TryFrom<SigningKeyRow> for defs::SigningKey {
+    type Error = StoreError;
+
+    fn try_from(row: SigningKeyRow) -> Result<Self, Self::Error> {
+        Ok(defs::SigningKey {
+            kid: row.kid,
+            secret_hex: row.secret_hex,
+            public_hex: row.public_hex,
+            created: decode_time(row.created)?,
+            expires: row.expires.map(decode_time).transpose()?
+        })
+    }
+}
+
+#[derive(FromRow)]
+struct LoginAttemptsRow {
+    failures: i64,
+    last_failure: i64,
+    locked_until: i64,
+}
+
+impl TryFrom<LoginAttemptsRow> for defs::LoginAttempts {
+    type Error = StoreError;
+
+    fn try_from(row: LoginAttemptsRow) -> Result<Self, Self::Error> {
+        Ok(defs::LoginAttempts {
+            failures: u32::try_from(row.failures)
+                .map_err(|e| StoreError { error_type: StoreErrors::Decode, e: e.to_string() })?,
+            last_failure: decode_time(row.last_failure)?,
+            locked_until: decode_time(row.locked_until)?
+        })
+    }
+}
+
+#[derive(FromRow)]
+struct RefreshTokenRow {
+    token_hash: String,
+    family_id: String,
+    user_hex: String,
+    issued: i64,
+    expires: i64,
+    used: bool,
+}
+
+impl TryFrom<RefreshTokenRow> for defs::RefreshToken {
+    type Error = StoreError;
+
+    fn try_from(row: RefreshTokenRow) -> Result<Self, Self::Error> {
+        Ok(defs::RefreshToken {
+            token_hash: row.token_hash,
+            family_id: row.family_id,
+            user_hex: row.user_hex,
+            issued: decode_time(row.issued)?,
+            expires: decode_time(row.expires)?,
+            used: row.used
+        })
+    }
+}
+
+#[derive(FromRow)]
+struct RevokedTokenRow {
+    jti: String,
+    expires: i64,
+}
+
+impl TryFrom<RevokedTokenRow> for defs::RevokedToken {
+    type Error = StoreError;
+
+    fn try_from(row: RevokedTokenRow) -> Result<Self, Self::Error> {
+        Ok(defs::RevokedToken {
+            jti: row.jti,
+            expires: decode_time(row.expires)?
+        })
+    }
+}
+
+#[derive(FromRow)]
+struct MailTokenRow {
+    token_hash: String,
+    user_hex: String,
+    purpose: String,
+    contact: String,
+    issued: i64,
+    expires: i64,
+}
+
+impl TryFrom<MailTokenRow> for defs::MailToken {
+    type Error = StoreError;
+
+    fn try_from(row: MailTokenRow) -> Result<Self, Self::Error> {
+        let purpose = match row.purpose.as_str() {
+            "verify_contact" => defs::MailTokenPurpose::VerifyContact,
+            "password_reset" => defs::MailTokenPurpose::PasswordReset,
+            other => return Err(StoreError { error_type: StoreErrors::Decode, e: format!("purpose: {}", other) })
+        };
+
+        Ok(defs::MailToken {
+            token_hash: row.token_hash,
+            user_hex: row.user_hex,
+            purpose,
+            contact: row.contact,
+            issued: decode_time(row.issued)?,
+            expires: decode_time(row.expires)?
+        })
+    }
+}
+
+pub async fn insert_user(conn: &mut SqliteConnection, save_user: &defs::SaveUserJson) -> StoreResult<()> {
+    sqlx::query("\
+INSERT INTO user_auth (user_hex, password_hash_hex, salt_hex, secret_hex, public_hex, password_changed,
+                       tokens_not_before, contact, contact_verified)
+VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)")
+        .bind(&save_user.user_hex)
+        .bind(&save_user.password_hash_hex)
+        .bind(&save_user.salt_hex)
+        .bind(&save_user.secret_hex)
+        .bind(&save_user.public_hex)
+        .bind(save_user.password_changed.map(encode_time).transpose()?)
+        .bind(save_user.tokens_not_before.map(encode_time).transpose()?)
+        .bind(&save_user.contact)
+        .bind(save_user.contact_verified)
+        .execute(conn).await?;
+
+    Ok(())
+}
+
+pub async fn insert_claim(conn: &mut SqliteConnection, user_hex: &str, claim: &defs::UserClaim) -> StoreResult<()> {
+    sqlx::query("\
+INSERT INTO user_claims (user_hex, origin, anphd_id, uuid, permission)
+VALUES (?, ?, ?, ?, ?)")
+        .bind(user_hex)
+        .bind(&claim.origin)
+        .bind(&claim.anphd_id)
+        .bind(&claim.uuid)
+        .bind(i64::from(claim.permission))
+        .execute(conn).await?;
+
+    Ok(())
+}
+
+async fn replace_user_claims(conn: &mut SqliteConnection, user_hex: &str, user_claims: &defs::Tiauth) -> StoreResult<()> {
+    sqlx::query("DELETE FROM user_claims WHERE user_hex = ?")
+        .bind(user_hex)
+        .execute(&mut *conn).await?;
+
+    for claim in &user_claims.claims {
+        insert_claim(conn, user_hex, claim).await?;
+    }
+
+    Ok(())
+}
+
+pub async fn insert_resource(conn: &mut SqliteConnection, resource_id: &str) -> StoreResult<()> {
+    sqlx::query("INSERT INTO resources (resource_id) VALUES (?)")
+        .bind(resource_id)
+        .execute(conn).await?;
+
+    Ok(())
+}
+
+pub async fn insert_signing_key(conn: &mut SqliteConnection, key: &defs::SigningKey) -> StoreResult<()> {
+    sqlx::query("\
+INSERT INTO signing_keys (kid, secret_hex, public_hex, created, expires)
+VALUES (?, ?, ?, ?, ?)")
+        .bind(&key.kid)
+        .bind(&key.secret_hex)
+        .bind(&key.public_hex)
+        .bind(encode_time(key.created)?)
+        .bind(key.expires.map(encode_time).transpose()?)
+        .execute(conn).await?;
+
+    Ok(())
+}
+
+pub async fn insert_salt_secret(conn: &mut SqliteConnection, secret_hex: &str) -> StoreResult<()> {
+    sqlx::query("INSERT INTO salt_secret (id, secret_hex) VALUES (1, ?)")
+        .bind(secret_hex)
+        .execute(conn).await?;
+
+    Ok(())
+}
+
+pub async fn insert_login_attempts(conn: &mut SqliteConnection, key: &str,
+                                   attempts: &defs::LoginAttempts) -> StoreResult<()> {
+    sqlx::query("\
+INSERT INTO login_attempts (attempt_key, failures, last_failure, locked_until)
+VALUES (?, ?, ?, ?)")
+        .bind(key)
+        .bind(attempts.failures)
+        .bind(encode_time(attempts.last_failure)?)
+        .bind(encode_time(attempts.locked_until)?)
+        .execute(conn).await?;
+
+    Ok(())
+}
+
+pub async fn insert_refresh_token(conn: &mut SqliteConnection, token: &defs::RefreshToken) -> StoreResult<()> {
+    sqlx::query("\
+INSERT INTO refresh_tokens (token_hash, family_id, user_hex, issued, expires, used)
+VALUES (?, ?, ?, ?, ?, ?)")
+        .bind(&token.token_hash)
+        .bind(&token.family_id)
+        .bind(&token.user_hex)
+        .bind(encode_time(token.issued)?)
+        .bind(encode_time(token.expires)?)
+        .bind(token.used)
+        .execute(conn).await?;
+
+    Ok(())
+}
+
+pub async fn insert_mail_token(conn: &mut SqliteConnection, token: &defs::MailToken) -> StoreResult<()> {
+    sqlx::query("\
+INSERT INTO mail_tokens (token_hash, user_hex, purpose, contact, issued, expires)
+VALUES (?, ?, ?, ?, ?, ?)")
+        .bind(&token.token_hash)
+        .bind(&token.user_hex)
+        .bind(token.purpose.as_str())
+        .bind(&token.contact)
+        .bind(encode_time(token.issued)?)
+        .bind(encode_time(token.expires)?)
+        .execute(conn).await?;
+
+    Ok(())
+}
+
+pub async fn insert_revoked_token(conn: &mut SqliteConnection, token: &defs::RevokedToken) -> StoreResult<()> {
+    sqlx::query("INSERT INTO revoked_tokens (jti, expires) VALUES (?, ?)")
+        .bind(&token.jti)
+        .bind(encode_time(token.expires)?)
+        .execute(conn).await?;
+
+    Ok(())
+}
+
+/// [`Store`] backed by a SQLite database, see the `migrations` directory for the schema.
+///
+/// The store holds a connection pool, which is shared by every handler through the
+/// [`crate::store::StoreRef`] they receive.
+pub struct SqliteStore {
+    pool: SqlitePool,
+    locks: StoreLocks,
+}
+
+impl SqliteStore {
+    /// Connects to the database at `url` (e.g. `sqlite://db/tidb.sqlite`), creating the file if
+    /// it does not exist yet.
+    pub async fn connect(url: &str) -> StoreResult<Self> {
+        let options = SqliteConnectOptions::from_str(url)?
+            .create_if_missing(true)
+            .foreign_keys(true);
+        let pool = SqlitePoolOptions::new()
+            .max_connections(5)
+            .connect_with(options).await?;
+
+        Ok(SqliteStore {
+            pool,
+            locks: StoreLocks::default()
+        })
+    }
+
+    pub fn pool(&self) -> &SqlitePool {
+        &self.pool
+    }
+}
+
+#[async_trait]
+impl Store for SqliteStore {
+    async fn prepare(&self) -> StoreResult<()> {
+        sqlx::migrate!("./migrations").run(&self.pool).await?;
+
+        Ok(())
+    }
+
+    async fn read_user(&self, user_hex: &str, secret: bool) -> StoreResult<defs::SaveUserJson> {
+        let row = sqlx::query_as::<_, UserRow>("\
+SELECT user_hex, password_hash_hex, salt_hex, secret_hex, public_hex, password_changed, tokens_not_before,
+       contact, contact_verified
+FROM user_auth WHERE user_hex = ?")
+            .bind(user_hex)
+            .fetch_one(&self.pool).await?;
+        let mut save_user = defs::SaveUserJson::try_from(row)?;
+
+        if !secret {
+            save_user.secret_hex = "".to_owned();
+            save_user.password_hash_hex = "".to_owned();
+        }
+
+        Ok(save_user)
+    }
+
+    async fn register_user(&self, user_json: &defs::UserJson, public_hex: String,
+                           secret_hex: String) -> StoreResult<()> {
+        let save_user = defs::SaveUserJson {
+            user_hex: user_json.user_hex.to_string(),
+            password_hash_hex: user_json.password_hash_hex.to_owned(),
+            salt_hex: user_json.salt_hex.to_owned(),
+            secret_hex,
+            public_hex,
+            password_changed: None,
+            tokens_not_before: None,
+            contact: None,
+            contact_verified: false
+        };
+        let mut conn = self.pool.acquire().await?;
+
+        insert_user(&mut conn, &save_user).await
+    }
+
+    async fn write_user_password(&self, user_hex: &str, password_hash: &str) -> StoreResult<()> {
+        let result = sqlx::query("UPDATE user_auth SET password_hash_hex = ? WHERE user_hex = ?")
+            .bind(password_hash)
+            .bind(user_hex)
+            .execute(&self.pool).await?;
+        if result.rows_affected() == 0 {
+            return Err(StoreError {
+                error_type: StoreErrors::NonExistent,
+                e: user_hex.to_owned()
+            })
+        }
+
+        Ok(())
+    }
+
+    async fn write_user_credentials(&self, user_hex: &str, password_hash: &str, salt_hex: &str,
+                                    changed: u64, revoke_sessions: bool) -> StoreResult<()> {
+        let result = sqlx::query("\
+UPDATE user_auth SET password_hash_hex = ?, salt_hex = ?, password_changed = ?,
+    tokens_not_before = CASE WHEN ? THEN ? ELSE tokens_not_before END
+WHERE user_hex = ?")
+            .bind(password_hash)
+            .bind(salt_hex)
+            .bind(encode_time(changed)?)
+            .bind(revoke_sessions)
+            .bind(encode_time(changed)?)
+            .bind(user_hex)
+            .execute(&self.pool).await?;
+        if result.rows_affected() == 0 {
+            return Err(StoreError {
+                error_type: StoreErrors::NonExistent,
+                e: user_hex.to_owned()
+            })
+        }
+
+        Ok(())
+    }
+
+    async fn write_tokens_not_before(&self, user_hex: &str, not_before: u64) -> StoreResult<()> {
+        let result = sqlx::query("UPDATE user_auth SET tokens_not_before = ? WHERE user_hex = ?")
+            .bind(encode_time(not_before)?)
+            .bind(user_hex)
+            .execute(&self.pool).await?;
+        if result.rows_affected() == 0 {
+            return Err(StoreError {
+                error_type: StoreErrors::NonExistent,
+                e: user_hex.to_owned()
+            })
+        }
+
+        Ok(())
+    }
+
+    async fn write_user_contact(&self, user_hex: &str, contact: Option<&str>, verified: bool) -> StoreResult<()> {
+        let result = sqlx::query("UPDATE user_auth SET contact = ?, contact_verified = ? WHERE user_hex = ?")
+            .bind(contact)
+            .bind(verified)
+            .bind(user_hex)
+            .execute(&self.pool).await?;
+        if result.rows_affected() == 0 {
+            return Err(StoreError {
+                error_type: StoreErrors::NonExistent,
+                e: user_hex.to_owned()
+            })
+        }
+
+        Ok(())
+    }
+
+    async fn user_secrets(&self) -> StoreResult<Vec<(String, String)>> {
+        let secrets = sqlx::query_as::<_, (String, String)>("SELECT user_hex, secret_hex FROM user_auth ORDER BY user_hex")
+            .fetch_all(&self.pool).await?;
+
+        Ok(secrets)
+    }
+
+    async fn write_user_secret(&self, user_hex: &str, secret_hex: &str) -> StoreResult<()> {
+        let result = sqlx::query("UPDATE user_auth SET secret_hex = ? WHERE user_hex = ?")
+            .bind(secret_hex)
+            .bind(user_hex)
+            .execute(&self.pool).await?;
+        if result.rows_affected() == 0 {
+            return Err(StoreError {
+                error_type: StoreErrors::NonExistent,
+                e: user_hex.to_owned()
+            })
+        }
+
+        Ok(())
+    }
+
+    async fn read_user_claims(&self, user_hex: &str) -> StoreResult<defs::Tiauth> {
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query("SELECT user_hex FROM user_auth WHERE user_hex = ?")
+            .bind(user_hex)
+            .fetch_one(&mut tx).await?;
+
+        let rows = sqlx::query_as::<_, ClaimRow>("\
+SELECT origin, anphd_id, uuid, permission FROM user_claims
+WHERE user_hex = ? ORDER BY rowid")
+            .bind(user_hex)
+            .fetch_all(&mut tx).await?;
+
+        tx.commit().await?;
+
+        let claims = rows.into_iter()
+            .map(defs::UserClaim::try_from)
+            .collect::<StoreResult<Vec<defs::UserClaim>>>()?;
+
+        Ok(defs::Tiauth { claims })
+    }
+
+    async fn write_user_claims(&self, user_hex: &str, user_claims: &defs::Tiauth) -> StoreResult<()> {
+        let mut tx = self.pool.begin().await?;
+        replace_user_claims(&mut tx, user_hex, user_claims).await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    async fn write_many_user_claims(&self, users_claims: &[(String, defs::Tiauth)]) -> StoreResult<()> {
+        let mut tx = self.pool.begin().await?;
+
+        for (user_hex, user_claims) in users_claims {
+            replace_user_claims(&mut tx, user_hex, user_claims).await?;
+        }
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    async fn read_resources(&self) -> StoreResult<defs::Resources> {
+        let resources = sqlx::query_scalar::<_, String>("SELECT resource_id FROM resources ORDER BY rowid")
+            .fetch_all(&self.pool).await?;
+
+        Ok(defs::Resources { resources })
+    }
+
+    async fn add_resource(&self, resource_id: &str) -> StoreResult<()> {
+        let mut conn = self.pool.acquire().await?;
+
+        insert_resource(&mut conn, resource_id).await
+    }
+
+    async fn remove_resource(&self, resource_id: &str) -> StoreResult<()> {
+        sqlx::query("DELETE FROM resources WHERE resource_id = ?")
+            .bind(resource_id)
+            .execute(&self.pool).await?;
+
+        Ok(())
+    }
+
+    async fn read_signing_keys(&self) -> StoreResult<Vec<defs::SigningKey>> {
+        let rows = sqlx::query_as::<_, SigningKeyRow>("\
+SELECT kid, secret_hex, public_hex, created, expires FROM signing_keys ORDER BY created")
+            .fetch_all(&self.pool).await?;
+
+        rows.into_iter().map(defs::SigningKey::try_from).collect()
+    }
+
+    async fn write_signing_keys(&self, keys: &[defs::SigningKey]) -> StoreResult<()> {
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query("DELETE FROM signing_keys")
+            .execute(&mut tx).await?;
+        for key in keys {
+            insert_signing_key(&mut tx, key).await?;
+        }
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    async fn read_salt_secret(&self) -> StoreResult<Option<String>> {
+        let secret_hex = sqlx::query_scalar::<_, String>("SELECT secret_hex FROM salt_secret WHERE id = 1")
+            .fetch_optional(&self.pool).await?;
+
+        Ok(secret_hex)
+    }
+
+    async fn add_salt_secret(&self, secret_hex: &str) -> StoreResult<()> {
+        let mut conn = self.pool.acquire().await?;
+
+        insert_salt_secret(&mut conn, secret_hex).await
+    }
+
+    async fn write_salt_secret(&self, secret_hex: &str) -> StoreResult<()> {
+        sqlx::query("UPDATE salt_secret SET secret_hex = ? WHERE id = 1")
+            .bind(secret_hex)
+            .execute(&self.pool).await?;
+
+        Ok(())
+    }
+
+    async fn read_login_attempts(&self, key: &str) -> StoreResult<defs::LoginAttempts> {
+        let row = sqlx::query_as::<_, LoginAttemptsRow>("\
+SELECT failures, last_failure, locked_until FROM login_attempts WHERE attempt_key = ?")
+            .bind(key)
+            .fetch_optional(&self.pool).await?;
+
+        row.map(defs::LoginAttempts::try_from).transpose().map(Option::unwrap_or_default)
+    }
+
+    async fn write_login_attempts(&self, key: &str, attempts: &defs::LoginAttempts) -> StoreResult<()> {
+        sqlx::query("\
+INSERT OR REPLACE INTO login_attempts (attempt_key, failures, last_failure, locked_until)
+VALUES (?, ?, ?, ?)")
+            .bind(key)
+            .bind(attempts.failures)
+            .bind(encode_time(attempts.last_failure)?)
+            .bind(encode_time(attempts.locked_until)?)
+            .execute(&self.pool).await?;
+
+        Ok(())
+    }
+
+    async fn remove_login_attempts(&self, key: &str) -> StoreResult<()> {
+        sqlx::query("DELETE FROM login_attempts WHERE attempt_key = ?")
+            .bind(key)
+            .execute(&self.pool).await?;
+
+        Ok(())
+    }
+
+    async fn prune_login_attempts(&self, now: u64, lockout: u64) -> StoreResult<usize> {
+        let result = sqlx::query("DELETE FROM login_attempts WHERE locked_until <= ? AND last_failure <= ?")
+            .bind(encode_time(now)?)
+            .bind(encode_time(now.saturating_sub(lockout))?)
+            .execute(&self.pool).await?;
+
+        Ok(result.rows_affected() as usize)
+    }
+
+    async fn read_refresh_token(&self, token_hash: &str) -> StoreResult<defs::RefreshToken> {
+        let row = sqlx::query_as::<_, RefreshTokenRow>("\
+SELECT token_hash, family_id, user_hex, issued, expires, used FROM refresh_tokens WHERE token_hash = ?")
+            .bind(token_hash)
+            .fetch_one(&self.pool).await?;
+
+        defs::RefreshToken::try_from(row)
+    }
+
+    async fn write_refresh_token(&self, token: &defs::RefreshToken) -> StoreResult<()> {
+        sqlx::query("\
+INSERT OR REPLACE INTO refresh_tokens (token_hash, family_id, user_hex, issued, expires, used)
+VALUES (?, ?, ?, ?, ?, ?)")
+            .bind(&token.token_hash)
+            .bind(&token.family_id)
+            .bind(&token.user_hex)
+            .bind(encode_time(token.issued)?)
+            .bind(encode_time(token.expires)?)
+            .bind(token.used)
+            .execute(&self.pool).await?;
+
+        Ok(())
+    }
+
+    async fn remove_refresh_family(&self, family_id: &str) -> StoreResult<()> {
+        sqlx::query("DELETE FROM refresh_tokens WHERE family_id = ?")
+            .bind(family_id)
+            .execute(&self.pool).await?;
+
+        Ok(())
+    }
+
+    async fn prune_refresh_tokens(&self, now: u64) -> StoreResult<usize> {
+        let result = sqlx::query("DELETE FROM refresh_tokens WHERE expires <= ?")
+            .bind(encode_time(now)?)
+            .execute(&self.pool).await?;
+
+        Ok(result.rows_affected() as usize)
+    }
+
+    async fn read_mail_token(&self, token_hash: &str) -> StoreResult<defs::MailToken> {
+        let row = sqlx::query_as::<_, MailTokenRow>("\
+SELECT token_hash, user_hex, purpose, contact, issued, expires FROM mail_tokens WHERE token_hash = ?")
+            .bind(token_hash)
+            .fetch_one(&self.pool).await?;
+
+        defs::MailToken::try_from(row)
+    }
+
+    async fn write_mail_token(&self, token: &defs::MailToken) -> StoreResult<()> {
+        sqlx::query("\
+INSERT OR REPLACE INTO mail_tokens (token_hash, user_hex, purpose, contact, issued, expires)
+VALUES (?, ?, ?, ?, ?, ?)")
+            .bind(&token.token_hash)
+            .bind(&token.user_hex)
+            .bind(token.purpose.as_str())
+            .bind(&token.contact)
+            .bind(encode_time(token.issued)?)
+            .bind(encode_time(token.expires)?)
+            .execute(&self.pool).await?;
+
+        Ok(())
+    }
+
+    async fn remove_mail_token(&self, token_hash: &str) -> StoreResult<()> {
+        sqlx::query("DELETE FROM mail_tokens WHERE token_hash = ?")
+            .bind(token_hash)
+            .execute(&self.pool).await?;
+
+        Ok(())
+    }
+
+    async fn prune_mail_tokens(&self, now: u64) -> StoreResult<usize> {
+        let result = sqlx::query("DELETE FROM mail_tokens WHERE expires <= ?")
+            .bind(encode_time(now)?)
+            .execute(&self.pool).await?;
+
+        Ok(result.rows_affected() as usize)
+    }
+
+    async fn revoke_token(&self, token: &defs::RevokedToken) -> StoreResult<()> {
+        sqlx::query("INSERT OR REPLACE INTO revoked_tokens (jti, expires) VALUES (?, ?)")
+            .bind(&token.jti)
+            .bind(encode_time(token.expires)?)
+            .execute(&self.pool).await?;
+
+        Ok(())
+    }
+
+    async fn is_token_revoked(&self, jti: &str) -> StoreResult<bool> {
+        let row = sqlx::query("SELECT jti FROM revoked_tokens WHERE jti = ?")
+            .bind(jti)
+            .fetch_optional(&self.pool).await?;
+
+        Ok(row.is_some())
+    }
+
+    async fn read_revoked_tokens(&self) -> StoreResult<Vec<defs::RevokedToken>> {
+        let rows = sqlx::query_as::<_, RevokedTokenRow>("SELECT jti, expires FROM revoked_tokens ORDER BY expires")
+            .fetch_all(&self.pool).await?;
+
+        rows.into_iter().map(defs::RevokedToken::try_from).collect()
+    }
+
+    async fn prune_revoked_tokens(&self, now: u64) -> StoreResult<usize> {
+        let result = sqlx::query("DELETE FROM revoked_tokens WHERE expires <= ?")
+            .bind(encode_time(now)?)
+            .execute(&self.pool).await?;
+
+        Ok(result.rows_affected() as usize)
+    }
+
+    fn locks(&self) -> &StoreLocks {
+        &self.locks
+    }
+}
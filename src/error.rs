@@ -3,7 +3,11 @@ use std::fmt::{Debug, Display, Formatter, Result as FmtResult};
 
 use serde::Serialize;
 use warp::Reply;
+use warp::body::BodyDeserializeError;
 use warp::http::StatusCode;
+use warp::http::header::{HeaderValue, RETRY_AFTER};
+use warp::reject::{InvalidHeader, InvalidQuery, LengthRequired, MethodNotAllowed, MissingHeader,
+                   PayloadTooLarge, UnsupportedMediaType};
 use warp::reply::Response;
 
 use crate::debug;
@@ -98,6 +102,50 @@ impl IntoError for sqlx::Error {
     fn error_type(&self) -> Errors { Errors::Internal }
 }
 
+impl IntoError for std::io::Error {
+    fn error_type(&self) -> Errors { Errors::IO }
+}
+
+impl IntoError for toml::de::Error {
+    fn error_type(&self) -> Errors { Errors::DecodeInternal }
+}
+
+impl IntoError for std::num::ParseIntError {
+    fn error_type(&self) -> Errors { Errors::DecodeInternal }
+}
+
+impl IntoError for std::str::ParseBoolError {
+    fn error_type(&self) -> Errors { Errors::DecodeInternal }
+}
+
+impl IntoError for std::net::AddrParseError {
+    fn error_type(&self) -> Errors { Errors::DecodeInternal }
+}
+
+impl IntoError for std::time::SystemTimeError {
+    fn error_type(&self) -> Errors { Errors::Internal }
+}
+
+impl IntoError for serde_json::Error {
+    fn error_type(&self) -> Errors { Errors::DecodeInternal }
+}
+
+impl IntoError for lettre::address::AddressError {
+    fn error_type(&self) -> Errors { Errors::DecodeInternal }
+}
+
+impl IntoError for lettre::error::Error {
+    fn error_type(&self) -> Errors { Errors::Internal }
+}
+
+impl IntoError for lettre::transport::smtp::Error {
+    fn error_type(&self) -> Errors { Errors::IO }
+}
+
+impl IntoError for crate::store::StoreError {
+    fn error_type(&self) -> Errors { Errors::Internal }
+}
+
 impl Reply for Error {
     fn into_response(self) -> Response {
         let reply = warp::reply::json(&ErrorReply::from(&self));
@@ -172,6 +220,10 @@ pub enum RejectTypes {
     DecodeExternal,
     Tampered,
     Incorrect,
+    Expired,
+    TooManyRequests,
+    /// A feature the server is not configured for.
+    Unavailable,
     Internal
 }
 
@@ -192,6 +244,9 @@ impl RejectType for RejectTypes {
             RejectTypes::DecodeExternal => 400,
             RejectTypes::Tampered => 400,
             RejectTypes::Incorrect => 400,
+            RejectTypes::Expired => 401,
+            RejectTypes::TooManyRequests => 429,
+            RejectTypes::Unavailable => 503,
             RejectTypes::Internal => 500,
         }
     }
@@ -206,6 +261,9 @@ impl RejectType for RejectTypes {
             RejectTypes::DecodeExternal => "Decode External Reject",
             RejectTypes::Tampered => "Tampered Reject",
             RejectTypes::Incorrect => "Incorrect Input Reject",
+            RejectTypes::Expired => "Expired Reject",
+            RejectTypes::TooManyRequests => "Too Many Requests Reject",
+            RejectTypes::Unavailable => "Unavailable Reject",
             RejectTypes::Internal => "Internal Error Reject"
         }
     }
@@ -222,49 +280,31 @@ struct ErrorMessage {
     message: String,
 }
 
-pub(crate) async fn handle_err_reject(err: warp::reject::Rejection) -> Result<impl warp::Reply, warp::Rejection> {
-    let code;
-    let message: String;
-    let error_str: String;
+/// An [`ErrorReject`] of a request that may be retried after `retry_after` seconds, which is sent
+/// to the requester in a `Retry-After` header.
+#[derive(Debug)]
+pub struct RetryAfterReject { pub reject: ErrorReject, pub retry_after: u64 }
 
+impl warp::reject::Reject for RetryAfterReject {}
+
+pub(crate) async fn handle_err_reject(err: warp::reject::Rejection) -> Result<impl warp::Reply, warp::Rejection> {
     if err.is_not_found() {
-        code = StatusCode::NOT_FOUND;
-        message = "NOT_FOUND".to_owned();
-        error_str = "".to_string();
-        custom_rejection_text(code, message, error_str)
+        let code = StatusCode::NOT_FOUND;
+        let message = "NOT_FOUND".to_owned();
+        let error_str = "".to_string();
+        Ok(custom_rejection_text(code, message, error_str))
     }
     else if let Some(error_reject) = err.find::<ErrorReject>() {
-        code = StatusCode::from_u16(error_reject.rt.code()).unwrap();
-
-        // Error messages can be propagated to the requester by putting them in between '@@@' on
-        // both sides.
-        let split_app = error_reject.e.split("@@@").collect::<Vec<&str>>();
-        let n_split = split_app.len();
-        let mut msg_apps: Vec<String> = Vec::new();
-        let mut err_apps: Vec<String> = Vec::new();
-        if n_split % 2 == 1 {
-            for (i, e) in split_app.iter().enumerate() {
-                if i % 2 == 0 {
-                    err_apps.push((*e).to_owned());
-                }
-                else {
-                    msg_apps.push((*e).to_owned())
-                }
-            }
-        }
-        else {
-            err_apps.push(error_reject.e.clone());
-        }
-        let msg_apps = msg_apps.join(" ");
-        let err_apps = err_apps.join(" ");
-
-        message = format!("{}: {} {}", error_reject.rt.name(), error_reject.msg, msg_apps);
+        let (code, message, error_str) = error_reject_text(error_reject);
 
-        error_str = err_apps;
-
-        //
+        Ok(custom_rejection_text(code, message, error_str))
+    }
+    else if let Some(retry_reject) = err.find::<RetryAfterReject>() {
+        let (code, message, error_str) = error_reject_text(&retry_reject.reject);
 
-        custom_rejection_text(code, message, error_str)
+        let mut res = custom_rejection_text(code, message, error_str);
+        res.headers_mut().insert(RETRY_AFTER, HeaderValue::from(retry_reject.retry_after));
+        Ok(res)
     }
     else {
         Err(err)
@@ -280,11 +320,72 @@ pub(crate) async fn handle_err_reject(err: warp::reject::Rejection) -> Result<im
     // }
 }
 
+fn error_reject_text(error_reject: &ErrorReject) -> (StatusCode, String, String) {
+    let code = StatusCode::from_u16(error_reject.rt.code()).unwrap();
+
+    // Error messages can be propagated to the requester by putting them in between '@@@' on
+    // both sides.
+    let split_app = error_reject.e.split("@@@").collect::<Vec<&str>>();
+    let n_split = split_app.len();
+    let mut msg_apps: Vec<String> = Vec::new();
+    let mut err_apps: Vec<String> = Vec::new();
+    if n_split % 2 == 1 {
+        for (i, e) in split_app.iter().enumerate() {
+            if i % 2 == 0 {
+                err_apps.push((*e).to_owned());
+            }
+            else {
+                msg_apps.push((*e).to_owned())
+            }
+        }
+    }
+    else {
+        err_apps.push(error_reject.e.clone());
+    }
+    let msg_apps = msg_apps.join(" ");
+    let err_apps = err_apps.join(" ");
+
+    let message = format!("{}: {} {}", error_reject.rt.name(), error_reject.msg, msg_apps);
+
+    (code, message, err_apps)
+}
+
+/// Replies to the rejections of warp's own filters, so that every reply passes through the CORS
+/// filter.
 pub(crate) async fn handle_reject(err: warp::reject::Rejection) -> Result<impl warp::Reply, std::convert::Infallible> {
-    Ok(warp::reply::ReplyRejection::from(err))
+    let (code, message) = if let Some(e) = err.find::<MethodNotAllowed>() {
+        (StatusCode::METHOD_NOT_ALLOWED, e.to_string())
+    }
+    else if let Some(e) = err.find::<BodyDeserializeError>() {
+        (StatusCode::BAD_REQUEST, e.to_string())
+    }
+    else if let Some(e) = err.find::<InvalidQuery>() {
+        (StatusCode::BAD_REQUEST, e.to_string())
+    }
+    else if let Some(e) = err.find::<InvalidHeader>() {
+        (StatusCode::BAD_REQUEST, e.to_string())
+    }
+    else if let Some(e) = err.find::<MissingHeader>() {
+        (StatusCode::BAD_REQUEST, e.to_string())
+    }
+    else if let Some(e) = err.find::<LengthRequired>() {
+        (StatusCode::LENGTH_REQUIRED, e.to_string())
+    }
+    else if let Some(e) = err.find::<PayloadTooLarge>() {
+        (StatusCode::PAYLOAD_TOO_LARGE, e.to_string())
+    }
+    else if let Some(e) = err.find::<UnsupportedMediaType>() {
+        (StatusCode::UNSUPPORTED_MEDIA_TYPE, e.to_string())
+    }
+    else {
+        log::error!("Unhandled rejection: {:?}", err);
+        (StatusCode::INTERNAL_SERVER_ERROR, "UNHANDLED_REJECTION".to_owned())
+    };
+
+    Ok(custom_rejection_text(code, message, "".to_string()))
 }
 
-fn custom_rejection_text(code: StatusCode, message: String, error_str: String) -> Result<impl warp::Reply, warp::Rejection> {
+fn custom_rejection_text(code: StatusCode, message: String, error_str: String) -> Response {
     let error_message = &ErrorMessage {
         code: code.as_u16(),
         message: message.to_owned(),
@@ -295,5 +396,5 @@ fn custom_rejection_text(code: StatusCode, message: String, error_str: String) -
     debug!("Rejection {}: {}", code, message);
     debug!("Err: {}", error_str);
 
-    Ok(warp::reply::with_status(j, code))
+    warp::reply::with_status(j, code).into_response()
 }
\ No newline at end of file
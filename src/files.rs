@@ -1,20 +1,19 @@
-use std::path::Path;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 
-use tokio::fs::{create_dir_all, File, OpenOptions};
+use async_trait::async_trait;
+use tokio::fs::{create_dir_all, hard_link, read_dir, remove_file, rename, File};
 use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+use tokio::sync::Mutex;
 
-use crate::{Deserialize, Serialize};
 use crate::defs;
-use crate::error::{ErrorReject, RejectTypes};
-
-#[derive(Serialize, Deserialize, Debug)]
-pub struct SaveUserJson {
-    pub user_hex: String,
-    pub password_hash_hex: String,
-    pub salt_hex: String,
-    pub secret_hex: String,
-    pub public_hex: String,
-}
+use crate::store::{Store, StoreError, StoreErrors, StoreLocks, StoreResult};
+
+/// Suffix of the temporary files that are renamed over their target once fully written.
+const TEMP_SUFFIX: &str = ".tmp";
+
+static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
 
 pub fn io_is_nonexistent(e: &io::Error) -> bool {
     e.raw_os_error().map_or(false, |i| {
@@ -22,114 +21,611 @@ pub fn io_is_nonexistent(e: &io::Error) -> bool {
     })
 }
 
-pub fn io_nonexistent_reject(e: io::Error, msg: &'static str, ne_msg: &'static str) -> ErrorReject {
-    let err_msg = e.to_string();
-    if io_is_nonexistent(&e) {
-        ErrorReject {
-            rt: RejectTypes::NonExistent,
-            msg: ne_msg,
-            e: err_msg
+/// [`Store`] keeping every user and their claims in a separate JSON file.
+///
+/// The layout under `root` is:
+/// - `users/<user_hex>.json`
+/// - `claims/<user_hex>.json`
+/// - `resources.json`
+/// - `signing_keys.json`
+/// - `salt_secret.json`
+/// - `login_attempts/<hex of key>.json`
+/// - `refresh_tokens/<token_hash>.json`
+/// - `mail_tokens/<token_hash>.json`
+/// - `revoked_tokens.json`
+///
+/// The ids of revoked tokens are kept in memory once read, as they are looked up on every request.
+/// Only the server writes `revoked_tokens.json`, so the copy is updated with every write instead of
+/// being read again.
+pub struct FileStore {
+    root: PathBuf,
+    locks: StoreLocks,
+    resources_file: Mutex<()>,
+    /// Guards `revoked_tokens.json` and holds its ids, `None` until they are read.
+    revoked_tokens: Mutex<Option<HashSet<String>>>,
+}
+
+impl FileStore {
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        FileStore {
+            root: root.into(),
+            locks: StoreLocks::default(),
+            resources_file: Mutex::new(()),
+            revoked_tokens: Mutex::new(None)
         }
     }
-    else {
-        ErrorReject {
-            rt: RejectTypes::IO,
-            msg,
-            e: err_msg
+
+    fn user_path(&self, user_hex: &str) -> PathBuf {
+        self.root.join("users/x").with_file_name(user_hex).with_extension("json")
+    }
+
+    fn claims_path(&self, user_hex: &str) -> PathBuf {
+        self.root.join("claims/x").with_file_name(user_hex).with_extension("json")
+    }
+
+    fn resources_path(&self) -> PathBuf {
+        self.root.join("resources.json")
+    }
+
+    fn signing_keys_path(&self) -> PathBuf {
+        self.root.join("signing_keys.json")
+    }
+
+    fn salt_secret_path(&self) -> PathBuf {
+        self.root.join("salt_secret.json")
+    }
+
+    /// Counter keys contain characters that are not safe in file names, e.g. IPv6 addresses.
+    fn login_attempts_path(&self, key: &str) -> PathBuf {
+        self.root.join("login_attempts").join(hex::encode(key)).with_extension("json")
+    }
+
+    fn revoked_tokens_path(&self) -> PathBuf {
+        self.root.join("revoked_tokens.json")
+    }
+
+    /// Replaces the revocation list by what `update` makes of it and updates the cached ids.
+    async fn update_revoked_tokens<F: FnOnce(&mut Vec<defs::RevokedToken>)>(&self, update: F) -> StoreResult<()> {
+        let mut cached = self.revoked_tokens.lock().await;
+        let mut revoked = self.read_revoked_tokens().await?;
+        update(&mut revoked);
+
+        // A failed write may still have replaced the file, so the ids are read again next time.
+        *cached = None;
+        write_json(&self.revoked_tokens_path(), &defs::RevokedTokens { revoked: revoked.clone() }).await?;
+        *cached = Some(revoked.into_iter().map(|token| token.jti).collect());
+
+        Ok(())
+    }
+
+    fn refresh_token_path(&self, token_hash: &str) -> PathBuf {
+        self.root.join("refresh_tokens/x").with_file_name(token_hash).with_extension("json")
+    }
+
+    fn mail_token_path(&self, token_hash: &str) -> PathBuf {
+        self.root.join("mail_tokens/x").with_file_name(token_hash).with_extension("json")
+    }
+
+    /// Removes every refresh token for which `remove` returns true, returning how many there were.
+    async fn remove_refresh_tokens<F: Fn(&defs::RefreshToken) -> bool>(&self, remove: F) -> StoreResult<usize> {
+        let mut removed = 0;
+        for token_hash in json_stems(&self.root.join("refresh_tokens")).await? {
+            let path = self.refresh_token_path(&token_hash);
+            let token = match read_json::<defs::RefreshToken>(&path).await {
+                Ok(token) => token,
+                Err(StoreError { error_type: StoreErrors::NonExistent, .. }) => continue,
+                Err(e) => return Err(e)
+            };
+            if remove(&token) {
+                match remove_file(&path).await {
+                    Err(e) if !io_is_nonexistent(&e) => return Err(e.into()),
+                    _ => removed += 1
+                }
+            }
         }
+
+        Ok(removed)
     }
-}
 
-pub async fn prepare_directories() -> Result<((), (), ()), io::Error> {
-    let new_resources = write_new_user_resource(vec![]);
-    let create_users = create_dir_all("resources/users");
-    let create_claims = create_dir_all("resources/claims");
-    tokio::try_join!(new_resources, create_users, create_claims)
-}
+    /// Lists the user hexes of every user file.
+    pub async fn user_hexes(&self) -> StoreResult<Vec<String>> {
+        json_stems(&self.root.join("users")).await
+    }
 
-pub async fn read_user(user_hex: &str, secret: bool) -> Result<SaveUserJson, io::Error> {
-    let mut f = open_user_file(user_hex).await?;
-    let mut buffer = String::new();
-    f.read_to_string(&mut buffer).await?;
+    /// Lists the user hexes of every claims file, which may include users without a user file.
+    pub async fn claims_user_hexes(&self) -> StoreResult<Vec<String>> {
+        json_stems(&self.root.join("claims")).await
+    }
 
-    let mut save_user: SaveUserJson = serde_json::from_str(&buffer)?;
-    if !secret {
-        save_user.secret_hex = "".to_owned();
-        save_user.password_hash_hex = "".to_owned();
+    /// Lists the keys of every failed login counter.
+    pub async fn login_attempt_keys(&self) -> StoreResult<Vec<String>> {
+        json_stems_if_exists(&self.root.join("login_attempts")).await?.iter()
+            .map(|stem| {
+                hex::decode(stem).ok()
+                    .and_then(|key| String::from_utf8(key).ok())
+                    .ok_or_else(|| StoreError {
+                        error_type: StoreErrors::Decode,
+                        e: format!("login attempts file name {:?}", stem)
+                    })
+            })
+            .collect()
     }
 
-    Ok(save_user)
+    /// Lists the hashes of every refresh token.
+    pub async fn refresh_token_hashes(&self) -> StoreResult<Vec<String>> {
+        json_stems_if_exists(&self.root.join("refresh_tokens")).await
+    }
+
+    /// Lists the hashes of every mailed token.
+    pub async fn mail_token_hashes(&self) -> StoreResult<Vec<String>> {
+        json_stems_if_exists(&self.root.join("mail_tokens")).await
+    }
+
+    /// Replaces fields of the file of an existing user. The file is locked from reading until
+    /// writing it, so that concurrent updates of other fields are not lost.
+    async fn update_user<F: FnOnce(&mut defs::SaveUserJson)>(&self, user_hex: &str, update: F) -> StoreResult<()> {
+        let _guard = self.locks.lock_user_record(user_hex).await;
+        let mut save_user = self.read_user(user_hex, true).await?;
+        update(&mut save_user);
+
+        write_json(&self.user_path(user_hex), &save_user).await
+    }
+
+    /// Lists temporary files left behind by writes that were interrupted, e.g. by a crash. The
+    /// files they were meant to replace are still intact.
+    pub async fn leftover_temp_files(&self) -> StoreResult<Vec<PathBuf>> {
+        let mut leftover = Vec::new();
+        for dir in &[self.root.clone(), self.root.join("users"), self.root.join("claims"),
+                    self.root.join("login_attempts"), self.root.join("refresh_tokens"),
+                    self.root.join("mail_tokens")] {
+            let mut entries = read_dir(dir).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                let path = entry.path();
+                if path.to_string_lossy().ends_with(TEMP_SUFFIX) {
+                    leftover.push(path);
+                }
+            }
+        }
+        leftover.sort();
+
+        Ok(leftover)
+    }
 }
 
-pub async fn read_user_claims(user_hex: &str) -> Result<defs::Tiauth, io::Error> {
-    let path = Path::new("resources/claims/x").with_file_name(user_hex);
-    let mut file = File::open(path.with_extension("json")).await?;
+async fn json_stems(dir: &Path) -> StoreResult<Vec<String>> {
+    let mut entries = read_dir(dir).await?;
+    let mut stems = Vec::new();
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if path.extension().and_then(|ext| ext.to_str()) == Some("json") {
+            if let Some(stem) = path.file_stem().and_then(|stem| stem.to_str()) {
+                stems.push(stem.to_owned());
+            }
+        }
+    }
+    stems.sort();
+
+    Ok(stems)
+}
+
+/// Like [`json_stems`], but a directory that does not exist has no stems. Data directories from
+/// before a record type was added do not have its directory yet.
+async fn json_stems_if_exists(dir: &Path) -> StoreResult<Vec<String>> {
+    match json_stems(dir).await {
+        Err(StoreError { error_type: StoreErrors::NonExistent, .. }) => Ok(vec![]),
+        result => result
+    }
+}
+
+async fn read_json<T: serde::de::DeserializeOwned>(path: &Path) -> StoreResult<T> {
+    let mut file = File::open(path).await?;
     let mut buffer = String::new();
     file.read_to_string(&mut buffer).await?;
 
-    let user_claims: defs::Tiauth = serde_json::from_str(&buffer)?;
-
-    Ok(user_claims)
+    Ok(serde_json::from_str(&buffer)?)
 }
 
-pub async fn register_user(user_json: &defs::UserJson, public_hex: String, secret_hex: String) -> tokio::io::Result<File> {
-    let save_user_json = SaveUserJson {
-        user_hex: user_json.user_hex.to_owned(),
-        password_hash_hex: user_json.password_hash_hex.to_owned(),
-        salt_hex: user_json.salt_hex.to_owned(),
-        secret_hex,
-        public_hex
-    };
-
-    let j = serde_json::to_string_pretty(&save_user_json)?;
-    let path = Path::new("resources/users/x").with_file_name(&user_json.user_hex);
-    let path = path.with_extension("json");
-    let mut file = File::create(path).await?;
-    file.write_all(j.as_bytes()).await?;
+/// Returns a new, unique temporary path next to `path`.
+fn temp_path(path: &Path) -> PathBuf {
+    let n = TEMP_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let mut temp_name = path.file_name().unwrap_or_default().to_owned();
+    temp_name.push(format!(".{}.{}{}", std::process::id(), n, TEMP_SUFFIX));
+
+    path.with_file_name(temp_name)
+}
 
-    Ok(file)
+/// Removes files on a best-effort basis, used to clean up after a failed write.
+async fn remove_files<'a, I: IntoIterator<Item = &'a PathBuf>>(paths: I) {
+    for path in paths {
+        let _ = remove_file(path).await;
+    }
 }
 
-pub async fn write_new_user_resource(resources_arr: Vec<String>) -> tokio::io::Result<()> {
-    let new_resources = defs::Resources {
-        resources: resources_arr,
-    };
+/// Writes `value` to a new temporary file next to `path` and syncs it to disk, returning the
+/// temporary path.
+async fn write_temp_json<T: serde::Serialize>(path: &Path, value: &T) -> StoreResult<PathBuf> {
+    let j = serde_json::to_string_pretty(value)?;
+    let temp_path = temp_path(path);
 
-    let j = serde_json::to_string_pretty(&new_resources)?;
-    let path = Path::new("resources/x").with_file_name("resources");
-    let path = path.with_extension("json");
-    let mut file = File::create(path).await?;
+    let mut file = File::create(&temp_path).await?;
     file.write_all(j.as_bytes()).await?;
+    file.sync_all().await?;
+
+    Ok(temp_path)
+}
+
+/// Syncs the directory containing `path`, so that a rename into it survives a crash.
+async fn sync_parent(path: &Path) -> StoreResult<()> {
+    if let Some(parent) = path.parent() {
+        File::open(parent).await?.sync_all().await?;
+    }
 
     Ok(())
 }
 
-pub async fn write_user_claims(user_hex: &str, user_claims: &defs::Tiauth) -> tokio::io::Result<File> {
-    let j = serde_json::to_string_pretty(user_claims)?;
+/// Replaces the file at `path` with `value` as JSON. The file is either fully replaced or left
+/// as it was, even if the process crashes halfway.
+async fn write_json<T: serde::Serialize>(path: &Path, value: &T) -> StoreResult<()> {
+    let temp_path = write_temp_json(path, value).await?;
+    if let Err(e) = rename(&temp_path, path).await {
+        let _ = remove_file(&temp_path).await;
+        return Err(e.into())
+    }
 
-    let path = Path::new("resources/claims/x").with_file_name(user_hex);
-    let mut file = File::create(path.with_extension("json")).await?;
-    file.write_all(j.as_bytes()).await?;
+    sync_parent(path).await
+}
+
+/// Like [`write_json`], but fails with [`StoreErrors::AlreadyExists`] if `path` already exists.
+async fn write_new_json<T: serde::Serialize>(path: &Path, value: &T) -> StoreResult<()> {
+    let temp_path = write_temp_json(path, value).await?;
+    let linked = hard_link(&temp_path, path).await;
+    remove_file(&temp_path).await?;
+    if let Err(e) = linked {
+        return Err(if e.kind() == io::ErrorKind::AlreadyExists {
+            StoreError {
+                error_type: StoreErrors::AlreadyExists,
+                e: format!("{:?}", path)
+            }
+        }
+        else {
+            e.into()
+        })
+    }
 
-    Ok(file)
+    sync_parent(path).await
 }
 
-pub async fn open_user_file(user_hex: &str) -> tokio::io::Result<File> {
-    let path = Path::new("resources/users/x").with_file_name(user_hex);
-    let file = File::open(path.with_extension("json")).await?;
+/// Replaces several files at once. If any of them cannot be replaced, the ones that already were
+/// are restored from a backup, so that either all files are written or none are.
+async fn write_many_json<T: serde::Serialize>(writes: &[(PathBuf, &T)]) -> StoreResult<()> {
+    let mut temp_paths = Vec::new();
+    for (path, value) in writes {
+        match write_temp_json(path, value).await {
+            Ok(temp_path) => temp_paths.push(temp_path),
+            Err(e) => {
+                remove_files(&temp_paths).await;
+                return Err(e)
+            }
+        }
+    }
+
+    let mut backups = Vec::new();
+    for (path, _) in writes {
+        let backup = temp_path(path);
+        match hard_link(path, &backup).await {
+            Ok(()) => backups.push(Some(backup)),
+            Err(e) if io_is_nonexistent(&e) => backups.push(None),
+            Err(e) => {
+                remove_files(&temp_paths).await;
+                remove_files(backups.iter().flatten()).await;
+                return Err(e.into())
+            }
+        }
+    }
 
-    Ok(file)
+    for (replaced, ((path, _), temp_path)) in writes.iter().zip(&temp_paths).enumerate() {
+        if let Err(e) = rename(temp_path, path).await {
+            for ((path, _), backup) in writes.iter().zip(&backups).take(replaced) {
+                let _ = match backup {
+                    Some(backup) => rename(backup, path).await,
+                    None => remove_file(path).await,
+                };
+            }
+            remove_files(&temp_paths[replaced..]).await;
+            remove_files(backups.iter().flatten()).await;
+            return Err(e.into())
+        }
+    }
+
+    remove_files(backups.iter().flatten()).await;
+    for (path, _) in writes {
+        sync_parent(path).await?;
+    }
+
+    Ok(())
 }
 
-pub async fn open_resources() -> tokio::io::Result<defs::Resources> {
-    let path = Path::new("resources/x").with_file_name("resources");
-    let path = path.with_extension("json");
-    eprintln!("{:?}", path);
-    let mut file = OpenOptions::new().write(true).read(true).create(true).open(path).await?;
-    let mut buffer = String::new();
-    file.read_to_string(&mut buffer).await?;
+#[async_trait]
+impl Store for FileStore {
+    async fn prepare(&self) -> StoreResult<()> {
+        create_dir_all(self.root.join("users")).await?;
+        create_dir_all(self.root.join("claims")).await?;
+        create_dir_all(self.root.join("login_attempts")).await?;
+        create_dir_all(self.root.join("refresh_tokens")).await?;
+        create_dir_all(self.root.join("mail_tokens")).await?;
+        for path in self.leftover_temp_files().await? {
+            log::warn!("Found temporary file {:?} from an interrupted write, it can be removed.", path);
+        }
+        if !self.resources_path().exists() {
+            write_json(&self.resources_path(), &defs::Resources { resources: vec![] }).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn read_user(&self, user_hex: &str, secret: bool) -> StoreResult<defs::SaveUserJson> {
+        let mut save_user: defs::SaveUserJson = read_json(&self.user_path(user_hex)).await?;
+        if !secret {
+            save_user.secret_hex = "".to_owned();
+            save_user.password_hash_hex = "".to_owned();
+        }
+
+        Ok(save_user)
+    }
+
+    async fn register_user(&self, user_json: &defs::UserJson, public_hex: String,
+                           secret_hex: String) -> StoreResult<()> {
+        let path = self.user_path(&user_json.user_hex);
 
-    let resources: defs::Resources = serde_json::from_str(&buffer)?;
+        let save_user_json = defs::SaveUserJson {
+            user_hex: user_json.user_hex.to_string(),
+            password_hash_hex: user_json.password_hash_hex.to_owned(),
+            salt_hex: user_json.salt_hex.to_owned(),
+            secret_hex,
+            public_hex,
+            password_changed: None,
+            tokens_not_before: None,
+            contact: None,
+            contact_verified: false
+        };
 
-    Ok(resources)
-}
\ No newline at end of file
+        write_new_json(&path, &save_user_json).await
+    }
+
+    async fn write_user_password(&self, user_hex: &str, password_hash: &str) -> StoreResult<()> {
+        self.update_user(user_hex, |save_user| {
+            save_user.password_hash_hex = password_hash.to_owned();
+        }).await
+    }
+
+    async fn write_user_credentials(&self, user_hex: &str, password_hash: &str, salt_hex: &str,
+                                    changed: u64, revoke_sessions: bool) -> StoreResult<()> {
+        self.update_user(user_hex, |save_user| {
+            save_user.password_hash_hex = password_hash.to_owned();
+            save_user.salt_hex = salt_hex.to_owned();
+            save_user.password_changed = Some(changed);
+            if revoke_sessions {
+                save_user.tokens_not_before = Some(changed);
+            }
+        }).await
+    }
+
+    async fn write_tokens_not_before(&self, user_hex: &str, not_before: u64) -> StoreResult<()> {
+        self.update_user(user_hex, |save_user| {
+            save_user.tokens_not_before = Some(not_before);
+        }).await
+    }
+
+    async fn write_user_contact(&self, user_hex: &str, contact: Option<&str>, verified: bool) -> StoreResult<()> {
+        self.update_user(user_hex, |save_user| {
+            save_user.contact = contact.map(str::to_owned);
+            save_user.contact_verified = verified;
+        }).await
+    }
+
+    async fn user_secrets(&self) -> StoreResult<Vec<(String, String)>> {
+        let mut secrets = Vec::new();
+        for user_hex in self.user_hexes().await? {
+            let save_user = self.read_user(&user_hex, true).await?;
+            secrets.push((user_hex, save_user.secret_hex));
+        }
+
+        Ok(secrets)
+    }
+
+    async fn write_user_secret(&self, user_hex: &str, secret_hex: &str) -> StoreResult<()> {
+        self.update_user(user_hex, |save_user| {
+            save_user.secret_hex = secret_hex.to_owned();
+        }).await
+    }
+
+    async fn read_user_claims(&self, user_hex: &str) -> StoreResult<defs::Tiauth> {
+        read_json(&self.claims_path(user_hex)).await
+    }
+
+    async fn write_user_claims(&self, user_hex: &str, user_claims: &defs::Tiauth) -> StoreResult<()> {
+        write_json(&self.claims_path(user_hex), user_claims).await
+    }
+
+    async fn write_many_user_claims(&self, users_claims: &[(String, defs::Tiauth)]) -> StoreResult<()> {
+        let writes: Vec<(PathBuf, &defs::Tiauth)> = users_claims.iter()
+            .map(|(user_hex, user_claims)| (self.claims_path(user_hex), user_claims))
+            .collect();
+
+        write_many_json(&writes).await
+    }
+
+    async fn read_resources(&self) -> StoreResult<defs::Resources> {
+        read_json(&self.resources_path()).await
+    }
+
+    async fn add_resource(&self, resource_id: &str) -> StoreResult<()> {
+        let _guard = self.resources_file.lock().await;
+        let mut resources = self.read_resources().await?;
+        if resources.resources.iter().any(|r| r == resource_id) {
+            return Err(StoreError {
+                error_type: StoreErrors::AlreadyExists,
+                e: resource_id.to_owned()
+            })
+        }
+        resources.resources.push(resource_id.to_owned());
+
+        write_json(&self.resources_path(), &resources).await
+    }
+
+    async fn remove_resource(&self, resource_id: &str) -> StoreResult<()> {
+        let _guard = self.resources_file.lock().await;
+        let mut resources = self.read_resources().await?;
+        resources.resources.retain(|r| r != resource_id);
+
+        write_json(&self.resources_path(), &resources).await
+    }
+
+    async fn read_signing_keys(&self) -> StoreResult<Vec<defs::SigningKey>> {
+        match read_json::<defs::SigningKeys>(&self.signing_keys_path()).await {
+            Ok(signing_keys) => Ok(signing_keys.keys),
+            Err(StoreError { error_type: StoreErrors::NonExistent, .. }) => Ok(vec![]),
+            Err(e) => Err(e)
+        }
+    }
+
+    async fn write_signing_keys(&self, keys: &[defs::SigningKey]) -> StoreResult<()> {
+        write_json(&self.signing_keys_path(), &defs::SigningKeys { keys: keys.to_vec() }).await
+    }
+
+    async fn read_salt_secret(&self) -> StoreResult<Option<String>> {
+        match read_json::<defs::StoredSaltSecret>(&self.salt_secret_path()).await {
+            Ok(salt_secret) => Ok(Some(salt_secret.secret_hex)),
+            Err(StoreError { error_type: StoreErrors::NonExistent, .. }) => Ok(None),
+            Err(e) => Err(e)
+        }
+    }
+
+    async fn add_salt_secret(&self, secret_hex: &str) -> StoreResult<()> {
+        write_new_json(&self.salt_secret_path(), &defs::StoredSaltSecret { secret_hex: secret_hex.to_owned() }).await
+    }
+
+    async fn write_salt_secret(&self, secret_hex: &str) -> StoreResult<()> {
+        write_json(&self.salt_secret_path(), &defs::StoredSaltSecret { secret_hex: secret_hex.to_owned() }).await
+    }
+
+    async fn read_login_attempts(&self, key: &str) -> StoreResult<defs::LoginAttempts> {
+        match read_json(&self.login_attempts_path(key)).await {
+            Err(StoreError { error_type: StoreErrors::NonExistent, .. }) => Ok(defs::LoginAttempts::default()),
+            result => result
+        }
+    }
+
+    async fn write_login_attempts(&self, key: &str, attempts: &defs::LoginAttempts) -> StoreResult<()> {
+        write_json(&self.login_attempts_path(key), attempts).await
+    }
+
+    async fn remove_login_attempts(&self, key: &str) -> StoreResult<()> {
+        match remove_file(self.login_attempts_path(key)).await {
+            Err(e) if io_is_nonexistent(&e) => Ok(()),
+            result => Ok(result?)
+        }
+    }
+
+    async fn prune_login_attempts(&self, now: u64, lockout: u64) -> StoreResult<usize> {
+        let mut removed = 0;
+        for key in self.login_attempt_keys().await? {
+            let _guard = self.lock_login_attempts(&key).await;
+            let attempts = self.read_login_attempts(&key).await?;
+            if attempts.locked_until <= now && attempts.last_failure.saturating_add(lockout) <= now {
+                self.remove_login_attempts(&key).await?;
+                removed += 1;
+            }
+        }
+
+        Ok(removed)
+    }
+
+    async fn read_refresh_token(&self, token_hash: &str) -> StoreResult<defs::RefreshToken> {
+        read_json(&self.refresh_token_path(token_hash)).await
+    }
+
+    async fn write_refresh_token(&self, token: &defs::RefreshToken) -> StoreResult<()> {
+        write_json(&self.refresh_token_path(&token.token_hash), token).await
+    }
+
+    async fn remove_refresh_family(&self, family_id: &str) -> StoreResult<()> {
+        self.remove_refresh_tokens(|token| token.family_id == family_id).await.map(|_| ())
+    }
+
+    async fn prune_refresh_tokens(&self, now: u64) -> StoreResult<usize> {
+        self.remove_refresh_tokens(|token| token.expires <= now).await
+    }
+
+    async fn read_mail_token(&self, token_hash: &str) -> StoreResult<defs::MailToken> {
+        read_json(&self.mail_token_path(token_hash)).await
+    }
+
+    async fn write_mail_token(&self, token: &defs::MailToken) -> StoreResult<()> {
+        write_json(&self.mail_token_path(&token.token_hash), token).await
+    }
+
+    async fn remove_mail_token(&self, token_hash: &str) -> StoreResult<()> {
+        match remove_file(self.mail_token_path(token_hash)).await {
+            Err(e) if io_is_nonexistent(&e) => Ok(()),
+            result => Ok(result?)
+        }
+    }
+
+    async fn prune_mail_tokens(&self, now: u64) -> StoreResult<usize> {
+        let mut removed = 0;
+        for token_hash in json_stems(&self.root.join("mail_tokens")).await? {
+            let token = match self.read_mail_token(&token_hash).await {
+                Ok(token) => token,
+                Err(StoreError { error_type: StoreErrors::NonExistent, .. }) => continue,
+                Err(e) => return Err(e)
+            };
+            if token.expires <= now {
+                self.remove_mail_token(&token_hash).await?;
+                removed += 1;
+            }
+        }
+
+        Ok(removed)
+    }
+
+    async fn revoke_token(&self, token: &defs::RevokedToken) -> StoreResult<()> {
+        self.update_revoked_tokens(|revoked| {
+            revoked.retain(|revoked_token| revoked_token.jti != token.jti);
+            revoked.push(token.clone());
+        }).await
+    }
+
+    async fn is_token_revoked(&self, jti: &str) -> StoreResult<bool> {
+        let mut cached = self.revoked_tokens.lock().await;
+        if cached.is_none() {
+            let revoked = self.read_revoked_tokens().await?;
+            *cached = Some(revoked.into_iter().map(|token| token.jti).collect());
+        }
+
+        Ok(cached.as_ref().map_or(false, |jtis| jtis.contains(jti)))
+    }
+
+    async fn read_revoked_tokens(&self) -> StoreResult<Vec<defs::RevokedToken>> {
+        match read_json::<defs::RevokedTokens>(&self.revoked_tokens_path()).await {
+            Ok(revoked_tokens) => Ok(revoked_tokens.revoked),
+            Err(StoreError { error_type: StoreErrors::NonExistent, .. }) => Ok(vec![]),
+            Err(e) => Err(e)
+        }
+    }
+
+    async fn prune_revoked_tokens(&self, now: u64) -> StoreResult<usize> {
+        let mut pruned = 0;
+        self.update_revoked_tokens(|revoked| {
+            let before = revoked.len();
+            revoked.retain(|token| token.expires > now);
+            pruned = before - revoked.len();
+        }).await?;
+
+        Ok(pruned)
+    }
+
+    fn locks(&self) -> &StoreLocks {
+        &self.locks
+    }
+}
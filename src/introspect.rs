@@ -0,0 +1,61 @@
+//! Token introspection (RFC 7662), for resource servers that ask the auth server whether a token
+//! is active instead of verifying it themselves.
+
+use crate::{Deserialize, Serialize};
+use crate::auth;
+use crate::config::ConfigRef;
+use crate::error::{ErrorReject, RejectTypes};
+use crate::jwt::{self, JwtClaims};
+use crate::keys::KeyringRef;
+use crate::store::StoreRef;
+
+/// The form posted to `/introspect`. Only access tokens can be introspected, so a
+/// `token_type_hint` is ignored.
+#[derive(Deserialize, Serialize)]
+pub struct IntrospectRequest {
+    token: String,
+}
+
+/// The claims are only present if the token is active.
+#[derive(Serialize)]
+struct Introspection {
+    active: bool,
+    #[serde(flatten)]
+    claims: Option<JwtClaims>,
+}
+
+/// The subject of the token, read before the token is verified, which checks it again.
+fn unverified_subject(token: &str) -> Option<String> {
+    let (_, payload_b64url, _) = jwt::split(token)?;
+    let payload = base64_url::decode(payload_b64url).ok()?;
+    let claims: JwtClaims = serde_json::from_slice(&payload).ok()?;
+
+    Some(claims.sub)
+}
+
+/// Replies whether the token is active, that is accepted by [`auth::verify_jwt`] like it is by the
+/// other endpoints, together with its claims. Errors of the server itself are not hidden as inactive.
+pub async fn introspect(
+    client_id: String, request: IntrospectRequest, store: StoreRef, config: ConfigRef,
+    keyring: KeyringRef) -> Result<impl warp::Reply, warp::Rejection> {
+    let inactive = Introspection { active: false, claims: None };
+
+    let sub = match unverified_subject(&request.token) {
+        Some(sub) => sub,
+        None => return Ok(warp::reply::json(&inactive))
+    };
+
+    let introspection = match auth::verify_jwt(&store, &keyring, &sub, &request.token,
+                                               &auth::Validation::from_config(&config)).await {
+        Ok(claims) => Introspection { active: true, claims: Some(claims) },
+        Err(rejection) => match rejection.find::<ErrorReject>() {
+            Some(e) if matches!(e.rt, RejectTypes::IO | RejectTypes::Internal | RejectTypes::DecodeInternal) => {
+                return Err(rejection)
+            }
+            _ => inactive
+        }
+    };
+    log::debug!("Client {} introspected a token, active: {}.", client_id, introspection.active);
+
+    Ok(warp::reply::json(&introspection))
+}
@@ -0,0 +1,109 @@
+//! Compact JWS serialization of tokens signed with Ed25519, following RFC 7515 and RFC 8037.
+
+use ed25519_dalek::{Keypair, PublicKey, Signer};
+use sha2::{Digest, Sha256};
+
+use crate::{Deserialize, Serialize};
+use crate::defs::Tiauth;
+
+/// The `alg` of tokens signed with Ed25519 (RFC 8037).
+pub const ALG_EDDSA: &str = "EdDSA";
+
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq)]
+pub struct JwtHeader {
+    pub alg: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub typ: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kid: Option<String>,
+}
+
+impl JwtHeader {
+    /// Header of a JWT signed by the key with the given key ID.
+    pub fn eddsa(kid: String) -> Self {
+        JwtHeader {
+            alg: ALG_EDDSA.to_owned(),
+            typ: Some("JWT".to_owned()),
+            kid: Some(kid)
+        }
+    }
+}
+
+/// Payload of the tokens issued on login.
+#[derive(Deserialize, Serialize, Debug)]
+pub struct JwtClaims {
+    pub iss: String,
+    pub iat: u64,
+    pub nbf: u64,
+    pub exp: u64,
+    pub jti: String,
+    pub sub: String,
+    pub tipten_auth: Tiauth,
+}
+
+/// Splits a compact JWS into its header, payload and signature segments. Returns `None` unless
+/// there are exactly three segments.
+pub fn split(jwt: &str) -> Option<(&str, &str, &str)> {
+    let mut segments = jwt.split('.');
+    match (segments.next(), segments.next(), segments.next(), segments.next()) {
+        (Some(header), Some(payload), Some(signature), None) => Some((header, payload, signature)),
+        _ => None
+    }
+}
+
+/// A public Ed25519 verification key as an RFC 8037 JWK.
+#[derive(Deserialize, Serialize, Debug)]
+pub struct Jwk {
+    pub kty: String,
+    pub crv: String,
+    pub x: String,
+    pub kid: String,
+    pub alg: String,
+    #[serde(rename = "use")]
+    pub use_: String,
+}
+
+impl Jwk {
+    pub fn ed25519(kid: String, public_key: &PublicKey) -> Self {
+        Jwk {
+            kty: "OKP".to_owned(),
+            crv: "Ed25519".to_owned(),
+            x: base64_url::encode(public_key.as_bytes()),
+            kid,
+            alg: ALG_EDDSA.to_owned(),
+            use_: "sig".to_owned()
+        }
+    }
+}
+
+/// A JWK set (RFC 7517).
+#[derive(Deserialize, Serialize, Debug)]
+pub struct JwkSet {
+    pub keys: Vec<Jwk>,
+}
+
+/// The RFC 7638 JWK thumbprint of an Ed25519 public key, used as its key ID.
+pub fn key_id(public_key: &PublicKey) -> String {
+    // Members in lexicographic order and without whitespace, as required for the thumbprint.
+    let jwk = format!(r#"{{"crv":"Ed25519","kty":"OKP","x":"{}"}}"#,
+                      base64_url::encode(public_key.as_bytes()));
+
+    base64_url::encode(&Sha256::digest(jwk.as_bytes()))
+}
+
+/// Signs `payload` as `<header>.<payload>.<signature>`, with both header and payload serialized as
+/// compact JSON.
+pub fn sign<T: Serialize>(keypair: &Keypair, header: &JwtHeader, payload: &T) -> serde_json::Result<String> {
+    let header_json = serde_json::to_vec(header)?;
+    let payload_json = serde_json::to_vec(payload)?;
+
+    Ok(sign_bytes(keypair, &header_json, &payload_json))
+}
+
+/// Like [`sign`], for a header and payload that are already serialized.
+pub fn sign_bytes(keypair: &Keypair, header: &[u8], payload: &[u8]) -> String {
+    let message = base64_url::encode(header) + "." + &base64_url::encode(payload);
+    let signature = keypair.sign(message.as_bytes());
+
+    message + "." + &base64_url::encode(&signature.to_bytes())
+}
@@ -0,0 +1,191 @@
+//! The keyring of server signing keys. Tokens are signed with the single active key and carry its
+//! key ID, retired keys stay available for verification until the tokens they signed expired.
+
+use std::convert::Infallible;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use ed25519_dalek::{Keypair, PublicKey, SecretKey};
+use tokio::sync::Mutex;
+use warp::Filter;
+
+use crate::config::{Config, ConfigRef};
+use crate::defs;
+use crate::jwt;
+use crate::register::generate_keypair;
+use crate::store::{StoreError, StoreErrors, StoreRef, StoreResult};
+
+/// How often [`rotate_periodically`] checks whether the active key is due for rotation.
+const ROTATION_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Seconds resource servers may cache the JWK set. A token with a `kid` that is not in a cached
+/// set means the key was rotated in the meantime and the set should be fetched again.
+const JWKS_MAX_AGE: u64 = 300;
+
+fn decode_error<E: ToString>(e: E) -> StoreError {
+    StoreError {
+        error_type: StoreErrors::Decode,
+        e: e.to_string()
+    }
+}
+
+fn decode_public(key: &defs::SigningKey) -> StoreResult<PublicKey> {
+    PublicKey::from_bytes(&hex::decode(&key.public_hex).map_err(decode_error)?).map_err(decode_error)
+}
+
+fn decode_keypair(key: &defs::SigningKey) -> StoreResult<Keypair> {
+    let secret = SecretKey::from_bytes(&hex::decode(&key.secret_hex).map_err(decode_error)?)
+        .map_err(decode_error)?;
+    let public = PublicKey::from(&secret);
+    if public != decode_public(key)? {
+        return Err(decode_error(format!("public key of {} does not match its secret key", key.kid)))
+    }
+
+    Ok(Keypair { secret, public })
+}
+
+fn new_key(now: u64) -> StoreResult<defs::SigningKey> {
+    let (public_hex, secret_hex) = generate_keypair();
+    let mut key = defs::SigningKey {
+        kid: String::new(),
+        secret_hex,
+        public_hex,
+        created: now,
+        expires: None
+    };
+    key.kid = jwt::key_id(&decode_public(&key)?);
+
+    Ok(key)
+}
+
+/// Whether `key` can still be used to verify tokens at `now`.
+fn is_valid(key: &defs::SigningKey, now: u64) -> bool {
+    match key.expires {
+        Some(expires) => expires > now,
+        None => true
+    }
+}
+
+#[derive(Default)]
+pub struct Keyring {
+    keys: RwLock<Vec<defs::SigningKey>>,
+    rotation: Mutex<()>,
+}
+
+impl Keyring {
+    /// Loads the keys from the store. Call [`Keyring::rotate_if_due`] afterwards to make sure
+    /// there is an active key.
+    pub async fn load(store: &StoreRef) -> StoreResult<Self> {
+        let keys = store.read_signing_keys().await?;
+        for key in &keys {
+            decode_keypair(key)?;
+        }
+
+        Ok(Keyring {
+            keys: RwLock::new(keys),
+            rotation: Mutex::new(())
+        })
+    }
+
+    /// The key ID and keypair of the active key, which new tokens are signed with.
+    pub fn signing_key(&self) -> StoreResult<(String, Keypair)> {
+        let keys = self.keys.read().unwrap();
+        let key = keys.iter().find(|key| key.expires.is_none())
+            .ok_or_else(|| StoreError { error_type: StoreErrors::NonExistent,
+                e: "no active signing key".to_owned() })?;
+
+        Ok((key.kid.clone(), decode_keypair(key)?))
+    }
+
+    /// The public key with key ID `kid`, if it can still be used to verify tokens at `now`.
+    pub fn verification_key(&self, kid: &str, now: u64) -> Option<PublicKey> {
+        let keys = self.keys.read().unwrap();
+        keys.iter()
+            .find(|key| key.kid == kid && is_valid(key, now))
+            .and_then(|key| decode_public(key).ok())
+    }
+
+    /// The JWK set of every key that can still be used to verify tokens at `now`.
+    pub fn jwk_set(&self, now: u64) -> jwt::JwkSet {
+        let keys = self.keys.read().unwrap();
+        let keys = keys.iter()
+            .filter(|key| is_valid(key, now))
+            .filter_map(|key| decode_public(key).ok().map(|public_key| jwt::Jwk::ed25519(key.kid.clone(), &public_key)))
+            .collect();
+
+        jwt::JwkSet { keys }
+    }
+
+    /// Creates a new active key if there is none or if the active one is older than the rotation
+    /// interval, and drops retired keys that expired. Returns whether a new key was created.
+    pub async fn rotate_if_due(&self, store: &StoreRef, config: &Config, now: u64) -> StoreResult<bool> {
+        self.update(store, config, now, false).await
+    }
+
+    /// Replaces the active key by a new one, regardless of its age.
+    pub async fn rotate(&self, store: &StoreRef, config: &Config, now: u64) -> StoreResult<()> {
+        self.update(store, config, now, true).await.map(|_| ())
+    }
+
+    async fn update(&self, store: &StoreRef, config: &Config, now: u64, force: bool) -> StoreResult<bool> {
+        let _guard = self.rotation.lock().await;
+        // The store is the source of truth, another server instance may have rotated already.
+        let mut keys = store.read_signing_keys().await?;
+        let stored = keys.len();
+        keys.retain(|key| is_valid(key, now));
+        let mut changed = keys.len() != stored;
+
+        let due = force || keys.iter()
+            .find(|key| key.expires.is_none())
+            .map(|key| key.created.saturating_add(config.key_rotation) <= now)
+            .unwrap_or(true);
+        if due {
+            // A token signed just before the rotation remains valid for its full lifetime.
+            let retired_expires = now.saturating_add(config.token_lifetime).saturating_add(config.clock_skew);
+            for key in keys.iter_mut().filter(|key| key.expires.is_none()) {
+                key.expires = Some(retired_expires);
+            }
+            keys.push(new_key(now)?);
+            changed = true;
+        }
+
+        if changed {
+            store.write_signing_keys(&keys).await?;
+        }
+        *self.keys.write().unwrap() = keys;
+
+        Ok(due)
+    }
+}
+
+pub type KeyringRef = Arc<Keyring>;
+
+/// Filter that hands a reference to the keyring to a handler.
+pub fn with_keyring(keyring: KeyringRef) -> impl Filter<Extract = (KeyringRef,), Error = Infallible> + Clone {
+    warp::any().map(move || keyring.clone())
+}
+
+/// Replies with the JWK set of the server signing keys, see `/.well-known/jwks.json`.
+pub async fn reply_jwks(keyring: KeyringRef) -> Result<impl warp::Reply, warp::Rejection> {
+    let jwk_set = keyring.jwk_set(crate::auth::unix_now()?);
+
+    Ok(warp::reply::with_header(warp::reply::json(&jwk_set), "cache-control",
+                                format!("public, max-age={}", JWKS_MAX_AGE)))
+}
+
+/// Rotates the keys whenever they are due, never returns.
+pub async fn rotate_periodically(keyring: KeyringRef, store: StoreRef, config: ConfigRef) {
+    let mut interval = tokio::time::interval(ROTATION_CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        let now = match crate::auth::unix_now() {
+            Ok(now) => now,
+            Err(_) => continue
+        };
+        match keyring.rotate_if_due(&store, &config, now).await {
+            Ok(true) => log::info!("Rotated the server signing key."),
+            Ok(false) => {},
+            Err(e) => log::error!("Failed to rotate the server signing key: {}", e)
+        }
+    }
+}
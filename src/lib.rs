@@ -1,10 +1,16 @@
+use std::sync::Arc;
+
 use env_logger::Env;
 use log::debug;
 use serde::{Deserialize, Serialize};
+use serde::de::DeserializeOwned;
 use warp::Filter;
+use warp::hyper::body::Bytes;
 use warp::path;
 use warp::reject::custom as reject;
 
+use error::ErrorExt;
+
 pub mod files;
 pub mod error;
 pub mod claims;
@@ -12,15 +18,100 @@ pub mod login;
 pub mod auth;
 pub mod register;
 pub mod db;
+pub mod store;
+pub mod config;
+pub mod jwt;
+pub mod keys;
+pub mod secrets;
+pub mod password;
+pub mod salt;
+pub mod throttle;
+pub mod refresh;
+pub mod admin;
+pub mod revoke;
+pub mod clients;
+pub mod introspect;
+pub mod credentials;
+pub mod mail;
+pub mod reset;
+
+pub mod defs {
+    use std::convert::TryFrom;
+    use std::fmt::{Display, Formatter, Result as FmtResult};
+    use std::ops::Deref;
 
-mod defs {
     use super::{Deserialize, Serialize};
 
+    /// Maximum length of a user hex, matching the `user_auth` table.
+    pub const USER_ID_MAX_LEN: usize = 999;
+
+    /// A user hex that is safe to use as an identifier and as a file name: 1 to
+    /// [`USER_ID_MAX_LEN`] ASCII letters, digits, '-' or '_'.
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+    #[serde(try_from = "String", into = "String")]
+    pub struct UserId(String);
+
+    impl TryFrom<String> for UserId {
+        type Error = String;
+
+        fn try_from(user_hex: String) -> Result<Self, Self::Error> {
+            if user_hex.is_empty() || user_hex.len() > USER_ID_MAX_LEN {
+                Err(format!("user hex must be between 1 and {} characters", USER_ID_MAX_LEN))
+            }
+            else if !user_hex.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+                Err("user hex may only contain ASCII letters, digits, '-' and '_'".to_owned())
+            }
+            else {
+                Ok(UserId(user_hex))
+            }
+        }
+    }
+
+    impl From<UserId> for String {
+        fn from(user_id: UserId) -> Self {
+            user_id.0
+        }
+    }
+
+    impl Deref for UserId {
+        type Target = str;
+
+        fn deref(&self) -> &str {
+            &self.0
+        }
+    }
+
+    impl Display for UserId {
+        fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+            f.write_str(&self.0)
+        }
+    }
+
     #[derive(Serialize, Deserialize)]
     pub struct UserJson {
+        pub user_hex: UserId,
+        pub password_hash_hex: String,
+        pub salt_hex: String,
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct SaveUserJson {
         pub user_hex: String,
         pub password_hash_hex: String,
         pub salt_hex: String,
+        pub secret_hex: String,
+        pub public_hex: String,
+        /// When the password was last changed, if ever.
+        #[serde(default)]
+        pub password_changed: Option<u64>,
+        /// Tokens of the user issued before this time are rejected, see `/change_password`.
+        #[serde(default)]
+        pub tokens_not_before: Option<u64>,
+        /// Mail address the user can reset its password with, once it is verified.
+        #[serde(default)]
+        pub contact: Option<String>,
+        #[serde(default)]
+        pub contact_verified: bool,
     }
 
     #[derive(Deserialize, Serialize, Debug)]
@@ -40,6 +131,90 @@ mod defs {
     pub struct Resources {
         pub resources: Vec<String>,
     }
+
+    /// A server signing key. The active key has no `expires`, retired keys are kept until then
+    /// so that the tokens they signed can still be verified.
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct SigningKey {
+        pub kid: String,
+        pub secret_hex: String,
+        pub public_hex: String,
+        pub created: u64,
+        pub expires: Option<u64>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct SigningKeys {
+        pub keys: Vec<SigningKey>,
+    }
+
+    /// The secret the pseudo-salts of unknown users are derived from, if none is configured.
+    #[derive(Serialize, Deserialize)]
+    pub struct StoredSaltSecret {
+        pub secret_hex: String,
+    }
+
+    /// A refresh token, stored by the hash of the token itself. Every refresh marks the token as
+    /// used and issues a new one in the same family, which descends from a single login.
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+    pub struct RefreshToken {
+        pub token_hash: String,
+        pub family_id: String,
+        pub user_hex: String,
+        pub issued: u64,
+        pub expires: u64,
+        pub used: bool,
+    }
+
+    /// What a [`MailToken`] can be used for.
+    #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+    #[serde(rename_all = "snake_case")]
+    pub enum MailTokenPurpose {
+        VerifyContact,
+        PasswordReset,
+    }
+
+    impl MailTokenPurpose {
+        pub fn as_str(&self) -> &'static str {
+            match self {
+                MailTokenPurpose::VerifyContact => "verify_contact",
+                MailTokenPurpose::PasswordReset => "password_reset",
+            }
+        }
+    }
+
+    /// A single-use token mailed to the contact address of a user, stored by the hash of the
+    /// token itself.
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+    pub struct MailToken {
+        pub token_hash: String,
+        pub user_hex: String,
+        pub purpose: MailTokenPurpose,
+        /// The address the token was sent to, it is only valid while the user has that address.
+        pub contact: String,
+        pub issued: u64,
+        pub expires: u64,
+    }
+
+    /// A revoked access token, kept until the token would have expired anyway.
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+    pub struct RevokedToken {
+        pub jti: String,
+        pub expires: u64,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct RevokedTokens {
+        pub revoked: Vec<RevokedToken>,
+    }
+
+    /// Recent failed logins of a user or a client address, see [`crate::throttle`].
+    #[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+    pub struct LoginAttempts {
+        pub failures: u32,
+        pub last_failure: u64,
+        pub locked_until: u64,
+    }
 }
 
 // async fn root_request() -> Result<impl warp::Reply, warp::Rejection> {
@@ -57,24 +232,145 @@ mod params {
 
     #[derive(Deserialize, Serialize)]
     pub struct UserHex {
-        pub user_hex: String,
+        pub user_hex: super::defs::UserId,
     }
 }
 
-pub async fn prepare_server() {
-    files::prepare_directories().await.unwrap();
+/// Like `warp::body::json`, but rejects with a DecodeExternal error that tells the requester why
+/// the body is invalid, e.g. because it contains a malformed user hex.
+fn json_body<T: DeserializeOwned + Send>() -> impl Filter<Extract = (T,), Error = warp::Rejection> + Clone {
+    warp::body::bytes()
+        .and_then(|bytes: Bytes| async move {
+            serde_json::from_slice(&bytes)
+                .map_err(|e| { reject(error::ErrorReject { rt: error::RejectTypes::DecodeExternal,
+                    msg: "Invalid request body!",
+                    e: format!("@@@{}@@@", e) }) })
+        })
 }
 
-pub async fn run_server() {
-    let env = Env::default()
-        .filter_or("MY_LOG_LEVEL", "debug")
-        .write_style_or("MY_LOG_STYLE", "always");
+/// Like `warp::query`, but rejects with a DecodeExternal error that tells the requester why the
+/// query is invalid.
+fn query<T: DeserializeOwned + Send>() -> impl Filter<Extract = (T,), Error = warp::Rejection> + Clone {
+    warp::query::raw()
+        .or(warp::any().map(String::new))
+        .unify()
+        .and_then(|query: String| async move {
+            serde_urlencoded::from_str(&query)
+                .map_err(|e| { reject(error::ErrorReject { rt: error::RejectTypes::DecodeExternal,
+                    msg: "Invalid query!",
+                    e: format!("@@@{}@@@", e) }) })
+        })
+}
 
-    env_logger::init_from_env(env);
+/// Opens the SQLite database if one is configured, otherwise falls back to the JSON files in the
+/// data directory. The store is prepared (migrations are applied) before it is returned.
+pub async fn open_store(config: &config::Config) -> Result<store::StoreRef, error::Error> {
+    let store: store::StoreRef = match &config.database {
+        Some(url) => Arc::new(db::SqliteStore::connect(url).await
+            .repl("Error connecting to the database!")?),
+        None => Arc::new(files::FileStore::new(&config.data_dir))
+    };
+    store.prepare().await
+        .repl("Error preparing the store!")?;
+
+    Ok(store)
+}
 
-    log::debug!("main debug");
-    log::info!("main info");
+/// Opens the store like [`open_store`]. If a master key is configured, secret keys are encrypted
+/// with it. Fails if the store holds encrypted secrets that the configured key cannot decrypt.
+pub async fn prepare_server(config: &config::Config) -> Result<store::StoreRef, error::Error> {
+    let store = open_store(config).await?;
+    let master_key = secrets::MasterKey::from_config(config)?;
 
+    let unsealed = secrets::check_secrets(&store, master_key.as_ref()).await
+        .repl("Stored secret keys cannot be opened with the configured master key!")?;
+    Ok(match master_key {
+        Some(master_key) => {
+            if unsealed > 0 {
+                log::warn!("{} secret keys are stored unencrypted, run rotate_master_key to encrypt them.", unsealed);
+            }
+            Arc::new(secrets::SealedStore::new(store, master_key))
+        }
+        None => {
+            log::warn!("No master key is configured, secret keys are stored unencrypted.");
+            store
+        }
+    })
+}
+
+/// Loads the server signing keys, creating the first one if there is none yet.
+pub async fn prepare_keyring(config: &config::Config, store: &store::StoreRef) -> Result<keys::KeyringRef, error::Error> {
+    let keyring = keys::Keyring::load(store).await
+        .repl("Error loading the server signing keys!")?;
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)
+        .repl("Error calculating system time!")?
+        .as_secs();
+    keyring.rotate_if_due(store, config, now).await
+        .repl("Error rotating the server signing key!")?;
+
+    Ok(Arc::new(keyring))
+}
+
+/// Loads the salt secret. Without a configured one, the one kept in the store is used, which is
+/// generated on the first start.
+pub async fn prepare_salt_secret(config: &config::Config, store: &store::StoreRef) -> Result<salt::SaltSecretRef, error::Error> {
+    let salt_secret = match salt::SaltSecret::from_config(config)? {
+        Some(salt_secret) => salt_secret,
+        None => salt::SaltSecret::load_or_create(store).await?
+    };
+
+    Ok(Arc::new(salt_secret))
+}
+
+/// Loads the admin token. Without one, the admin endpoints reject every request.
+pub fn prepare_admin(config: &config::Config) -> Result<admin::AdminRef, error::Error> {
+    let admin_token = admin::AdminToken::from_config(config)?;
+    if admin_token.is_none() {
+        log::info!("No admin token is configured, the admin endpoints are disabled.");
+    }
+
+    Ok(Arc::new(admin_token))
+}
+
+/// Loads the clients that may introspect tokens.
+pub fn prepare_clients(config: &config::Config) -> Result<clients::ClientsRef, error::Error> {
+    Ok(Arc::new(clients::Clients::from_config(config)?))
+}
+
+/// Sets up the sender of the mails to users. Without one, contact addresses cannot be set and
+/// password resets cannot be requested.
+pub fn prepare_mailer(config: &config::Config) -> Result<Option<mail::MailerRef>, error::Error> {
+    if config.smtp_host.is_none() {
+        match &config.mail_dir {
+            Some(mail_dir) => log::warn!("No SMTP server is configured, mails are written to {:?}.", mail_dir),
+            None if config.mail_log => log::warn!("No SMTP server is configured, mails are only logged. \
+Their tokens end up in the log, only use this for development."),
+            None => log::warn!("No mail sender is configured, contact addresses and password resets are disabled.")
+        }
+    }
+
+    mail::from_config(config)
+}
+
+/// Like `warp::body::form`, but rejects with a DecodeExternal error that tells the requester why
+/// the form is invalid.
+fn form_body<T: DeserializeOwned + Send>() -> impl Filter<Extract = (T,), Error = warp::Rejection> + Clone {
+    warp::body::bytes()
+        .and_then(|bytes: Bytes| async move {
+            serde_urlencoded::from_bytes(&bytes)
+                .map_err(|e| { reject(error::ErrorReject { rt: error::RejectTypes::DecodeExternal,
+                    msg: "Invalid form!",
+                    e: format!("@@@{}@@@", e) }) })
+        })
+}
+
+/// All routes of the server, including rejection handling and CORS.
+pub fn routes(config: config::ConfigRef, store: store::StoreRef,
+              keyring: keys::KeyringRef,
+              salt_secret: salt::SaltSecretRef,
+              admin: admin::AdminRef,
+              clients: clients::ClientsRef,
+              mailer: Option<mail::MailerRef>) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     let cors = warp::cors()
         .allow_any_origin()
         .allow_headers(vec!["content-type"])
@@ -82,34 +378,133 @@ pub async fn run_server() {
 
     let user_verify = path("user_verify")
         .and(warp::get())
-        .and(warp::query::<params::UserHex>())
+        .and(query::<params::UserHex>())
+        .and(store::with_store(store.clone()))
         .and_then(login::reply_user_public);
 
     let register = path("register")
         .and(warp::post())
-        .and(warp::body::json())
+        .and(json_body())
+        .and(store::with_store(store.clone()))
+        .and(config::with_config(config.clone()))
         .and_then(register::write_user);
 
     let user_salt = warp::path("user_salt")
         .and(warp::get())
-        .and(warp::query::<params::UserHex>())
+        .and(query::<params::UserHex>())
+        .and(store::with_store(store.clone()))
+        .and(salt::with_salt_secret(salt_secret))
         .and_then(login::reply_user_salt);
 
     let login = path("login")
         .and(warp::post())
-        .and(warp::body::json())
+        .and(json_body())
+        .and(warp::addr::remote())
+        .and(store::with_store(store.clone()))
+        .and(config::with_config(config.clone()))
+        .and(keys::with_keyring(keyring.clone()))
         .and_then(login::login_user);
 
+    let change_password = path("change_password")
+        .and(warp::post())
+        .and(json_body())
+        .and(warp::addr::remote())
+        .and(store::with_store(store.clone()))
+        .and(config::with_config(config.clone()))
+        .and(keys::with_keyring(keyring.clone()))
+        .and_then(credentials::change_password);
+
+    let set_contact = path("contact")
+        .and(path::end())
+        .and(warp::post())
+        .and(json_body())
+        .and(store::with_store(store.clone()))
+        .and(config::with_config(config.clone()))
+        .and(keys::with_keyring(keyring.clone()))
+        .and(mail::with_mailer(mailer.clone()))
+        .and_then(reset::set_contact);
+
+    let verify_contact = path!("contact" / "verify")
+        .and(warp::post())
+        .and(json_body())
+        .and(store::with_store(store.clone()))
+        .and_then(reset::verify_contact);
+
+    let request_reset = path!("password_reset" / "request")
+        .and(warp::post())
+        .and(json_body())
+        .and(warp::addr::remote())
+        .and(store::with_store(store.clone()))
+        .and(config::with_config(config.clone()))
+        .and(mail::with_mailer(mailer))
+        .and_then(reset::request_reset);
+
+    let confirm_reset = path!("password_reset" / "confirm")
+        .and(warp::post())
+        .and(json_body())
+        .and(store::with_store(store.clone()))
+        .and(config::with_config(config.clone()))
+        .and_then(reset::confirm_reset);
+
+    let refresh_token = path!("token" / "refresh")
+        .and(warp::post())
+        .and(json_body())
+        .and(store::with_store(store.clone()))
+        .and(config::with_config(config.clone()))
+        .and(keys::with_keyring(keyring.clone()))
+        .and_then(refresh::refresh_token);
+
+    let logout = path("logout")
+        .and(warp::post())
+        .and(json_body())
+        .and(store::with_store(store.clone()))
+        .and(config::with_config(config.clone()))
+        .and(keys::with_keyring(keyring.clone()))
+        .and_then(revoke::logout);
+
+    let revoke = path("revoke")
+        .and(warp::post())
+        .and(admin::require_admin(admin))
+        .and(json_body())
+        .and(store::with_store(store.clone()))
+        .and(config::with_config(config.clone()))
+        .and_then(revoke::revoke_token);
+
+    let revoked = path("revoked")
+        .and(warp::get())
+        .and(store::with_store(store.clone()))
+        .and_then(revoke::reply_revoked);
+
+    let introspect = path("introspect")
+        .and(warp::post())
+        .and(clients::require_client(clients))
+        .and(form_body())
+        .and(store::with_store(store.clone()))
+        .and(config::with_config(config.clone()))
+        .and(keys::with_keyring(keyring.clone()))
+        .and_then(introspect::introspect);
+
     let new_claim = path("new_claim")
         .and(warp::post())
-        .and(warp::body::json())
+        .and(json_body())
+        .and(store::with_store(store.clone()))
+        .and(config::with_config(config.clone()))
+        .and(keys::with_keyring(keyring.clone()))
         .and_then(claims::new_user_claim);
 
     let modify_claims = path("modify_claims")
         .and(warp::post())
-        .and(warp::body::json())
+        .and(json_body())
+        .and(store::with_store(store.clone()))
+        .and(config::with_config(config))
+        .and(keys::with_keyring(keyring.clone()))
         .and_then(claims::modify_user_claims);
 
+    let jwks = path!(".well-known" / "jwks.json")
+        .and(warp::get())
+        .and(keys::with_keyring(keyring))
+        .and_then(keys::reply_jwks);
+
     let alive = path("alive")
         .map(|| "alive!");
 
@@ -118,18 +513,50 @@ pub async fn run_server() {
         // .and(warp::get())
         // .and_then(root_request);
 
-    let routes = warp::any().and(
+    warp::any().and(
         register
             .or(user_salt)
             .or(login)
+            .or(change_password)
+            .or(set_contact)
+            .or(verify_contact)
+            .or(request_reset)
+            .or(confirm_reset)
+            .or(refresh_token)
+            .or(logout)
+            .or(revoke)
+            .or(revoked)
+            .or(introspect)
             .or(user_verify)
             .or(new_claim)
             .or(modify_claims)
+            .or(jwks)
             .or(alive)
             .or(root), )
         .recover(error::handle_err_reject)
         .recover(error::handle_reject)
-        .with(cors);
+        .with(cors)
+}
+
+/// Sets up logging according to the config. `RUST_LOG` still takes precedence if it is set.
+pub fn init_logging(config: &config::Config) {
+    let env = Env::default()
+        .default_filter_or(&config.log_level)
+        .write_style_or("MY_LOG_STYLE", "always");
+
+    env_logger::init_from_env(env);
+}
+
+pub async fn run_server(config: config::ConfigRef, store: store::StoreRef, keyring: keys::KeyringRef,
+                        salt_secret: salt::SaltSecretRef, admin: admin::AdminRef,
+                        clients: clients::ClientsRef, mailer: Option<mail::MailerRef>) {
+    log::info!("Listening on {}:{}", config.bind_address, config.port);
+    let address = (config.bind_address, config.port);
 
-    warp::serve(routes).run(([0, 0, 0, 0], 3031)).await;
+    tokio::spawn(keys::rotate_periodically(keyring.clone(), store.clone(), config.clone()));
+    tokio::spawn(refresh::prune_periodically(store.clone()));
+    tokio::spawn(revoke::prune_periodically(store.clone()));
+    tokio::spawn(reset::prune_periodically(store.clone()));
+    tokio::spawn(throttle::prune_periodically(store.clone(), config.clone()));
+    warp::serve(routes(config, store, keyring, salt_secret, admin, clients, mailer)).run(address).await;
 }
\ No newline at end of file
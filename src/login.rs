@@ -1,29 +1,32 @@
+use std::net::{IpAddr, SocketAddr};
+
 use crate::{Deserialize, Serialize};
-use crate::files;
-use std::time::SystemTime;
-use ed25519_dalek::Signer;
+use crate::store::{store_reject, StoreErrors, StoreRef};
+use crate::config::{Config, ConfigRef};
+use crate::defs::SaveUserJson;
+use crate::keys::{Keyring, KeyringRef};
+use crate::refresh;
+use crate::password::{self, Verified};
+use crate::salt::SaltSecretRef;
+use crate::throttle;
 use crate::error::{ErrorReject, RejectTypes};
 use crate::params;
+use crate::defs::UserId;
 use crate::reject;
+use crate::auth::{self, unix_now};
+use crate::jwt::{self, JwtClaims, JwtHeader};
 
 #[derive(Deserialize, Serialize)]
 pub struct UserLogin {
-    user_hex: String,
+    user_hex: UserId,
     password_hash_hex: String,
 }
 
 #[derive(Deserialize, Serialize)]
-struct ClaimsJWTPayload {
-    iss: String,
-    iat: u64,
-    sub: String,
-    tipten_auth: serde_json::Value,
-}
-
-#[derive(Deserialize, Serialize)]
-struct JwtResponse {
-    public_hex: String,
-    jwt: String,
+pub(crate) struct JwtResponse {
+    pub(crate) public_hex: String,
+    pub(crate) jwt: String,
+    pub(crate) refresh_token: String,
 }
 
 #[derive(Deserialize, Serialize)]
@@ -36,33 +39,32 @@ struct UserPublic {
     public_hex: String,
 }
 
-const ED25519JWT: &str = r#"
-        {
-            "alg": "ED25519",
-            "typ": "JWT
-        }"#;
-
+/// Replies with the salt of the user. Unknown users get a pseudo-salt, so they cannot be told apart.
 pub async fn reply_user_salt(
-    user_hex_param: params::UserHex) -> Result<impl warp::Reply, warp::Rejection> {
+    user_hex_param: params::UserHex, store: StoreRef,
+    salt_secret: SaltSecretRef) -> Result<impl warp::Reply, warp::Rejection> {
     let user_hex = user_hex_param.user_hex;
 
-    let save_user = files::read_user(&user_hex, false).await
-        .map_err(|e| { files::io_nonexistent_reject(e, "Error reading user data (user salt)",
-                                                    "User does not exist! (user salt)") })?;
+    let salt_hex = match store.read_user(&user_hex, false).await {
+        Ok(save_user) => save_user.salt_hex,
+        Err(e) if matches!(e.error_type, StoreErrors::NonExistent) => salt_secret.fake_salt_hex(&user_hex),
+        Err(e) => return Err(reject(store_reject(e, "Error reading user data (user salt)",
+                                                 "User does not exist! (user salt)")))
+    };
 
     let user_salt = UserSalt {
-        salt_hex: save_user.salt_hex,
+        salt_hex,
     };
 
     Ok(warp::reply::json(&user_salt))
 }
 
 pub async fn reply_user_public(
-    user_hex_param: params::UserHex) -> Result<impl warp::Reply, warp::Rejection> {
+    user_hex_param: params::UserHex, store: StoreRef) -> Result<impl warp::Reply, warp::Rejection> {
     let user_hex = user_hex_param.user_hex;
-    let save_user = files::read_user(&user_hex, false).await
-        .map_err(|e| { files::io_nonexistent_reject(e, "Error reading user data (user public)",
-                                                    "User does not exist! (user public)") })?;
+    let save_user = store.read_user(&user_hex, false).await
+        .map_err(|e| { store_reject(e, "Error reading user data (user public)",
+                                    "User does not exist! (user public)") })?;
 
     let user_public = UserPublic {
         public_hex: save_user.public_hex,
@@ -71,82 +73,133 @@ pub async fn reply_user_public(
     Ok(warp::reply::json(&user_public))
 }
 
-pub async fn login_user(
-    user_login: UserLogin) -> Result<impl warp::Reply, warp::Rejection> {
+/// The keypair of the user itself, used to sign tokens in the per-user keys compatibility mode.
+fn user_keypair(save_user: &SaveUserJson) -> Result<ed25519_dalek::Keypair, warp::Rejection> {
+    let mut keypair_bytes = hex::decode(&save_user.secret_hex)
+        .map_err(|e| { reject(ErrorReject{ rt: RejectTypes::DecodeInternal,
+            msg: "Error decoding saved user data (login user)",
+            e: e.to_string()
+        }) })?;
+    keypair_bytes.append(&mut hex::decode(&save_user.public_hex)
+        .map_err(|e| { reject(ErrorReject{ rt: RejectTypes::DecodeInternal,
+            msg: "Error decoding saved user data (login user)",
+            e: e.to_string()
+        }) })?);
+
+    ed25519_dalek::Keypair::from_bytes(&keypair_bytes)
+        .map_err(|e| { reject(ErrorReject{ rt: RejectTypes::DecodeInternal,
+            msg: "Error decoding saved user data (login user)",
+            e: e.to_string()
+        }) })
+}
 
-    let user_hex = &user_login.user_hex;
+/// Signs an access token for the user with its current claims. Returns the public key that
+/// verifies it, followed by the token.
+pub(crate) async fn access_token(store: &StoreRef, config: &Config, keyring: &Keyring, save_user: &SaveUserJson,
+                                 now: u64) -> Result<(String, String), warp::Rejection> {
+    let claims = store.read_user_claims(&save_user.user_hex).await
+        .map_err(|e| { store_reject(e, "Error reading user claims (access token)",
+                                    "User claims do not exist! (access token)") })?;
+
+    let payload_obj = JwtClaims {
+        iss: config.issuer.clone(),
+        iat: auth::issue_time(save_user, now),
+        nbf: now,
+        exp: now.saturating_add(config.token_lifetime),
+        jti: hex::encode(rand::random::<[u8; 16]>()),
+        sub: save_user.user_hex.clone(),
+        tipten_auth: claims,
+    };
 
-    let save_user = files::read_user(user_hex, true).await
-        .map_err(|e| { files::io_nonexistent_reject(e, "Error reading user data (login user)",
-                                                    "User does not exist! (login user)") })?;
-
-    if save_user.password_hash_hex == user_login.password_hash_hex {
-        let jwt_header = base64_url::encode(ED25519JWT);
-        let claims = files::read_user_claims(user_hex).await
-            .map_err(|e| { files::io_nonexistent_reject(e, "Error reading user claims (login user)",
-                                                        "User claims do not exist! (login user)") })?;
-        let claims = serde_json::to_value(claims)
-            .map_err(|e| { reject(ErrorReject { rt: RejectTypes::DecodeInternal,
-                msg: "Error converting to serde JSON Value (login_user)",
-                e: e.to_string()
-            }) })?;
-        let n = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)
-            .map_err(|e| { reject(ErrorReject { rt: RejectTypes::Internal,
-                msg: "Error calculating system time (login_user)",
-                e: e.to_string()
-            }) })?.as_secs();
-
-        let payload_obj = ClaimsJWTPayload {
-            iss: "auth.tipten.nl".to_owned(),
-            iat: n,
-            sub: user_hex.to_owned(),
-            tipten_auth: claims,
-        };
-
-        let mut keypair_bytes = hex::decode(&save_user.secret_hex)
-            .map_err(|e| { reject(ErrorReject{ rt: RejectTypes::DecodeInternal,
-                msg: "Error decoding saved user data (login user)",
-                e: e.to_string()
-            }) })?;
-        keypair_bytes.append(&mut hex::decode(&save_user.public_hex)
-            .map_err(|e| { reject(ErrorReject{ rt: RejectTypes::DecodeInternal,
-                msg: "Error decoding saved user data (login user)",
-                e: e.to_string()
-            }) })?);
-        let keypair = ed25519_dalek::Keypair::from_bytes(&keypair_bytes)
-            .map_err(|e| { reject(ErrorReject{ rt: RejectTypes::DecodeInternal,
-                msg: "Error decoding saved user data (login user)",
+    let (kid, keypair) = if config.sign_with_user_keys {
+        let keypair = user_keypair(save_user)?;
+        (jwt::key_id(&keypair.public), keypair)
+    }
+    else {
+        keyring.signing_key()
+            .map_err(|e| { reject(ErrorReject{ rt: RejectTypes::Internal,
+                msg: "Error reading server signing key (access token)",
                 e: e.to_string()
-            }) })?;
+            }) })?
+    };
 
-        let jwt_payload_json = serde_json::to_string_pretty(&payload_obj)
-            .map_err(|e| { reject(ErrorReject{ rt: RejectTypes::DecodeInternal,
-                msg: "Error JSONing jwt payload (login user)",
-                e: e.to_string()
-            }) })?;
-        let jwt_payload = base64_url::encode(&jwt_payload_json);
-        let jwt_combined: String = jwt_header.clone() + "." + &jwt_payload;
-        let jwt_combined_bytes = jwt_combined.as_bytes();
+    let jwt_header = JwtHeader::eddsa(kid);
+    let jwt = jwt::sign(&keypair, &jwt_header, &payload_obj)
+        .map_err(|e| { reject(ErrorReject{ rt: RejectTypes::DecodeInternal,
+            msg: "Error JSONing jwt payload (access token)",
+            e: e.to_string()
+        }) })?;
 
-        let signature: ed25519_dalek::Signature = keypair.sign(jwt_combined_bytes);
-        let public_hex = hex::encode(keypair.public.to_bytes());
+    Ok((hex::encode(keypair.public.to_bytes()), jwt))
+}
 
-        let jwt = JwtResponse {
-            public_hex,
-            jwt: (jwt_combined + "." + & base64_url::encode( &signature.to_bytes())),
-        };
+/// Checks the password of the user if the throttle allows another attempt, and records the
+/// outcome. An unknown user is rejected like a wrong password, after the same amount of work.
+/// Returns the user and whether its password hash should be upgraded. The caller holds the lock
+/// of [`throttle::lock_user`].
+pub(crate) async fn check_password(store: &StoreRef, config: &ConfigRef, user_hex: &str, password_hash_hex: &str,
+                                   ip: Option<IpAddr>, now: u64) -> Result<(SaveUserJson, bool), warp::Rejection> {
+    throttle::check(store, config, user_hex, ip, now).await?;
+
+    let save_user = match store.read_user(user_hex, true).await {
+        Ok(save_user) => Some(save_user),
+        Err(e) if matches!(e.error_type, StoreErrors::NonExistent) => None,
+        Err(e) => return Err(reject(store_reject(e, "Error reading user data (check password)",
+                                                 "User does not exist! (check password)")))
+    };
+
+    let verified = match &save_user {
+        Some(save_user) => password::verify(config, &save_user.password_hash_hex, password_hash_hex).await?,
+        None => password::verify_unknown(config, password_hash_hex).await?
+    };
 
-        Ok(warp::reply::json(&jwt))
+    let user_exists = save_user.is_some();
+    if let (Some(save_user), Verified::Correct { rehash }) = (save_user, verified) {
+        throttle::record_success(store, user_hex).await?;
+
+        Ok((save_user, rehash))
     }
     else {
+        throttle::record_failure(store, config, user_hex, user_exists, ip, now).await?;
+
         Err(reject(ErrorReject {
             rt: RejectTypes::Incorrect,
-            msg: "Input password is incorrect!",
+            msg: "User does not exist or password is incorrect!",
             e: "".to_string()
         }))
     }
 }
 
+pub async fn login_user(
+    user_login: UserLogin, remote: Option<SocketAddr>, store: StoreRef, config: ConfigRef,
+    keyring: KeyringRef) -> Result<impl warp::Reply, warp::Rejection> {
+
+    let user_hex = &user_login.user_hex;
+    let ip = remote.map(|remote| remote.ip());
+    let now = unix_now()?;
+
+    let _attempts_guard = throttle::lock_user(&store, user_hex).await;
+    let (save_user, rehash) = check_password(&store, &config, user_hex, &user_login.password_hash_hex,
+                                             ip, now).await?;
+    if rehash {
+        let password_hash = password::hash(&config, &user_login.password_hash_hex).await?;
+        store.write_user_password(user_hex, &password_hash).await
+            .map_err(|e| { store_reject(e, "Error upgrading password hash (login user)",
+                                        "User does not exist! (login user)") })?;
+    }
+
+    let (public_hex, jwt) = access_token(&store, &config, &keyring, &save_user, now).await?;
+    let refresh_token = refresh::issue(&store, &config, user_hex, None, auth::issue_time(&save_user, now)).await?;
+
+    let jwt = JwtResponse {
+        public_hex,
+        jwt,
+        refresh_token,
+    };
+
+    Ok(warp::reply::json(&jwt))
+}
+
 #[cfg(test)]
 mod tests {
     #[test]
@@ -0,0 +1,192 @@
+//! Mail to the contact addresses of users, delivered by a [`MailSender`].
+//!
+//! [`SmtpSender`] sends it through the SMTP server at `smtp_host`. For local development and
+//! tests, [`FileSender`] writes every mail to a JSON file in `mail_dir` instead, or, only if
+//! `mail_log` is set, [`LogSender`] logs it. Without any of them there is no sender, and the
+//! routes that send mail reject every request.
+
+use std::env;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use lettre::message::Mailbox;
+use lettre::message::header::ContentType;
+use lettre::transport::smtp::authentication::Credentials;
+use warp::Filter;
+
+use crate::{Deserialize, Serialize};
+use crate::config::Config;
+use crate::error::{Error, ErrorExt, ErrorReject, Errors, RejectTypes};
+use crate::reject;
+
+/// Environment variable holding the SMTP password, it takes precedence over `smtp_password_file`.
+pub const SMTP_PASSWORD_VAR: &str = "TIAUTH_SMTP_PASSWORD";
+
+/// A plain text mail.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Mail {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+#[async_trait]
+pub trait MailSender: Send + Sync {
+    async fn send(&self, mail: &Mail) -> Result<(), Error>;
+}
+
+pub type MailerRef = Arc<dyn MailSender>;
+
+/// Whether `address` is a mail address that can be sent to.
+pub fn is_valid_address(address: &str) -> bool {
+    address.parse::<lettre::Address>().is_ok()
+}
+
+pub struct SmtpSender {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+impl SmtpSender {
+    /// Sends through `host` with STARTTLS, logging in with `credentials` if they are given.
+    pub fn new(host: &str, port: u16, credentials: Option<Credentials>, from: &str) -> Result<Self, Error> {
+        let mut builder = AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(host)
+            .repl("Error setting up SMTP transport!")?
+            .port(port);
+        if let Some(credentials) = credentials {
+            builder = builder.credentials(credentials);
+        }
+
+        Ok(SmtpSender {
+            transport: builder.build(),
+            from: from.parse().repl("Invalid mail_from address!")?
+        })
+    }
+}
+
+#[async_trait]
+impl MailSender for SmtpSender {
+    async fn send(&self, mail: &Mail) -> Result<(), Error> {
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(mail.to.parse().repl("Invalid mail recipient!")?)
+            .subject(&mail.subject)
+            .header(ContentType::TEXT_PLAIN)
+            .body(mail.body.clone())
+            .repl("Error building mail!")?;
+        self.transport.send(message).await
+            .repl("Error sending mail!")?;
+
+        Ok(())
+    }
+}
+
+/// Writes every mail to a JSON file in a directory, named so that they sort in the order they
+/// were sent.
+pub struct FileSender {
+    dir: PathBuf,
+}
+
+impl FileSender {
+    pub fn new(dir: &Path) -> Self {
+        FileSender { dir: dir.to_owned() }
+    }
+
+    /// Reads the mails written to `dir`, oldest first.
+    pub fn read_mails(dir: &Path) -> Result<Vec<Mail>, Error> {
+        let mut paths = match std::fs::read_dir(dir) {
+            Ok(entries) => entries.map(|entry| entry.map(|entry| entry.path()))
+                .collect::<Result<Vec<_>, _>>()
+                .repl("Error reading mail directory!")?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => vec![],
+            Err(e) => return Err(e).repl("Error reading mail directory!")
+        };
+        paths.retain(|path| path.extension().and_then(|ext| ext.to_str()) == Some("json"));
+        paths.sort();
+
+        paths.iter()
+            .map(|path| {
+                let contents = std::fs::read(path).repl("Error reading mail file!")?;
+                serde_json::from_slice(&contents).repl("Error parsing mail file!")
+            })
+            .collect()
+    }
+}
+
+#[async_trait]
+impl MailSender for FileSender {
+    async fn send(&self, mail: &Mail) -> Result<(), Error> {
+        let sent = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+        let name = format!("{:020}-{}.json", sent.as_nanos(), hex::encode(rand::random::<[u8; 4]>()));
+        let contents = serde_json::to_vec_pretty(mail)
+            .repl("Error serializing mail!")?;
+
+        tokio::fs::create_dir_all(&self.dir).await
+            .repl("Error creating mail directory!")?;
+        tokio::fs::write(self.dir.join(name), contents).await
+            .repl("Error writing mail file!")
+    }
+}
+
+/// Only logs every mail, including the tokens in it, so it must never be used in production.
+pub struct LogSender;
+
+#[async_trait]
+impl MailSender for LogSender {
+    async fn send(&self, mail: &Mail) -> Result<(), Error> {
+        log::info!("Mail to {}, {}:\n{}", mail.to, mail.subject, mail.body);
+
+        Ok(())
+    }
+}
+
+fn smtp_password(config: &Config) -> Result<String, Error> {
+    if let Some(password) = env::var(SMTP_PASSWORD_VAR).ok().filter(|v| !v.is_empty()) {
+        return Ok(password)
+    }
+    match &config.smtp_password_file {
+        Some(path) => Ok(std::fs::read_to_string(path)
+            .repl("Error reading SMTP password file!")?.trim().to_owned()),
+        None => Err(Error {
+            message: "SMTP username is configured without a password!",
+            error_type: Errors::Internal,
+            e: "".to_owned()
+        })
+    }
+}
+
+/// The sender for the configured SMTP server, or else for the mail directory, or else a
+/// [`LogSender`] if `mail_log` is set. Without any of them there is none.
+pub fn from_config(config: &Config) -> Result<Option<MailerRef>, Error> {
+    if let Some(host) = &config.smtp_host {
+        let credentials = match &config.smtp_username {
+            Some(username) => Some(Credentials::new(username.clone(), smtp_password(config)?)),
+            None => None
+        };
+
+        return Ok(Some(Arc::new(SmtpSender::new(host, config.smtp_port, credentials, &config.mail_from)?)))
+    }
+
+    Ok(match &config.mail_dir {
+        Some(dir) => Some(Arc::new(FileSender::new(dir))),
+        None if config.mail_log => Some(Arc::new(LogSender)),
+        None => None
+    })
+}
+
+/// Filter that hands a reference to the mail sender to a handler. Without a sender it rejects
+/// every request with a 503, so that clients can tell the disabled feature from a missing user.
+pub fn with_mailer(mailer: Option<MailerRef>) -> impl Filter<Extract = (MailerRef,), Error = warp::Rejection> + Clone {
+    warp::any()
+        .and_then(move || {
+            let mailer = mailer.clone();
+            async move {
+                mailer.ok_or_else(|| reject(ErrorReject { rt: RejectTypes::Unavailable,
+                    msg: "No mail sender is configured, contact addresses and password resets are disabled!",
+                    e: "".to_owned() }))
+            }
+        })
+}
@@ -1,5 +1,34 @@
 #[tokio::main]
 async fn main() {
-    tiauth::prepare_server().await;
-    tiauth::run_server().await;
+    let config = tiauth::config::Config::load().unwrap_or_else(|e| {
+        eprintln!("Failed to load config: {}", e);
+        std::process::exit(1);
+    });
+    let config = std::sync::Arc::new(config);
+    tiauth::init_logging(&config);
+    let store = tiauth::prepare_server(&config).await.unwrap_or_else(|e| {
+        log::error!("Failed to prepare the server: {}", e);
+        std::process::exit(1);
+    });
+    let keyring = tiauth::prepare_keyring(&config, &store).await.unwrap_or_else(|e| {
+        log::error!("Failed to load the signing keys: {}", e);
+        std::process::exit(1);
+    });
+    let salt_secret = tiauth::prepare_salt_secret(&config, &store).await.unwrap_or_else(|e| {
+        log::error!("Failed to load the salt secret: {}", e);
+        std::process::exit(1);
+    });
+    let admin = tiauth::prepare_admin(&config).unwrap_or_else(|e| {
+        log::error!("Failed to load the admin token: {}", e);
+        std::process::exit(1);
+    });
+    let clients = tiauth::prepare_clients(&config).unwrap_or_else(|e| {
+        log::error!("Failed to load the introspection clients: {}", e);
+        std::process::exit(1);
+    });
+    let mailer = tiauth::prepare_mailer(&config).unwrap_or_else(|e| {
+        log::error!("Failed to set up the mail sender: {}", e);
+        std::process::exit(1);
+    });
+    tiauth::run_server(config, store, keyring, salt_secret, admin, clients, mailer).await;
 }
\ No newline at end of file
@@ -0,0 +1,135 @@
+//! Server-side hashing of the password hashes that clients send.
+//!
+//! Clients send a PBKDF2 hash of the password, which is hashed again with Argon2id before it is
+//! stored as a PHC string. Records from before this was introduced hold the client hash itself
+//! and are rehashed on the next successful login, as are records hashed with other parameters
+//! than the configured ones.
+//!
+//! Comparisons are constant-time, and a login for an unknown user or against a record that was not
+//! rehashed yet does the same Argon2id work as one against a stored hash, see [`verify_unknown`].
+
+use std::convert::TryFrom;
+
+use argon2::password_hash::{self, PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::{Algorithm, Argon2, Params, Version};
+use subtle::ConstantTimeEq;
+
+use crate::config::{Config, ConfigRef};
+use crate::error::{ErrorReject, RejectTypes};
+use crate::reject;
+
+const SALT_LEN: usize = 16;
+
+/// Salt of the hashes that are only computed for their timing, see [`dummy_hash_blocking`].
+const DUMMY_SALT: &str = "AAAAAAAAAAAAAAAAAAAAAA";
+
+/// Result of checking a password against a stored record.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Verified {
+    Incorrect,
+    /// The password is correct. If `rehash` is set, the record should be replaced by a new hash.
+    Correct { rehash: bool },
+}
+
+fn hash_reject(e: password_hash::Error) -> warp::Rejection {
+    reject(ErrorReject { rt: RejectTypes::Internal,
+        msg: "Error hashing password",
+        e: e.to_string() })
+}
+
+fn argon2(config: &Config) -> Result<Argon2<'static>, password_hash::Error> {
+    let params = Params::new(config.argon2_memory_kib, config.argon2_iterations, config.argon2_parallelism, None)?;
+
+    Ok(Argon2::new(Algorithm::Argon2id, Version::V0x13, params))
+}
+
+/// Whether `stored` is a PHC string rather than a client hash stored as is.
+fn is_phc(stored: &str) -> bool {
+    stored.starts_with('$')
+}
+
+fn hash_blocking(config: &Config, password_hash_hex: &str) -> Result<String, password_hash::Error> {
+    let salt = SaltString::encode_b64(&rand::random::<[u8; SALT_LEN]>())?;
+
+    Ok(argon2(config)?.hash_password(password_hash_hex.as_bytes(), &salt)?.to_string())
+}
+
+/// Hashes the password with the configured parameters and throws the hash away. This takes as long
+/// as verifying it against a stored hash, since stored hashes are rehashed with the configured
+/// parameters on login.
+fn dummy_hash_blocking(config: &Config, password_hash_hex: &str) -> Result<(), password_hash::Error> {
+    let salt = SaltString::from_b64(DUMMY_SALT)?;
+    argon2(config)?.hash_password(password_hash_hex.as_bytes(), &salt)?;
+
+    Ok(())
+}
+
+fn verify_blocking(config: &Config, stored: &str, password_hash_hex: &str) -> Result<Verified, password_hash::Error> {
+    if !is_phc(stored) {
+        // Without it, a wrong password for a record that was not rehashed yet would be answered
+        // faster than one for an unknown user.
+        dummy_hash_blocking(config, password_hash_hex)?;
+        return Ok(if bool::from(stored.as_bytes().ct_eq(password_hash_hex.as_bytes())) {
+            Verified::Correct { rehash: true }
+        }
+        else {
+            Verified::Incorrect
+        })
+    }
+
+    let parsed = PasswordHash::new(stored)?;
+    match Argon2::default().verify_password(password_hash_hex.as_bytes(), &parsed) {
+        Ok(()) => {}
+        Err(password_hash::Error::Password) => return Ok(Verified::Incorrect),
+        Err(e) => return Err(e)
+    }
+
+    let configured = argon2(config)?;
+    let rehash = parsed.algorithm.as_str() != Algorithm::Argon2id.as_str()
+        || Params::try_from(&parsed)? != *configured.params();
+
+    Ok(Verified::Correct { rehash })
+}
+
+/// Hashes the client password hash with Argon2id, returning a PHC string.
+pub async fn hash(config: &ConfigRef, password_hash_hex: &str) -> Result<String, warp::Rejection> {
+    let config = config.clone();
+    let password_hash_hex = password_hash_hex.to_owned();
+
+    tokio::task::spawn_blocking(move || hash_blocking(&config, &password_hash_hex))
+        .await
+        .map_err(|e| { reject(ErrorReject { rt: RejectTypes::Internal,
+            msg: "Error hashing password",
+            e: e.to_string() }) })?
+        .map_err(hash_reject)
+}
+
+/// Checks a client password hash against the stored record.
+pub async fn verify(config: &ConfigRef, stored: &str, password_hash_hex: &str) -> Result<Verified, warp::Rejection> {
+    let config = config.clone();
+    let stored = stored.to_owned();
+    let password_hash_hex = password_hash_hex.to_owned();
+
+    tokio::task::spawn_blocking(move || verify_blocking(&config, &stored, &password_hash_hex))
+        .await
+        .map_err(|e| { reject(ErrorReject { rt: RejectTypes::Internal,
+            msg: "Error verifying password",
+            e: e.to_string() }) })?
+        .map_err(hash_reject)
+}
+
+/// Stands in for [`verify`] when the user does not exist. It does the same Argon2id work as
+/// verifying the password against a stored hash and always returns [`Verified::Incorrect`].
+pub async fn verify_unknown(config: &ConfigRef, password_hash_hex: &str) -> Result<Verified, warp::Rejection> {
+    let config = config.clone();
+    let password_hash_hex = password_hash_hex.to_owned();
+
+    tokio::task::spawn_blocking(move || dummy_hash_blocking(&config, &password_hash_hex))
+        .await
+        .map_err(|e| { reject(ErrorReject { rt: RejectTypes::Internal,
+            msg: "Error verifying password",
+            e: e.to_string() }) })?
+        .map_err(hash_reject)?;
+
+    Ok(Verified::Incorrect)
+}
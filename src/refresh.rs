@@ -0,0 +1,132 @@
+//! Refresh tokens, with which a new access token is obtained without the password.
+//!
+//! A refresh token is an opaque random string of which only the SHA-256 hash is stored. It can be
+//! used once: refreshing returns a new access token and a new refresh token in the same family.
+//! A used refresh token that is presented again must have leaked, so its whole family is revoked
+//! and the user has to log in again.
+
+use std::time::Duration;
+
+use sha2::{Digest, Sha256};
+
+use crate::{Deserialize, Serialize};
+use crate::auth::{self, unix_now};
+use crate::config::{Config, ConfigRef};
+use crate::defs;
+use crate::error::{ErrorReject, RejectTypes};
+use crate::keys::KeyringRef;
+use crate::login::{self, JwtResponse};
+use crate::reject;
+use crate::store::{store_reject, StoreErrors, StoreRef};
+
+const TOKEN_LEN: usize = 32;
+
+/// How often [`prune_periodically`] removes expired refresh tokens.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(3600);
+
+#[derive(Deserialize, Serialize)]
+pub struct RefreshRequest {
+    refresh_token: String,
+}
+
+/// The hash a refresh token is stored by.
+pub fn token_hash(refresh_token: &str) -> String {
+    hex::encode(Sha256::digest(refresh_token.as_bytes()))
+}
+
+fn invalid_token(e: &str) -> warp::Rejection {
+    reject(ErrorReject { rt: RejectTypes::Permission,
+        msg: "Refresh token is invalid, log in again!",
+        e: e.to_owned() })
+}
+
+/// Issues a new refresh token for the user, in family `family_id` or else in a new family.
+pub async fn issue(store: &StoreRef, config: &Config, user_hex: &str, family_id: Option<String>,
+                   now: u64) -> Result<String, warp::Rejection> {
+    let refresh_token = base64_url::encode(&rand::random::<[u8; TOKEN_LEN]>());
+    let token = defs::RefreshToken {
+        token_hash: token_hash(&refresh_token),
+        family_id: family_id.unwrap_or_else(|| hex::encode(rand::random::<[u8; 16]>())),
+        user_hex: user_hex.to_owned(),
+        issued: now,
+        expires: now.saturating_add(config.refresh_token_lifetime),
+        used: false
+    };
+    store.write_refresh_token(&token).await
+        .map_err(|e| { store_reject(e, "Error saving refresh token (issue refresh token)",
+                                    "Error saving refresh token (issue refresh token)") })?;
+
+    Ok(refresh_token)
+}
+
+pub async fn refresh_token(
+    request: RefreshRequest, store: StoreRef, config: ConfigRef,
+    keyring: KeyringRef) -> Result<impl warp::Reply, warp::Rejection> {
+    let now = unix_now()?;
+    let hash = token_hash(&request.refresh_token);
+    let _guard = store.lock_refresh_token(&hash).await;
+
+    let mut token = match store.read_refresh_token(&hash).await {
+        Ok(token) => token,
+        Err(e) if matches!(e.error_type, StoreErrors::NonExistent) => return Err(invalid_token("unknown refresh token")),
+        Err(e) => return Err(reject(store_reject(e, "Error reading refresh token (refresh token)",
+                                                 "Refresh token is invalid, log in again!")))
+    };
+    if token.used {
+        store.remove_refresh_family(&token.family_id).await
+            .map_err(|e| { store_reject(e, "Error revoking refresh tokens (refresh token)",
+                                        "Error revoking refresh tokens (refresh token)") })?;
+        log::warn!("A used refresh token of {} was presented again, revoked its family.", token.user_hex);
+        return Err(invalid_token("reused refresh token"))
+    }
+    if token.expires <= now {
+        return Err(reject(ErrorReject { rt: RejectTypes::Expired,
+            msg: "Refresh token expired, log in again!",
+            e: "".to_owned() }))
+    }
+
+    token.used = true;
+    store.write_refresh_token(&token).await
+        .map_err(|e| { store_reject(e, "Error saving refresh token (refresh token)",
+                                    "Error saving refresh token (refresh token)") })?;
+
+    let save_user = match store.read_user(&token.user_hex, true).await {
+        Ok(save_user) => save_user,
+        Err(e) if matches!(e.error_type, StoreErrors::NonExistent) => return Err(invalid_token("user does not exist")),
+        Err(e) => return Err(reject(store_reject(e, "Error reading user data (refresh token)",
+                                                 "User does not exist! (refresh token)")))
+    };
+    if matches!(save_user.tokens_not_before, Some(not_before) if token.issued <= not_before) {
+        store.remove_refresh_family(&token.family_id).await
+            .map_err(|e| { store_reject(e, "Error revoking refresh tokens (refresh token)",
+                                        "Error revoking refresh tokens (refresh token)") })?;
+        return Err(invalid_token("sessions of the user were revoked"))
+    }
+
+    let (public_hex, jwt) = login::access_token(&store, &config, &keyring, &save_user, now).await?;
+    let refresh_token = issue(&store, &config, &token.user_hex, Some(token.family_id),
+                              auth::issue_time(&save_user, now)).await?;
+
+    Ok(warp::reply::json(&JwtResponse {
+        public_hex,
+        jwt,
+        refresh_token,
+    }))
+}
+
+/// Removes expired refresh tokens every hour, never returns.
+pub async fn prune_periodically(store: StoreRef) {
+    let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+    loop {
+        interval.tick().await;
+        let now = match unix_now() {
+            Ok(now) => now,
+            Err(_) => continue
+        };
+        match store.prune_refresh_tokens(now).await {
+            Ok(0) => {},
+            Ok(pruned) => log::info!("Removed {} expired refresh tokens.", pruned),
+            Err(e) => log::error!("Failed to remove expired refresh tokens: {}", e)
+        }
+    }
+}
@@ -1,10 +1,27 @@
-use crate::{defs, files};
+use crate::defs;
 use crate::error::{ErrorReject, RejectTypes};
 use ed25519_zebra::{SigningKey, VerificationKey};
 use rand::rngs;
+use crate::store::{store_reject, StoreErrors, StoreRef};
+use crate::config::ConfigRef;
+use crate::password;
 use crate::reject;
 
-fn generate_keypair() -> (String, String) {
+/// Length of a salt as hex, 16 bytes.
+const SALT_HEX_LEN: usize = 32;
+
+/// Rejects a salt that is not 16 bytes as hex, which the stores could not keep alike.
+pub(crate) fn check_salt_hex(salt_hex: &str) -> Result<(), warp::Rejection> {
+    if salt_hex.len() != SALT_HEX_LEN || !salt_hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(reject(ErrorReject { rt: RejectTypes::DecodeExternal,
+            msg: "Salt must be 32 hex characters!",
+            e: format!("@@@salt length: {}@@@", salt_hex.len()) }))
+    }
+
+    Ok(())
+}
+
+pub(crate) fn generate_keypair() -> (String, String) {
     let os_rng = rngs::OsRng::default();
     let secret = SigningKey::new(os_rng);
     let secret_bytes: [u8; 32] = secret.into();
@@ -14,40 +31,35 @@ fn generate_keypair() -> (String, String) {
 }
 
 pub async fn write_user(
-    user_json: defs::UserJson) -> Result<impl warp::Reply, warp::Rejection> {
-    let f = files::open_user_file(&user_json.user_hex).await;
-    let err = f.err();
-    if err.is_some() {
-        let err = err.unwrap();
-        if files::io_is_nonexistent(&err) {
-            let (public_hex, secret_hex): (String, String) = generate_keypair();
-            files::register_user(&user_json, public_hex, secret_hex).await
-                .map_err(|e| { reject(ErrorReject { rt: RejectTypes::IO,
-                    msg: "Error writing user registration! (write user)",
-                    e: e.to_string() }) })?;
-
-            let empty_claims = defs::Tiauth {
-                claims: vec![]
-            };
-
-            files::write_user_claims(&user_json.user_hex, &empty_claims).await
-                .map_err(|e| { reject(ErrorReject { rt:RejectTypes::IO,
-                    msg: "Error writing empty user claims! (write user)",
-                    e: e.to_string() }) })?;
-
-            Ok(warp::reply::json(&user_json))
-        }
-        else {
-            Err(reject(ErrorReject { rt: RejectTypes::IO,
-                msg: "Error opening user file (write user)",
-                e: err.to_string()
-            }))
-        }
-    }
-    else {
-        let appendix = format!("@@@user_hex: {}@@@", &user_json.user_hex);
-        Err(reject(ErrorReject { rt: RejectTypes::AlreadyExists,
-            msg: "User already exists!",
-            e: appendix }))
-    }
-}
\ No newline at end of file
+    user_json: defs::UserJson, store: StoreRef, config: ConfigRef) -> Result<impl warp::Reply, warp::Rejection> {
+    check_salt_hex(&user_json.salt_hex)?;
+    let (public_hex, secret_hex): (String, String) = generate_keypair();
+    let stored_user = defs::UserJson {
+        user_hex: user_json.user_hex.clone(),
+        password_hash_hex: password::hash(&config, &user_json.password_hash_hex).await?,
+        salt_hex: user_json.salt_hex.clone()
+    };
+    store.register_user(&stored_user, public_hex, secret_hex).await
+        .map_err(|e| {
+            if let StoreErrors::AlreadyExists = e.error_type {
+                let appendix = format!("@@@user_hex: {}@@@", &user_json.user_hex);
+                ErrorReject { rt: RejectTypes::AlreadyExists,
+                    msg: "User already exists!",
+                    e: appendix }
+            }
+            else {
+                store_reject(e, "Error writing user registration! (write user)",
+                             "Error writing user registration! (write user)")
+            }
+        })?;
+
+    let empty_claims = defs::Tiauth {
+        claims: vec![]
+    };
+
+    store.write_user_claims(&user_json.user_hex, &empty_claims).await
+        .map_err(|e| { store_reject(e, "Error writing empty user claims! (write user)",
+                                    "Error writing empty user claims! (write user)") })?;
+
+    Ok(warp::reply::json(&user_json))
+}
@@ -0,0 +1,263 @@
+//! Contact addresses and password resets.
+//!
+//! A user sets its contact address at `/contact` with an access token, after which a token is
+//! mailed there that verifies the address at `/contact/verify`. Until then the new address is only
+//! kept in that token, and the previously verified address stays in place. With a verified
+//! address, a user that forgot its password requests a reset token at `/password_reset/request`,
+//! with which `/password_reset/confirm` sets new credentials and ends every session of the user.
+//!
+//! Mailed tokens are random strings of which only the SHA-256 hash is stored. They can be used
+//! once and only until they expire. Reset tokens are only valid while the user still has the
+//! address they were sent to.
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use crate::{Deserialize, Serialize};
+use crate::auth::{self, unix_now};
+use crate::config::{Config, ConfigRef};
+use crate::credentials;
+use crate::defs::{self, MailTokenPurpose, UserId};
+use crate::error::{ErrorReject, RejectTypes};
+use crate::keys::KeyringRef;
+use crate::mail::{self, Mail, MailerRef};
+use crate::refresh;
+use crate::reject;
+use crate::store::{store_reject, StoreErrors, StoreRef};
+use crate::throttle;
+
+const TOKEN_LEN: usize = 32;
+
+/// How often [`prune_periodically`] removes expired mailed tokens.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(3600);
+
+#[derive(Deserialize, Serialize)]
+pub struct ContactRequest {
+    user_hex: UserId,
+    jwt: String,
+    contact: String,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct VerifyContactRequest {
+    token: String,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct ResetRequest {
+    user_hex: UserId,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct ResetConfirm {
+    token: String,
+    new_password_hash_hex: String,
+    new_salt_hex: String,
+}
+
+fn invalid_token(e: &str) -> warp::Rejection {
+    reject(ErrorReject { rt: RejectTypes::Permission,
+        msg: "Token is invalid or was already used!",
+        e: e.to_owned() })
+}
+
+/// Saves a new token for the user and returns the mail that delivers it.
+async fn issue(store: &StoreRef, config: &Config, user_hex: &str, purpose: MailTokenPurpose, contact: &str,
+               now: u64) -> Result<Mail, warp::Rejection> {
+    let token = base64_url::encode(&rand::random::<[u8; TOKEN_LEN]>());
+    let mail_token = defs::MailToken {
+        token_hash: refresh::token_hash(&token),
+        user_hex: user_hex.to_owned(),
+        purpose,
+        contact: contact.to_owned(),
+        issued: now,
+        expires: now.saturating_add(config.mail_token_lifetime)
+    };
+    store.write_mail_token(&mail_token).await
+        .map_err(|e| { store_reject(e, "Error saving mail token (issue mail token)",
+                                    "Error saving mail token (issue mail token)") })?;
+
+    let minutes = config.mail_token_lifetime / 60;
+    let (subject, body) = match purpose {
+        MailTokenPurpose::VerifyContact => ("Verify your contact address", format!(
+            "Verify this address for {} with the following code, which is valid for {} minutes:\n\n{}\n",
+            user_hex, minutes, token)),
+        MailTokenPurpose::PasswordReset => ("Reset your password", format!(
+            "Reset the password of {} with the following code, which is valid for {} minutes:\n\n{}\n\n\
+If you did not ask for this, you can ignore this mail.\n", user_hex, minutes, token))
+    };
+
+    Ok(Mail { to: contact.to_owned(), subject: subject.to_owned(), body })
+}
+
+/// Reads and removes the token, so that it is used only once. An expired token is removed as
+/// well, but reported as expired even if that fails.
+async fn use_token(store: &StoreRef, token: &str, purpose: MailTokenPurpose,
+                   now: u64) -> Result<defs::MailToken, warp::Rejection> {
+    let hash = refresh::token_hash(token);
+    let _guard = store.lock_mail_token(&hash).await;
+
+    let mail_token = match store.read_mail_token(&hash).await {
+        Ok(mail_token) if mail_token.purpose == purpose => mail_token,
+        Ok(_) => return Err(invalid_token("token has another purpose")),
+        Err(e) if matches!(e.error_type, StoreErrors::NonExistent) => return Err(invalid_token("unknown token")),
+        Err(e) => return Err(reject(store_reject(e, "Error reading mail token (use mail token)",
+                                                 "Token is invalid or was already used!")))
+    };
+    if mail_token.expires <= now {
+        if let Err(e) = store.remove_mail_token(&hash).await {
+            log::error!("Failed to remove an expired mail token: {}", e);
+        }
+        return Err(reject(ErrorReject { rt: RejectTypes::Expired,
+            msg: "Token expired, request a new one!",
+            e: "".to_owned() }))
+    }
+    store.remove_mail_token(&hash).await
+        .map_err(|e| { store_reject(e, "Error removing mail token (use mail token)",
+                                    "Error removing mail token (use mail token)") })?;
+
+    Ok(mail_token)
+}
+
+async fn read_user(store: &StoreRef, user_hex: &str) -> Result<defs::SaveUserJson, warp::Rejection> {
+    store.read_user(user_hex, false).await
+        .map_err(|e| { reject(store_reject(e, "Error reading user data (reset)",
+                                           "User does not exist! (reset)")) })
+}
+
+/// Mails a token to verify a new contact address of the user. The address is only stored once it
+/// is verified, so a typo or someone else with an access token cannot take away the address that
+/// password resets are sent to.
+pub async fn set_contact(
+    request: ContactRequest, store: StoreRef, config: ConfigRef, keyring: KeyringRef,
+    mailer: MailerRef) -> Result<impl warp::Reply, warp::Rejection> {
+    auth::verify_jwt(&store, &keyring, &request.user_hex, &request.jwt,
+                     &auth::Validation::from_config(&config)).await?;
+    let contact = request.contact.trim();
+    if !mail::is_valid_address(contact) {
+        return Err(reject(ErrorReject { rt: RejectTypes::DecodeExternal,
+            msg: "Invalid contact address!",
+            e: format!("@@@contact: {}@@@", contact) }))
+    }
+    let now = unix_now()?;
+
+    let mail = issue(&store, &config, &request.user_hex, MailTokenPurpose::VerifyContact, contact, now).await?;
+    mailer.send(&mail).await
+        .map_err(|e| { reject(ErrorReject { rt: RejectTypes::Internal,
+            msg: "Error sending verification mail (set contact)",
+            e: e.to_string() }) })?;
+
+    Ok(warp::reply())
+}
+
+pub async fn verify_contact(
+    request: VerifyContactRequest, store: StoreRef) -> Result<impl warp::Reply, warp::Rejection> {
+    let now = unix_now()?;
+    let mail_token = use_token(&store, &request.token, MailTokenPurpose::VerifyContact, now).await?;
+
+    store.write_user_contact(&mail_token.user_hex, Some(&mail_token.contact), true).await
+        .map_err(|e| { store_reject(e, "Error writing contact address (verify contact)",
+                                    "User does not exist! (verify contact)") })?;
+
+    Ok(warp::reply())
+}
+
+/// Mails a reset token to the verified contact address of the user, unless the user has to wait
+/// before requesting another one.
+async fn mail_reset(store: &StoreRef, config: &Config, mailer: &MailerRef, user_hex: &str,
+                    now: u64) -> Result<(), warp::Rejection> {
+    let save_user = match store.read_user(user_hex, false).await {
+        Ok(save_user) => save_user,
+        Err(e) if matches!(e.error_type, StoreErrors::NonExistent) => return Ok(()),
+        Err(e) => return Err(reject(store_reject(e, "Error reading user data (request reset)",
+                                                 "User does not exist! (request reset)")))
+    };
+    let contact = match save_user.contact {
+        Some(contact) if save_user.contact_verified => contact,
+        _ => return Ok(())
+    };
+    // Tokens dated at or before the last password change are rejected, so a token requested in
+    // the same second is dated just after it.
+    let issued = save_user.password_changed.map_or(now, |changed| now.max(changed.saturating_add(1)));
+
+    let key = throttle::reset_user_key(user_hex);
+    let throttled = throttle::count_request(store, config, &key, config.login_max_failures, now).await
+        .map_err(|e| { reject(store_reject(e, "Error updating reset attempts (request reset)",
+                                           "Error updating reset attempts (request reset)")) })?;
+    if let Some(until) = throttled {
+        log::info!("Not sending a password reset mail to {} before {}, too many were requested.", user_hex, until);
+        return Ok(())
+    }
+
+    let mail = issue(store, config, user_hex, MailTokenPurpose::PasswordReset, &contact, issued).await?;
+    mailer.send(&mail).await
+        .map_err(|e| { reject(ErrorReject { rt: RejectTypes::Internal,
+            msg: "Error sending reset mail (request reset)",
+            e: e.to_string() }) })
+}
+
+/// Mails a reset token to the verified contact address of the user. Only the address is told to
+/// wait when it sent too many requests. Everything else happens after replying, so that neither
+/// the reply nor its timing reveals whether the user exists, has such an address or was sent a
+/// mail.
+pub async fn request_reset(
+    request: ResetRequest, remote: Option<SocketAddr>, store: StoreRef, config: ConfigRef,
+    mailer: MailerRef) -> Result<impl warp::Reply, warp::Rejection> {
+    let now = unix_now()?;
+    throttle::check_reset_ip(&store, &config, remote.map(|addr| addr.ip()), now).await?;
+
+    let user_hex = request.user_hex.to_string();
+    tokio::spawn(async move {
+        if let Err(e) = mail_reset(&store, &config, &mailer, &user_hex, now).await {
+            log::error!("Failed to send the password reset mail of {}: {:?}", user_hex, e);
+        }
+    });
+
+    Ok(warp::reply())
+}
+
+/// Sets new credentials with a reset token, ends every session of the user and lifts its login
+/// lockout.
+pub async fn confirm_reset(
+    request: ResetConfirm, store: StoreRef, config: ConfigRef) -> Result<impl warp::Reply, warp::Rejection> {
+    let now = unix_now()?;
+    let mail_token = use_token(&store, &request.token, MailTokenPurpose::PasswordReset, now).await?;
+    let user_hex = &mail_token.user_hex;
+
+    let _attempts_guard = throttle::lock_user(&store, user_hex).await;
+    let save_user = read_user(&store, user_hex).await?;
+    if !save_user.contact_verified || save_user.contact.as_deref() != Some(mail_token.contact.as_str()) {
+        return Err(invalid_token("contact address changed"))
+    }
+    if matches!(save_user.password_changed, Some(changed) if mail_token.issued <= changed) {
+        return Err(invalid_token("password changed since the token was issued"))
+    }
+
+    credentials::replace_password(&store, &config, user_hex, &request.new_password_hash_hex,
+                                  &request.new_salt_hex, true, now).await?;
+    // Like a successful login, this clears the failed logins of the user, and its reset requests.
+    throttle::record_success(&store, user_hex).await?;
+    throttle::unlock(&store, &throttle::reset_user_key(user_hex)).await
+        .map_err(|e| { store_reject(e, "Error updating reset attempts (confirm reset)",
+                                    "Error updating reset attempts (confirm reset)") })?;
+    log::info!("Reset the password of {}.", user_hex);
+
+    Ok(warp::reply())
+}
+
+/// Removes expired mailed tokens every hour, never returns.
+pub async fn prune_periodically(store: StoreRef) {
+    let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+    loop {
+        interval.tick().await;
+        let now = match unix_now() {
+            Ok(now) => now,
+            Err(_) => continue
+        };
+        match store.prune_mail_tokens(now).await {
+            Ok(0) => {},
+            Ok(pruned) => log::info!("Removed {} expired mail tokens.", pruned),
+            Err(e) => log::error!("Failed to remove expired mail tokens: {}", e)
+        }
+    }
+}
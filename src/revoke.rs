@@ -0,0 +1,114 @@
+//! Revocation of access tokens before they expire.
+//!
+//! Revoked tokens are identified by their `jti` and kept on the revocation list until they would
+//! have expired anyway. [`crate::auth::verify_jwt`] rejects them, and resource servers that verify
+//! tokens themselves can poll the list at `/revoked`.
+
+use std::time::Duration;
+
+use crate::{Deserialize, Serialize};
+use crate::auth::{self, unix_now};
+use crate::config::ConfigRef;
+use crate::defs::{self, UserId};
+use crate::keys::KeyringRef;
+use crate::refresh;
+use crate::reject;
+use crate::store::{store_reject, StoreErrors, StoreRef};
+
+/// Seconds resource servers may cache the revocation list.
+const REVOKED_MAX_AGE: u64 = 60;
+
+/// How often [`prune_periodically`] removes expired entries from the revocation list.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(3600);
+
+#[derive(Deserialize, Serialize)]
+pub struct LogoutRequest {
+    user_hex: UserId,
+    jwt: String,
+    /// If given, the refresh tokens of the same login are revoked as well.
+    #[serde(default)]
+    refresh_token: Option<String>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct RevokeRequest {
+    jti: String,
+    /// The `exp` of the token. If it is unknown, the token is remembered for the longest time a
+    /// token can be valid.
+    #[serde(default)]
+    exp: Option<u64>,
+}
+
+async fn revoke(store: &StoreRef, jti: &str, expires: u64) -> Result<(), warp::Rejection> {
+    store.revoke_token(&defs::RevokedToken { jti: jti.to_owned(), expires }).await
+        .map_err(|e| { reject(store_reject(e, "Error revoking token (revoke)",
+                                           "Error revoking token (revoke)")) })
+}
+
+/// Revokes the token the user logs out with, and its refresh tokens if they are given.
+pub async fn logout(
+    request: LogoutRequest, store: StoreRef, config: ConfigRef,
+    keyring: KeyringRef) -> Result<impl warp::Reply, warp::Rejection> {
+    let claims = auth::verify_jwt(&store, &keyring, &request.user_hex, &request.jwt,
+                                  &auth::Validation::from_config(&config)).await?;
+    // Verification tolerates the clock skew, so the token has to be remembered that much longer.
+    revoke(&store, &claims.jti, claims.exp.saturating_add(config.clock_skew)).await?;
+
+    if let Some(refresh_token) = request.refresh_token {
+        match store.read_refresh_token(&refresh::token_hash(&refresh_token)).await {
+            Ok(token) if token.user_hex == *request.user_hex => {
+                store.remove_refresh_family(&token.family_id).await
+                    .map_err(|e| { store_reject(e, "Error revoking refresh tokens (logout)",
+                                                "Error revoking refresh tokens (logout)") })?;
+            }
+            Ok(_) => {}
+            Err(e) if matches!(e.error_type, StoreErrors::NonExistent) => {}
+            Err(e) => return Err(reject(store_reject(e, "Error reading refresh token (logout)",
+                                                     "Error reading refresh token (logout)")))
+        }
+    }
+
+    Ok(warp::reply())
+}
+
+/// Revokes any token by its `jti`, for admins.
+pub async fn revoke_token(
+    request: RevokeRequest, store: StoreRef, config: ConfigRef) -> Result<impl warp::Reply, warp::Rejection> {
+    let exp = match request.exp {
+        Some(exp) => exp,
+        None => unix_now()?.saturating_add(config.token_lifetime)
+    };
+    revoke(&store, &request.jti, exp.saturating_add(config.clock_skew)).await?;
+    log::info!("Revoked token {}.", request.jti);
+
+    Ok(warp::reply())
+}
+
+/// Replies with the revocation list, see `/revoked`.
+pub async fn reply_revoked(store: StoreRef) -> Result<impl warp::Reply, warp::Rejection> {
+    let now = unix_now()?;
+    let mut revoked = store.read_revoked_tokens().await
+        .map_err(|e| { store_reject(e, "Error reading revoked tokens (revoked)",
+                                    "Error reading revoked tokens (revoked)") })?;
+    revoked.retain(|token| token.expires > now);
+
+    Ok(warp::reply::with_header(warp::reply::json(&defs::RevokedTokens { revoked }), "cache-control",
+                                format!("public, max-age={}", REVOKED_MAX_AGE)))
+}
+
+/// Removes expired entries from the revocation list every hour, never returns.
+pub async fn prune_periodically(store: StoreRef) {
+    let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+    loop {
+        interval.tick().await;
+        let now = match unix_now() {
+            Ok(now) => now,
+            Err(_) => continue
+        };
+        match store.prune_revoked_tokens(now).await {
+            Ok(0) => {},
+            Ok(pruned) => log::info!("Removed {} expired entries from the revocation list.", pruned),
+            Err(e) => log::error!("Failed to prune the revocation list: {}", e)
+        }
+    }
+}
@@ -0,0 +1,118 @@
+//! Pseudo-salts for users that do not exist, so `/user_salt` does not reveal which users do.
+//!
+//! The pseudo-salt of a user is the truncated HMAC-SHA256 of its user_hex under a server secret.
+//! It is the same on every request and looks like a real salt, until the user registers. Without
+//! a configured secret, one is generated once and kept in the store, so that pseudo-salts do not
+//! change on a restart while real salts stay the same.
+
+use std::convert::Infallible;
+use std::env;
+use std::sync::Arc;
+
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+use warp::Filter;
+
+use crate::config::Config;
+use crate::error::{Error, ErrorExt, Errors};
+use crate::store::{StoreError, StoreErrors, StoreRef};
+
+/// Environment variable holding the hex salt secret, it takes precedence over `salt_secret_file`.
+pub const SALT_SECRET_VAR: &str = "TIAUTH_SALT_SECRET";
+
+const SECRET_LEN: usize = 32;
+/// Length of a salt in bytes, real salts are 32 hex characters.
+const SALT_LEN: usize = 16;
+
+pub struct SaltSecret {
+    mac: Hmac<Sha256>,
+}
+
+impl SaltSecret {
+    /// A new random salt secret as hex.
+    pub fn generate_hex() -> String {
+        hex::encode(rand::random::<[u8; SECRET_LEN]>())
+    }
+
+    pub fn from_hex(secret_hex: &str) -> Result<Self, Error> {
+        let secret = hex::decode(secret_hex.trim())
+            .repl("Salt secret is not valid hex!")?;
+        if secret.len() != SECRET_LEN {
+            return Err(Error {
+                message: "Salt secret must be 32 bytes!",
+                error_type: Errors::DecodeInternal,
+                e: format!("length: {}", secret.len())
+            })
+        }
+
+        Ok(SaltSecret::new(&secret))
+    }
+
+    pub fn from_file(path: &std::path::Path) -> Result<Self, Error> {
+        let secret_hex = std::fs::read_to_string(path)
+            .repl("Error reading salt secret file!")?;
+
+        SaltSecret::from_hex(&secret_hex)
+    }
+
+    /// Loads the salt secret from `TIAUTH_SALT_SECRET` or else from the configured file, if any.
+    pub fn from_config(config: &Config) -> Result<Option<Self>, Error> {
+        if let Some(secret_hex) = env::var(SALT_SECRET_VAR).ok().filter(|v| !v.is_empty()) {
+            return SaltSecret::from_hex(&secret_hex).map(Some)
+        }
+
+        config.salt_secret_file.as_deref().map(SaltSecret::from_file).transpose()
+    }
+
+    /// Loads the salt secret from the store, first generating and storing one if there is none.
+    pub async fn load_or_create(store: &StoreRef) -> Result<Self, Error> {
+        if let Some(secret_hex) = store.read_salt_secret().await.repl("Error reading the stored salt secret!")? {
+            return SaltSecret::from_hex(&secret_hex)
+        }
+
+        let secret_hex = SaltSecret::generate_hex();
+        match store.add_salt_secret(&secret_hex).await {
+            Ok(()) => {
+                log::info!("Generated a salt secret and saved it in the store.");
+                SaltSecret::from_hex(&secret_hex)
+            }
+            // Another instance on the same store saved one first.
+            Err(StoreError { error_type: StoreErrors::AlreadyExists, .. }) => {
+                let secret_hex = store.read_salt_secret().await.repl("Error reading the stored salt secret!")?
+                    .ok_or_else(|| Error {
+                        message: "Stored salt secret disappeared!",
+                        error_type: Errors::Internal,
+                        e: "".to_owned()
+                    })?;
+                SaltSecret::from_hex(&secret_hex)
+            }
+            Err(e) => Err(e).repl("Error saving the salt secret!")
+        }
+    }
+
+    /// A random secret, only valid for the lifetime of the process.
+    pub fn random() -> Self {
+        SaltSecret::new(&rand::random::<[u8; SECRET_LEN]>())
+    }
+
+    fn new(secret: &[u8]) -> Self {
+        SaltSecret {
+            mac: Hmac::new_from_slice(secret).expect("HMAC accepts keys of any length")
+        }
+    }
+
+    /// The pseudo-salt of `user_hex` as hex.
+    pub fn fake_salt_hex(&self, user_hex: &str) -> String {
+        let mut mac = self.mac.clone();
+        mac.update(user_hex.as_bytes());
+
+        hex::encode(&mac.finalize().into_bytes()[..SALT_LEN])
+    }
+}
+
+pub type SaltSecretRef = Arc<SaltSecret>;
+
+/// Filter that hands a reference to the salt secret to a handler.
+pub fn with_salt_secret(salt_secret: SaltSecretRef) -> impl Filter<Extract = (SaltSecretRef,), Error = Infallible> + Clone {
+    warp::any().map(move || salt_secret.clone())
+}
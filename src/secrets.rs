@@ -0,0 +1,475 @@
+//! Envelope encryption of the secret keys in the store.
+//!
+//! Every secret is encrypted with its own random data key, which is in turn encrypted with the
+//! master key. Rotating the master key therefore only re-encrypts the data keys. A sealed secret
+//! is stored as `enc1.<master key id>.<encrypted data key>.<encrypted secret>`, where both
+//! encrypted parts are the base64url encoded nonce followed by the ChaCha20-Poly1305 ciphertext.
+//! Both are bound to the [`SecretOwner`] as associated data, so a sealed secret that is copied to
+//! another user or signing key does not open.
+
+use std::env;
+
+use async_trait::async_trait;
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use rand::RngCore;
+use rand::rngs::OsRng;
+use sha2::{Digest, Sha256};
+
+use crate::config::Config;
+use crate::defs;
+use crate::error::{Error, ErrorExt, Errors};
+use crate::store::{Store, StoreError, StoreErrors, StoreLocks, StoreRef, StoreResult};
+
+/// Environment variable holding the hex master key, it takes precedence over `master_key_file`.
+pub const MASTER_KEY_VAR: &str = "TIAUTH_MASTER_KEY";
+
+const SEALED_PREFIX: &str = "enc1";
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+
+fn seal_error<E: ToString>(e: E) -> StoreError {
+    StoreError {
+        error_type: StoreErrors::Decode,
+        e: e.to_string()
+    }
+}
+
+fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut bytes = [0u8; N];
+    OsRng.fill_bytes(&mut bytes);
+
+    bytes
+}
+
+fn encrypt(cipher: &ChaCha20Poly1305, plaintext: &[u8], aad: &[u8]) -> StoreResult<String> {
+    let nonce = random_bytes::<NONCE_LEN>();
+    let mut sealed = nonce.to_vec();
+    sealed.extend(cipher.encrypt(Nonce::from_slice(&nonce), Payload { msg: plaintext, aad }).map_err(seal_error)?);
+
+    Ok(base64_url::encode(&sealed))
+}
+
+fn decrypt(cipher: &ChaCha20Poly1305, sealed: &str, aad: &[u8]) -> StoreResult<Vec<u8>> {
+    let sealed = base64_url::decode(sealed).map_err(seal_error)?;
+    if sealed.len() < NONCE_LEN {
+        return Err(seal_error("sealed value is too short"))
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+
+    cipher.decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad }).map_err(seal_error)
+}
+
+/// What a secret is stored for.
+#[derive(Debug, Clone, Copy)]
+pub enum SecretOwner<'a> {
+    /// The `secret_hex` of the user with this user hex.
+    User(&'a str),
+    /// The `secret_hex` of the server signing key with this key ID.
+    SigningKey(&'a str),
+    /// The stored salt secret.
+    SaltSecret,
+}
+
+impl SecretOwner<'_> {
+    /// The associated data of the sealed secret: the record and the field it is stored in.
+    fn aad(&self) -> Vec<u8> {
+        match self {
+            SecretOwner::User(user_hex) => format!("user:{}:secret_hex", user_hex),
+            SecretOwner::SigningKey(kid) => format!("signing_key:{}:secret_hex", kid),
+            SecretOwner::SaltSecret => "salt_secret:secret_hex".to_owned(),
+        }.into_bytes()
+    }
+}
+
+/// Whether `secret` was sealed by a master key, rather than stored as plain hex.
+pub fn is_sealed(secret: &str) -> bool {
+    secret.starts_with(SEALED_PREFIX) && secret[SEALED_PREFIX.len()..].starts_with('.')
+}
+
+/// The ID of the master key that sealed `secret`.
+pub fn sealed_key_id(secret: &str) -> Option<&str> {
+    if !is_sealed(secret) {
+        return None
+    }
+
+    secret.split('.').nth(1)
+}
+
+/// Splits a sealed secret into the ID of its master key, its encrypted data key and the
+/// encrypted secret itself.
+fn split_sealed(secret: &str) -> StoreResult<(&str, &str, &str)> {
+    let mut parts = secret.split('.');
+    match (parts.next(), parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(SEALED_PREFIX), Some(key_id), Some(data_key), Some(ciphertext), None) => Ok((key_id, data_key, ciphertext)),
+        _ => Err(seal_error("malformed sealed secret"))
+    }
+}
+
+pub struct MasterKey {
+    id: String,
+    cipher: ChaCha20Poly1305,
+}
+
+impl MasterKey {
+    /// A new random master key as hex.
+    pub fn generate_hex() -> String {
+        hex::encode(random_bytes::<KEY_LEN>())
+    }
+
+    pub fn from_hex(key_hex: &str) -> Result<Self, Error> {
+        let key = hex::decode(key_hex.trim())
+            .repl("Master key is not valid hex!")?;
+        if key.len() != KEY_LEN {
+            return Err(Error {
+                message: "Master key must be 32 bytes!",
+                error_type: Errors::DecodeInternal,
+                e: format!("length: {}", key.len())
+            })
+        }
+
+        Ok(MasterKey {
+            id: hex::encode(&Sha256::digest(&key)[..8]),
+            cipher: ChaCha20Poly1305::new(Key::from_slice(&key))
+        })
+    }
+
+    pub fn from_file(path: &std::path::Path) -> Result<Self, Error> {
+        let key_hex = std::fs::read_to_string(path)
+            .repl("Error reading master key file!")?;
+
+        MasterKey::from_hex(&key_hex)
+    }
+
+    /// Loads the master key from `TIAUTH_MASTER_KEY` or else from the configured file, if any.
+    pub fn from_config(config: &Config) -> Result<Option<Self>, Error> {
+        if let Some(key_hex) = env::var(MASTER_KEY_VAR).ok().filter(|v| !v.is_empty()) {
+            return MasterKey::from_hex(&key_hex).map(Some)
+        }
+
+        config.master_key_file.as_deref().map(MasterKey::from_file).transpose()
+    }
+
+    /// Identifies the key without revealing it, it is stored with every secret it seals.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn seal(&self, owner: SecretOwner, secret_hex: &str) -> StoreResult<String> {
+        let data_key = random_bytes::<KEY_LEN>();
+        let data_cipher = ChaCha20Poly1305::new(Key::from_slice(&data_key));
+        let aad = owner.aad();
+
+        Ok(format!("{}.{}.{}.{}", SEALED_PREFIX, self.id,
+                   encrypt(&self.cipher, &data_key, &aad)?,
+                   encrypt(&data_cipher, secret_hex.as_bytes(), &aad)?))
+    }
+
+    /// Decrypts a secret sealed for `owner`. Secrets that are not sealed are returned as they are.
+    pub fn open(&self, owner: SecretOwner, secret: &str) -> StoreResult<String> {
+        if !is_sealed(secret) {
+            return Ok(secret.to_owned())
+        }
+        let (key_id, data_key, ciphertext) = split_sealed(secret)?;
+        self.check_id(key_id)?;
+        let aad = owner.aad();
+
+        let data_key = decrypt(&self.cipher, data_key, &aad)?;
+        let data_cipher = ChaCha20Poly1305::new(Key::from_slice(&data_key));
+        let secret_hex = decrypt(&data_cipher, ciphertext, &aad)?;
+
+        String::from_utf8(secret_hex).map_err(seal_error)
+    }
+
+    /// Re-encrypts the data key of a secret sealed by this key with `new_key`. Secrets that are
+    /// not sealed yet are sealed by `new_key`.
+    pub fn rewrap(&self, new_key: &MasterKey, owner: SecretOwner, secret: &str) -> StoreResult<String> {
+        if !is_sealed(secret) {
+            return new_key.seal(owner, secret)
+        }
+        let (key_id, data_key, ciphertext) = split_sealed(secret)?;
+        self.check_id(key_id)?;
+        let aad = owner.aad();
+        let data_key = decrypt(&self.cipher, data_key, &aad)?;
+
+        Ok(format!("{}.{}.{}.{}", SEALED_PREFIX, new_key.id, encrypt(&new_key.cipher, &data_key, &aad)?, ciphertext))
+    }
+
+    fn check_id(&self, key_id: &str) -> StoreResult<()> {
+        if key_id != self.id {
+            return Err(seal_error(format!("secret was sealed by master key {}, not by {}", key_id, self.id)))
+        }
+
+        Ok(())
+    }
+}
+
+/// Every secret key in the store, of users and the server keyring, and the salt secret.
+async fn stored_secrets(store: &StoreRef) -> StoreResult<Vec<String>> {
+    let mut secrets: Vec<String> = store.user_secrets().await?.into_iter()
+        .map(|(_, secret)| secret)
+        .collect();
+    secrets.extend(store.read_signing_keys().await?.into_iter().map(|key| key.secret_hex));
+    secrets.extend(store.read_salt_secret().await?);
+
+    Ok(secrets)
+}
+
+/// Checks that every sealed secret in `store` can be opened with `master_key`, so that the server
+/// refuses to start instead of failing on every login. Returns the number of secrets that are
+/// not sealed.
+pub async fn check_secrets(store: &StoreRef, master_key: Option<&MasterKey>) -> StoreResult<usize> {
+    let mut unsealed = 0;
+    for secret in stored_secrets(store).await? {
+        match (sealed_key_id(&secret), master_key) {
+            (None, _) => unsealed += 1,
+            (Some(_), None) => return Err(seal_error("store contains encrypted secrets, but no master key is configured")),
+            (Some(key_id), Some(master_key)) if key_id != master_key.id() => {
+                return Err(seal_error(format!("store contains secrets encrypted with master key {}, but the configured master key is {}",
+                                              key_id, master_key.id())))
+            }
+            (Some(_), Some(_)) => {}
+        }
+    }
+
+    Ok(unsealed)
+}
+
+/// Re-encrypts every secret in the raw (not [`SealedStore`]) `store` with `new_key`, sealing the
+/// ones that are not encrypted yet. Secrets already sealed by `new_key` are skipped, so an
+/// interrupted rotation can be completed by running it again. Returns the number of secrets that
+/// were rewritten.
+pub async fn rotate_master_key(store: &StoreRef, old_key: Option<&MasterKey>, new_key: &MasterKey) -> StoreResult<usize> {
+    let rewrap = |owner: SecretOwner, secret: &str| -> StoreResult<Option<String>> {
+        match (sealed_key_id(secret), old_key) {
+            (Some(key_id), _) if key_id == new_key.id() => Ok(None),
+            (Some(_), Some(old_key)) => old_key.rewrap(new_key, owner, secret).map(Some),
+            (Some(_), None) => Err(seal_error("store contains encrypted secrets, but no old master key was given")),
+            (None, _) => new_key.seal(owner, secret).map(Some),
+        }
+    };
+
+    let mut rewritten = 0;
+    for (user_hex, secret) in store.user_secrets().await? {
+        if let Some(secret) = rewrap(SecretOwner::User(&user_hex), &secret)? {
+            store.write_user_secret(&user_hex, &secret).await?;
+            rewritten += 1;
+        }
+    }
+
+    let mut keys = store.read_signing_keys().await?;
+    let mut keys_rewritten = 0;
+    for key in keys.iter_mut() {
+        if let Some(secret) = rewrap(SecretOwner::SigningKey(&key.kid), &key.secret_hex)? {
+            key.secret_hex = secret;
+            keys_rewritten += 1;
+        }
+    }
+    if keys_rewritten > 0 {
+        store.write_signing_keys(&keys).await?;
+    }
+
+    if let Some(secret) = store.read_salt_secret().await? {
+        if let Some(secret) = rewrap(SecretOwner::SaltSecret, &secret)? {
+            store.write_salt_secret(&secret).await?;
+            rewritten += 1;
+        }
+    }
+
+    Ok(rewritten + keys_rewritten)
+}
+
+/// [`Store`] that seals secret keys before they are written to the store it wraps and opens them
+/// after they are read. Everything else is passed through.
+pub struct SealedStore {
+    inner: StoreRef,
+    master_key: MasterKey,
+}
+
+impl SealedStore {
+    pub fn new(inner: StoreRef, master_key: MasterKey) -> Self {
+        SealedStore {
+            inner,
+            master_key
+        }
+    }
+}
+
+#[async_trait]
+impl Store for SealedStore {
+    async fn prepare(&self) -> StoreResult<()> {
+        self.inner.prepare().await
+    }
+
+    async fn read_user(&self, user_hex: &str, secret: bool) -> StoreResult<defs::SaveUserJson> {
+        let mut save_user = self.inner.read_user(user_hex, secret).await?;
+        if secret {
+            save_user.secret_hex = self.master_key.open(SecretOwner::User(user_hex), &save_user.secret_hex)?;
+        }
+
+        Ok(save_user)
+    }
+
+    async fn register_user(&self, user_json: &defs::UserJson, public_hex: String,
+                           secret_hex: String) -> StoreResult<()> {
+        let secret_hex = self.master_key.seal(SecretOwner::User(&user_json.user_hex), &secret_hex)?;
+
+        self.inner.register_user(user_json, public_hex, secret_hex).await
+    }
+
+    async fn write_user_password(&self, user_hex: &str, password_hash: &str) -> StoreResult<()> {
+        self.inner.write_user_password(user_hex, password_hash).await
+    }
+
+    async fn write_user_credentials(&self, user_hex: &str, password_hash: &str, salt_hex: &str,
+                                    changed: u64, revoke_sessions: bool) -> StoreResult<()> {
+        self.inner.write_user_credentials(user_hex, password_hash, salt_hex, changed, revoke_sessions).await
+    }
+
+    async fn write_tokens_not_before(&self, user_hex: &str, not_before: u64) -> StoreResult<()> {
+        self.inner.write_tokens_not_before(user_hex, not_before).await
+    }
+
+    async fn write_user_contact(&self, user_hex: &str, contact: Option<&str>, verified: bool) -> StoreResult<()> {
+        self.inner.write_user_contact(user_hex, contact, verified).await
+    }
+
+    async fn user_secrets(&self) -> StoreResult<Vec<(String, String)>> {
+        self.inner.user_secrets().await?.into_iter()
+            .map(|(user_hex, secret)| {
+                let secret = self.master_key.open(SecretOwner::User(&user_hex), &secret)?;
+                Ok((user_hex, secret))
+            })
+            .collect()
+    }
+
+    async fn write_user_secret(&self, user_hex: &str, secret_hex: &str) -> StoreResult<()> {
+        let secret_hex = self.master_key.seal(SecretOwner::User(user_hex), secret_hex)?;
+
+        self.inner.write_user_secret(user_hex, &secret_hex).await
+    }
+
+    async fn read_user_claims(&self, user_hex: &str) -> StoreResult<defs::Tiauth> {
+        self.inner.read_user_claims(user_hex).await
+    }
+
+    async fn write_user_claims(&self, user_hex: &str, user_claims: &defs::Tiauth) -> StoreResult<()> {
+        self.inner.write_user_claims(user_hex, user_claims).await
+    }
+
+    async fn write_many_user_claims(&self, users_claims: &[(String, defs::Tiauth)]) -> StoreResult<()> {
+        self.inner.write_many_user_claims(users_claims).await
+    }
+
+    async fn read_resources(&self) -> StoreResult<defs::Resources> {
+        self.inner.read_resources().await
+    }
+
+    async fn add_resource(&self, resource_id: &str) -> StoreResult<()> {
+        self.inner.add_resource(resource_id).await
+    }
+
+    async fn remove_resource(&self, resource_id: &str) -> StoreResult<()> {
+        self.inner.remove_resource(resource_id).await
+    }
+
+    async fn read_signing_keys(&self) -> StoreResult<Vec<defs::SigningKey>> {
+        let mut keys = self.inner.read_signing_keys().await?;
+        for key in keys.iter_mut() {
+            key.secret_hex = self.master_key.open(SecretOwner::SigningKey(&key.kid), &key.secret_hex)?;
+        }
+
+        Ok(keys)
+    }
+
+    async fn write_signing_keys(&self, keys: &[defs::SigningKey]) -> StoreResult<()> {
+        let mut keys = keys.to_vec();
+        for key in keys.iter_mut() {
+            key.secret_hex = self.master_key.seal(SecretOwner::SigningKey(&key.kid), &key.secret_hex)?;
+        }
+
+        self.inner.write_signing_keys(&keys).await
+    }
+
+    async fn read_salt_secret(&self) -> StoreResult<Option<String>> {
+        self.inner.read_salt_secret().await?
+            .map(|secret| self.master_key.open(SecretOwner::SaltSecret, &secret))
+            .transpose()
+    }
+
+    async fn add_salt_secret(&self, secret_hex: &str) -> StoreResult<()> {
+        self.inner.add_salt_secret(&self.master_key.seal(SecretOwner::SaltSecret, secret_hex)?).await
+    }
+
+    async fn write_salt_secret(&self, secret_hex: &str) -> StoreResult<()> {
+        self.inner.write_salt_secret(&self.master_key.seal(SecretOwner::SaltSecret, secret_hex)?).await
+    }
+
+    async fn read_login_attempts(&self, key: &str) -> StoreResult<defs::LoginAttempts> {
+        self.inner.read_login_attempts(key).await
+    }
+
+    async fn write_login_attempts(&self, key: &str, attempts: &defs::LoginAttempts) -> StoreResult<()> {
+        self.inner.write_login_attempts(key, attempts).await
+    }
+
+    async fn remove_login_attempts(&self, key: &str) -> StoreResult<()> {
+        self.inner.remove_login_attempts(key).await
+    }
+
+    async fn prune_login_attempts(&self, now: u64, lockout: u64) -> StoreResult<usize> {
+        self.inner.prune_login_attempts(now, lockout).await
+    }
+
+    async fn read_refresh_token(&self, token_hash: &str) -> StoreResult<defs::RefreshToken> {
+        self.inner.read_refresh_token(token_hash).await
+    }
+
+    async fn write_refresh_token(&self, token: &defs::RefreshToken) -> StoreResult<()> {
+        self.inner.write_refresh_token(token).await
+    }
+
+    async fn remove_refresh_family(&self, family_id: &str) -> StoreResult<()> {
+        self.inner.remove_refresh_family(family_id).await
+    }
+
+    async fn prune_refresh_tokens(&self, now: u64) -> StoreResult<usize> {
+        self.inner.prune_refresh_tokens(now).await
+    }
+
+    async fn read_mail_token(&self, token_hash: &str) -> StoreResult<defs::MailToken> {
+        self.inner.read_mail_token(token_hash).await
+    }
+
+    async fn write_mail_token(&self, token: &defs::MailToken) -> StoreResult<()> {
+        self.inner.write_mail_token(token).await
+    }
+
+    async fn remove_mail_token(&self, token_hash: &str) -> StoreResult<()> {
+        self.inner.remove_mail_token(token_hash).await
+    }
+
+    async fn prune_mail_tokens(&self, now: u64) -> StoreResult<usize> {
+        self.inner.prune_mail_tokens(now).await
+    }
+
+    async fn revoke_token(&self, token: &defs::RevokedToken) -> StoreResult<()> {
+        self.inner.revoke_token(token).await
+    }
+
+    async fn is_token_revoked(&self, jti: &str) -> StoreResult<bool> {
+        self.inner.is_token_revoked(jti).await
+    }
+
+    async fn read_revoked_tokens(&self) -> StoreResult<Vec<defs::RevokedToken>> {
+        self.inner.read_revoked_tokens().await
+    }
+
+    async fn prune_revoked_tokens(&self, now: u64) -> StoreResult<usize> {
+        self.inner.prune_revoked_tokens(now).await
+    }
+
+    fn locks(&self) -> &StoreLocks {
+        self.inner.locks()
+    }
+}
@@ -0,0 +1,285 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use tokio::io;
+use tokio::sync::{Mutex as AsyncMutex, OwnedMutexGuard};
+use warp::Filter;
+
+use crate::defs;
+use crate::error::{ErrorReject, RejectTypes};
+use crate::files;
+
+#[derive(Debug)]
+pub enum StoreErrors {
+    NonExistent,
+    AlreadyExists,
+    Invalid,
+    IO,
+    Decode,
+}
+
+#[derive(Debug)]
+pub struct StoreError {
+    pub error_type: StoreErrors,
+    pub e: String,
+}
+
+impl Display for StoreError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "{:?}: {}", self.error_type, self.e)
+    }
+}
+
+impl std::error::Error for StoreError { }
+
+impl From<io::Error> for StoreError {
+    fn from(e: io::Error) -> Self {
+        let error_type = if files::io_is_nonexistent(&e) {
+            StoreErrors::NonExistent
+        }
+        else {
+            StoreErrors::IO
+        };
+
+        StoreError {
+            error_type,
+            e: e.to_string()
+        }
+    }
+}
+
+impl From<serde_json::Error> for StoreError {
+    fn from(e: serde_json::Error) -> Self {
+        StoreError {
+            error_type: StoreErrors::Decode,
+            e: e.to_string()
+        }
+    }
+}
+
+pub type StoreResult<T> = Result<T, StoreError>;
+
+/// Maps a [`StoreError`] to an [`ErrorReject`], using `ne_msg` if the requested record does not
+/// exist and `msg` otherwise.
+pub fn store_reject(e: StoreError, msg: &'static str, ne_msg: &'static str) -> ErrorReject {
+    let (rt, msg) = match e.error_type {
+        StoreErrors::NonExistent => (RejectTypes::NonExistent, ne_msg),
+        StoreErrors::AlreadyExists => (RejectTypes::AlreadyExists, msg),
+        StoreErrors::Invalid => (RejectTypes::Incorrect, msg),
+        StoreErrors::IO => (RejectTypes::IO, msg),
+        StoreErrors::Decode => (RejectTypes::DecodeInternal, msg),
+    };
+
+    ErrorReject {
+        rt,
+        msg,
+        e: e.e
+    }
+}
+
+/// Number of idle locks [`KeyedLocks`] keeps around before it starts dropping them.
+const IDLE_LOCKS: usize = 1024;
+
+/// A set of async locks, one for every key.
+#[derive(Default)]
+pub struct KeyedLocks {
+    locks: Mutex<HashMap<String, Arc<AsyncMutex<()>>>>,
+}
+
+impl KeyedLocks {
+    /// Waits until no one else holds the lock for `key`. The lock is held until the guard is dropped.
+    pub async fn lock(&self, key: &str) -> OwnedMutexGuard<()> {
+        let lock = {
+            let mut locks = self.locks.lock().unwrap();
+            if locks.len() > IDLE_LOCKS {
+                locks.retain(|_, lock| Arc::strong_count(lock) > 1);
+            }
+            locks.entry(key.to_owned()).or_default().clone()
+        };
+
+        lock.lock_owned().await
+    }
+}
+
+/// Locks used to serialize read-modify-write cycles on a store.
+#[derive(Default)]
+pub struct StoreLocks {
+    user_claims: KeyedLocks,
+    resources: KeyedLocks,
+    login_attempts: KeyedLocks,
+    refresh_tokens: KeyedLocks,
+    mail_tokens: KeyedLocks,
+    user_records: KeyedLocks,
+}
+
+impl StoreLocks {
+    /// Locks the stored record of a user while a store replaces some of its fields. Only a store
+    /// takes this lock, around a single read-modify-write of the record.
+    pub(crate) async fn lock_user_record(&self, user_hex: &str) -> OwnedMutexGuard<()> {
+        self.user_records.lock(user_hex).await
+    }
+}
+
+/// Storage backend for users, their claims, the index of existing resources, the server signing
+/// keys, the salt secret, the failed login counters, the refresh tokens, the revoked access tokens and the tokens
+/// mailed to users.
+///
+/// Handlers only talk to storage through this trait, so that the backend can be swapped without
+/// touching their logic. [`files::FileStore`] is the JSON file implementation.
+#[async_trait]
+pub trait Store: Send + Sync {
+    /// Creates whatever the backend needs before it can be used. Existing data is left untouched.
+    async fn prepare(&self) -> StoreResult<()>;
+
+    /// Reads a user. If `secret` is false, the secret key and password hash are blanked.
+    async fn read_user(&self, user_hex: &str, secret: bool) -> StoreResult<defs::SaveUserJson>;
+
+    /// Saves a new user, failing with [`StoreErrors::AlreadyExists`] if it is already present.
+    async fn register_user(&self, user_json: &defs::UserJson, public_hex: String,
+                           secret_hex: String) -> StoreResult<()>;
+
+    /// Replaces the stored password hash of an existing user.
+    async fn write_user_password(&self, user_hex: &str, password_hash: &str) -> StoreResult<()>;
+
+    /// Replaces the password hash and salt of an existing user, and records when they changed. If
+    /// `revoke_sessions`, the tokens of the user issued at or before `changed` are rejected as
+    /// well, in the same write.
+    async fn write_user_credentials(&self, user_hex: &str, password_hash: &str, salt_hex: &str,
+                                    changed: u64, revoke_sessions: bool) -> StoreResult<()>;
+
+    /// Rejects the tokens of an existing user that were issued at or before `not_before`.
+    async fn write_tokens_not_before(&self, user_hex: &str, not_before: u64) -> StoreResult<()>;
+
+    /// Replaces the contact address of an existing user.
+    async fn write_user_contact(&self, user_hex: &str, contact: Option<&str>, verified: bool) -> StoreResult<()>;
+
+    /// Lists the user hex and secret key of every user.
+    async fn user_secrets(&self) -> StoreResult<Vec<(String, String)>>;
+
+    /// Replaces the secret key of an existing user.
+    async fn write_user_secret(&self, user_hex: &str, secret_hex: &str) -> StoreResult<()>;
+
+    async fn read_user_claims(&self, user_hex: &str) -> StoreResult<defs::Tiauth>;
+
+    /// Replaces all claims of a user. To update them based on what was read, hold the lock from
+    /// [`Store::lock_user_claims`] from before reading until after writing.
+    async fn write_user_claims(&self, user_hex: &str, user_claims: &defs::Tiauth) -> StoreResult<()>;
+
+    /// Replaces the claims of several users at once. Either all of them are written or, if an
+    /// error occurs, none are. The caller must hold the [`Store::lock_user_claims`] lock of every
+    /// user it read the claims of.
+    async fn write_many_user_claims(&self, users_claims: &[(String, defs::Tiauth)]) -> StoreResult<()>;
+
+    async fn read_resources(&self) -> StoreResult<defs::Resources>;
+
+    /// Adds a resource to the index, failing with [`StoreErrors::AlreadyExists`] if it is
+    /// already present.
+    async fn add_resource(&self, resource_id: &str) -> StoreResult<()>;
+
+    /// Removes a resource from the index, if it is present. Only meant to undo [`Store::add_resource`]
+    /// when writing the claims on it fails.
+    async fn remove_resource(&self, resource_id: &str) -> StoreResult<()>;
+
+    /// Reads the server signing keys, which are empty if none were written yet.
+    async fn read_signing_keys(&self) -> StoreResult<Vec<defs::SigningKey>>;
+
+    /// Replaces all server signing keys.
+    async fn write_signing_keys(&self, keys: &[defs::SigningKey]) -> StoreResult<()>;
+
+    /// Reads the stored salt secret as hex, if one was written.
+    async fn read_salt_secret(&self) -> StoreResult<Option<String>>;
+
+    /// Saves the salt secret, failing with [`StoreErrors::AlreadyExists`] if one is stored already.
+    async fn add_salt_secret(&self, secret_hex: &str) -> StoreResult<()>;
+
+    /// Replaces the stored salt secret.
+    async fn write_salt_secret(&self, secret_hex: &str) -> StoreResult<()>;
+
+    /// Reads the failed login counter with key `key`, which is empty if none was written yet.
+    async fn read_login_attempts(&self, key: &str) -> StoreResult<defs::LoginAttempts>;
+
+    /// Replaces the failed login counter with key `key`. To update it based on what was read, hold
+    /// the lock from [`Store::lock_login_attempts`] from before reading until after writing.
+    async fn write_login_attempts(&self, key: &str, attempts: &defs::LoginAttempts) -> StoreResult<()>;
+
+    /// Removes the failed login counter with key `key`, if there is one.
+    async fn remove_login_attempts(&self, key: &str) -> StoreResult<()>;
+
+    /// Removes the failed login counters that are not locked at `now` and have no failures in the
+    /// last `lockout` seconds, returning how many were removed.
+    async fn prune_login_attempts(&self, now: u64, lockout: u64) -> StoreResult<usize>;
+
+    /// Reads the refresh token with hash `token_hash`.
+    async fn read_refresh_token(&self, token_hash: &str) -> StoreResult<defs::RefreshToken>;
+
+    /// Saves a refresh token, replacing the one with the same hash if there is one. To update it
+    /// based on what was read, hold the lock from [`Store::lock_refresh_token`].
+    async fn write_refresh_token(&self, token: &defs::RefreshToken) -> StoreResult<()>;
+
+    /// Removes every refresh token of a family.
+    async fn remove_refresh_family(&self, family_id: &str) -> StoreResult<()>;
+
+    /// Removes the refresh tokens that expired at `now`, returning how many were removed.
+    async fn prune_refresh_tokens(&self, now: u64) -> StoreResult<usize>;
+
+    /// Reads the mailed token with hash `token_hash`.
+    async fn read_mail_token(&self, token_hash: &str) -> StoreResult<defs::MailToken>;
+
+    /// Saves a mailed token, replacing the one with the same hash if there is one.
+    async fn write_mail_token(&self, token: &defs::MailToken) -> StoreResult<()>;
+
+    /// Removes a mailed token, if it exists. To use it only once, hold the lock from
+    /// [`Store::lock_mail_token`] between reading and removing it.
+    async fn remove_mail_token(&self, token_hash: &str) -> StoreResult<()>;
+
+    /// Removes the mailed tokens that expired at `now`, returning how many were removed.
+    async fn prune_mail_tokens(&self, now: u64) -> StoreResult<usize>;
+
+    /// Adds an access token to the revocation list. Revoking it again updates its expiry.
+    async fn revoke_token(&self, token: &defs::RevokedToken) -> StoreResult<()>;
+
+    async fn is_token_revoked(&self, jti: &str) -> StoreResult<bool>;
+
+    /// Reads the revocation list, including entries that expired but were not pruned yet.
+    async fn read_revoked_tokens(&self) -> StoreResult<Vec<defs::RevokedToken>>;
+
+    /// Removes the revoked tokens that expired at `now`, returning how many were removed.
+    async fn prune_revoked_tokens(&self, now: u64) -> StoreResult<usize>;
+
+    fn locks(&self) -> &StoreLocks;
+
+    /// Locks the claims of a user, so that concurrent modifications do not overwrite each other.
+    async fn lock_user_claims(&self, user_hex: &str) -> OwnedMutexGuard<()> {
+        self.locks().user_claims.lock(user_hex).await
+    }
+
+    /// Locks a resource while it is being created or its claims are being modified.
+    async fn lock_resource(&self, resource_id: &str) -> OwnedMutexGuard<()> {
+        self.locks().resources.lock(resource_id).await
+    }
+
+    /// Locks a failed login counter while it is being checked and updated.
+    async fn lock_login_attempts(&self, key: &str) -> OwnedMutexGuard<()> {
+        self.locks().login_attempts.lock(key).await
+    }
+
+    /// Locks a refresh token while it is being used, so that it cannot be used twice at once.
+    async fn lock_refresh_token(&self, token_hash: &str) -> OwnedMutexGuard<()> {
+        self.locks().refresh_tokens.lock(token_hash).await
+    }
+
+    /// Locks a mailed token while it is being used, so that it cannot be used twice at once.
+    async fn lock_mail_token(&self, token_hash: &str) -> OwnedMutexGuard<()> {
+        self.locks().mail_tokens.lock(token_hash).await
+    }
+}
+
+pub type StoreRef = Arc<dyn Store>;
+
+/// Filter that hands a reference to the store to a handler.
+pub fn with_store(store: StoreRef) -> impl Filter<Extract = (StoreRef,), Error = Infallible> + Clone {
+    warp::any().map(move || store.clone())
+}
@@ -0,0 +1,190 @@
+//! Throttling of failed logins, counted per user and per client address.
+//!
+//! After a failed login the next attempt has to wait `login_backoff` seconds, twice as long after
+//! every further failure. Too many failures lock the counter for `login_lockout` seconds. Failures
+//! for unknown users are only counted per address, so that requests for made up users cannot fill
+//! the store with counters. A successful login clears the counter of the user, but not that of the
+//! address. Counters whose failures are forgotten are removed by [`prune_periodically`].
+//!
+//! Password reset requests are throttled the same way with counters of their own, see
+//! [`count_request`], where every request counts as a failure.
+
+use std::net::IpAddr;
+use std::time::Duration;
+
+use tokio::sync::OwnedMutexGuard;
+
+use crate::auth::unix_now;
+use crate::config::{Config, ConfigRef};
+use crate::defs::LoginAttempts;
+use crate::error::{ErrorReject, RejectTypes, RetryAfterReject};
+use crate::reject;
+use crate::store::{store_reject, StoreError, StoreRef, StoreResult};
+
+/// How often [`prune_periodically`] removes counters whose failures are forgotten.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(3600);
+
+pub fn user_key(user_hex: &str) -> String {
+    format!("user:{}", user_hex)
+}
+
+pub fn ip_key(ip: IpAddr) -> String {
+    format!("ip:{}", ip)
+}
+
+pub fn reset_user_key(user_hex: &str) -> String {
+    format!("reset_user:{}", user_hex)
+}
+
+pub fn reset_ip_key(ip: IpAddr) -> String {
+    format!("reset_ip:{}", ip)
+}
+
+/// Whether the failures are old enough to be forgotten.
+fn is_stale(attempts: &LoginAttempts, config: &Config, now: u64) -> bool {
+    attempts.locked_until <= now && attempts.last_failure.saturating_add(config.login_lockout) <= now
+}
+
+/// The time before which no new login is allowed.
+fn blocked_until(attempts: &LoginAttempts, config: &Config) -> u64 {
+    if attempts.failures == 0 {
+        return attempts.locked_until
+    }
+    let backoff = config.login_backoff
+        .saturating_mul(2u64.saturating_pow(attempts.failures - 1))
+        .min(config.login_lockout);
+
+    attempts.locked_until.max(attempts.last_failure.saturating_add(backoff))
+}
+
+async fn count_failure(store: &StoreRef, config: &Config, key: &str, max_failures: u32, now: u64) -> StoreResult<()> {
+    let mut attempts = store.read_login_attempts(key).await?;
+    if is_stale(&attempts, config, now) {
+        attempts = LoginAttempts::default();
+    }
+    attempts.failures = attempts.failures.saturating_add(1);
+    attempts.last_failure = now;
+    if attempts.failures >= max_failures {
+        attempts.locked_until = now.saturating_add(config.login_lockout);
+    }
+
+    store.write_login_attempts(key, &attempts).await
+}
+
+fn attempts_reject(e: StoreError) -> warp::Rejection {
+    reject(store_reject(e, "Error updating login attempts (login user)",
+                        "Error updating login attempts (login user)"))
+}
+
+/// Locks the counter of the user for the whole login, so that parallel attempts cannot all pass
+/// [`check`] before the first failure is counted.
+pub async fn lock_user(store: &StoreRef, user_hex: &str) -> OwnedMutexGuard<()> {
+    store.lock_login_attempts(&user_key(user_hex)).await
+}
+
+fn too_many_requests(msg: &'static str, until: u64, now: u64) -> warp::Rejection {
+    let retry_after = until - now;
+    reject(RetryAfterReject {
+        reject: ErrorReject { rt: RejectTypes::TooManyRequests,
+            msg,
+            e: format!("@@@retry after {} seconds@@@", retry_after) },
+        retry_after })
+}
+
+/// Rejects the login with a 429 if the user or the address has to wait.
+pub async fn check(store: &StoreRef, config: &Config, user_hex: &str, ip: Option<IpAddr>,
+                   now: u64) -> Result<(), warp::Rejection> {
+    let mut counters = vec![(user_key(user_hex), config.login_max_failures)];
+    if let Some(ip) = ip {
+        counters.push((ip_key(ip), config.login_max_failures_ip));
+    }
+
+    for (key, _) in counters.iter().filter(|(_, max_failures)| *max_failures > 0) {
+        let attempts = store.read_login_attempts(key).await.map_err(attempts_reject)?;
+        let until = blocked_until(&attempts, config);
+        if until > now {
+            return Err(too_many_requests("Too many failed logins, try again later!", until, now))
+        }
+    }
+
+    Ok(())
+}
+
+/// Counts a request against the counter `key` unless it has to wait, in which case it returns
+/// until when. Waiting requests are not counted, so that they do not extend the wait.
+pub async fn count_request(store: &StoreRef, config: &Config, key: &str, max_requests: u32,
+                           now: u64) -> StoreResult<Option<u64>> {
+    if max_requests == 0 {
+        return Ok(None)
+    }
+    let _guard = store.lock_login_attempts(key).await;
+    let until = blocked_until(&store.read_login_attempts(key).await?, config);
+    if until > now {
+        return Ok(Some(until))
+    }
+    count_failure(store, config, key, max_requests, now).await?;
+
+    Ok(None)
+}
+
+/// Rejects the reset request with a 429 if the address has to wait, and counts it otherwise.
+pub async fn check_reset_ip(store: &StoreRef, config: &Config, ip: Option<IpAddr>,
+                            now: u64) -> Result<(), warp::Rejection> {
+    let ip = match ip {
+        Some(ip) => ip,
+        None => return Ok(())
+    };
+    match count_request(store, config, &reset_ip_key(ip), config.login_max_failures_ip, now).await {
+        Ok(None) => Ok(()),
+        Ok(Some(until)) => Err(too_many_requests("Too many password reset requests, try again later!", until, now)),
+        Err(e) => Err(reject(store_reject(e, "Error updating reset attempts (request reset)",
+                                          "Error updating reset attempts (request reset)")))
+    }
+}
+
+/// Counts a failed login, for the user only if it exists. The caller holds the [`lock_user`] lock.
+pub async fn record_failure(store: &StoreRef, config: &Config, user_hex: &str, user_exists: bool,
+                            ip: Option<IpAddr>, now: u64) -> Result<(), warp::Rejection> {
+    if user_exists && config.login_max_failures > 0 {
+        count_failure(store, config, &user_key(user_hex), config.login_max_failures, now).await
+            .map_err(attempts_reject)?;
+    }
+    if let (Some(ip), true) = (ip, config.login_max_failures_ip > 0) {
+        let key = ip_key(ip);
+        let _guard = store.lock_login_attempts(&key).await;
+        count_failure(store, config, &key, config.login_max_failures_ip, now).await
+            .map_err(attempts_reject)?;
+    }
+
+    Ok(())
+}
+
+/// Clears the counter of the user after a successful login. The caller holds the [`lock_user`]
+/// lock.
+pub async fn record_success(store: &StoreRef, user_hex: &str) -> Result<(), warp::Rejection> {
+    store.remove_login_attempts(&user_key(user_hex)).await.map_err(attempts_reject)
+}
+
+/// Lifts the lockout of a counter, see [`user_key`] and [`ip_key`].
+pub async fn unlock(store: &StoreRef, key: &str) -> StoreResult<()> {
+    let _guard = store.lock_login_attempts(key).await;
+
+    store.remove_login_attempts(key).await
+}
+
+/// Removes the counters whose failures are forgotten every hour, never returns.
+pub async fn prune_periodically(store: StoreRef, config: ConfigRef) {
+    let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+    loop {
+        interval.tick().await;
+        let now = match unix_now() {
+            Ok(now) => now,
+            Err(_) => continue
+        };
+        match store.prune_login_attempts(now, config.login_lockout).await {
+            Ok(0) => {},
+            Ok(pruned) => log::info!("Removed {} failed login counters.", pruned),
+            Err(e) => log::error!("Failed to remove failed login counters: {}", e)
+        }
+    }
+}
@@ -0,0 +1,89 @@
+use std::path::{Path, PathBuf};
+
+use tiauth::defs::{Tiauth, UserClaim, UserJson};
+use tiauth::files::FileStore;
+use tiauth::store::Store;
+
+const USER: &str = "atomic_user";
+
+fn claims(anphd_ids: &[&str]) -> Tiauth {
+    Tiauth {
+        claims: anphd_ids.iter().map(|anphd_id| UserClaim {
+            origin: USER.to_owned(),
+            anphd_id: anphd_id.to_string(),
+            uuid: format!("uuid-{}", anphd_id),
+            permission: 0
+        }).collect()
+    }
+}
+
+/// Every file below `dir` that is a temporary file of a write.
+fn temp_files(dir: &Path) -> Vec<PathBuf> {
+    let mut found = Vec::new();
+    for entry in std::fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            found.extend(temp_files(&path));
+        }
+        else if path.to_string_lossy().ends_with(".tmp") {
+            found.push(path);
+        }
+    }
+
+    found
+}
+
+async fn store_with_user(dir: &Path) -> FileStore {
+    let store = FileStore::new(dir);
+    store.prepare().await.unwrap();
+    let user_json: UserJson = serde_json::from_value(serde_json::json!({
+        "user_hex": USER,
+        "password_hash_hex": "hash",
+        "salt_hex": "0".repeat(32)
+    })).unwrap();
+    store.register_user(&user_json, "ab".repeat(32), "secret".to_owned()).await.unwrap();
+
+    store
+}
+
+#[tokio::test]
+async fn writes_replace_files_without_leaving_temp_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = store_with_user(dir.path()).await;
+
+    store.write_user_claims(USER, &claims(&["a"])).await.unwrap();
+    store.write_user_claims(USER, &claims(&["a", "b"])).await.unwrap();
+    store.add_resource("atomic_user:a").await.unwrap();
+    store.write_user_password(USER, "new hash").await.unwrap();
+
+    assert_eq!(store.read_user_claims(USER).await.unwrap().claims.len(), 2);
+    assert_eq!(store.read_user(USER, true).await.unwrap().password_hash_hex, "new hash");
+    assert!(temp_files(dir.path()).is_empty());
+}
+
+#[tokio::test]
+async fn failed_rename_keeps_file_and_removes_temp_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = store_with_user(dir.path()).await;
+    // A non-empty directory in place of the claims file cannot be replaced by a rename.
+    let claims_path = dir.path().join("claims").join(format!("{}.json", USER));
+    std::fs::create_dir_all(claims_path.join("blocker")).unwrap();
+
+    assert!(store.write_user_claims(USER, &claims(&["a"])).await.is_err());
+    assert!(claims_path.join("blocker").is_dir());
+    assert!(temp_files(dir.path()).is_empty());
+}
+
+#[tokio::test]
+async fn interrupted_write_is_detected_at_startup() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = store_with_user(dir.path()).await;
+    // What a crash halfway through writing the user file leaves behind.
+    let leftover = dir.path().join("users").join(format!("{}.json.1234.0.tmp", USER));
+    std::fs::write(&leftover, "{\"user_hex\": \"atom").unwrap();
+
+    let store_after_restart = FileStore::new(dir.path());
+    store_after_restart.prepare().await.unwrap();
+    assert_eq!(store_after_restart.leftover_temp_files().await.unwrap(), vec![leftover]);
+    assert_eq!(store.read_user(USER, false).await.unwrap().user_hex, USER);
+}
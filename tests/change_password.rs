@@ -0,0 +1,176 @@
+mod common;
+
+use std::time::Duration;
+
+use serde_json::{json, Value};
+use warp::http::StatusCode;
+
+use tiauth::config::Config;
+
+const USER: &str = "change_password_user";
+const NEW_PASSWORD_HASH_HEX: &str = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+const NEW_SALT_HEX: &str = "0123456789abcdef0123456789abcdef";
+
+fn change(credential: Value, revoke_sessions: bool) -> Value {
+    let mut body = json!({
+        "user_hex": USER,
+        "new_password_hash_hex": NEW_PASSWORD_HASH_HEX,
+        "new_salt_hex": NEW_SALT_HEX,
+        "revoke_sessions": revoke_sessions
+    });
+    body.as_object_mut().unwrap().extend(credential.as_object().unwrap().clone());
+
+    body
+}
+
+/// Without backoff, so a wrong password does not throttle the next attempt.
+async fn unthrottled_store() -> common::TestStore {
+    common::file_store_with(Config { login_backoff: 0, ..common::test_config() }).await
+}
+
+async fn login_with(store: &common::TestStore, password_hash_hex: &str) -> (StatusCode, Value) {
+    common::post(store, "/login", &json!({ "user_hex": USER, "password_hash_hex": password_hash_hex })).await
+}
+
+#[tokio::test]
+async fn current_password_changes_password() {
+    let store = unthrottled_store().await;
+    common::register(&store, USER).await;
+    let before = common::unix_now();
+
+    let (status, _) = common::post(&store, "/change_password",
+                                   &change(json!({ "password_hash_hex": common::PASSWORD_HASH_HEX }), false)).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let save_user = store.read_user(USER, false).await.unwrap();
+    assert_eq!(save_user.salt_hex, NEW_SALT_HEX);
+    assert!(save_user.password_changed.unwrap() >= before);
+    assert_eq!(save_user.tokens_not_before, None);
+    let (status, _) = login_with(&store, common::PASSWORD_HASH_HEX).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _) = login_with(&store, NEW_PASSWORD_HASH_HEX).await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn token_changes_password() {
+    let store = common::file_store().await;
+    common::register(&store, USER).await;
+    let jwt = common::login(&store, USER).await;
+
+    let (status, _) = common::post(&store, "/change_password", &change(json!({ "jwt": jwt }), false)).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = login_with(&store, NEW_PASSWORD_HASH_HEX).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = common::new_claim(&store, USER, &jwt, "still_valid").await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn change_requires_valid_credential() {
+    let store = unthrottled_store().await;
+    common::register(&store, USER).await;
+
+    let (status, _) = common::post(&store, "/change_password", &change(json!({}), false)).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _) = common::post(&store, "/change_password",
+                                   &change(json!({ "password_hash_hex": "00".repeat(32) }), false)).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _) = common::post(&store, "/change_password", &change(json!({ "jwt": "not a jwt" }), false)).await;
+    assert_ne!(status, StatusCode::OK);
+
+    assert_eq!(store.read_user(USER, false).await.unwrap().salt_hex, common::SALT_HEX);
+    let (status, _) = login_with(&store, common::PASSWORD_HASH_HEX).await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn revoke_sessions_rejects_earlier_tokens() {
+    let store = common::file_store().await;
+    common::register(&store, USER).await;
+    let (_, tokens) = login_with(&store, common::PASSWORD_HASH_HEX).await;
+    let jwt = tokens["jwt"].as_str().unwrap();
+
+    let (status, _) = common::post(&store, "/change_password",
+                                   &change(json!({ "password_hash_hex": common::PASSWORD_HASH_HEX }), true)).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (status, body) = common::new_claim(&store, USER, jwt, "revoked").await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert!(body["message"].as_str().unwrap().contains("revoked"), "{}", body);
+    let (status, _) = common::post(&store, "/token/refresh", &json!({ "refresh_token": tokens["refresh_token"] })).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let (_, tokens) = login_with(&store, NEW_PASSWORD_HASH_HEX).await;
+    let (status, _) = common::new_claim(&store, USER, tokens["jwt"].as_str().unwrap(), "new_session").await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+fn issued_at(jwt: &str) -> u64 {
+    let (_, payload, _) = tiauth::jwt::split(jwt).unwrap();
+    let claims: Value = serde_json::from_slice(&base64_url::decode(payload).unwrap()).unwrap();
+
+    claims["iat"].as_u64().unwrap()
+}
+
+#[tokio::test]
+async fn revoke_sessions_rejects_tokens_of_the_same_second() {
+    let store = common::file_store().await;
+    common::register(&store, USER).await;
+    // Start at the beginning of a second, so that everything below happens within it.
+    let into_second = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().subsec_nanos();
+    tokio::time::sleep(Duration::from_nanos(u64::from(1_000_000_000 - into_second))).await;
+
+    let (_, tokens) = login_with(&store, common::PASSWORD_HASH_HEX).await;
+    let jwt = tokens["jwt"].as_str().unwrap();
+    let (status, _) = common::post(&store, "/change_password",
+                                   &change(json!({ "password_hash_hex": common::PASSWORD_HASH_HEX }), true)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(store.read_user(USER, false).await.unwrap().tokens_not_before, Some(issued_at(jwt)));
+
+    let (status, _) = common::new_claim(&store, USER, jwt, "revoked").await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = common::post(&store, "/token/refresh", &json!({ "refresh_token": tokens["refresh_token"] })).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    // Tokens issued after the change in the same second are not affected.
+    let (_, tokens) = login_with(&store, NEW_PASSWORD_HASH_HEX).await;
+    let (status, _) = common::new_claim(&store, USER, tokens["jwt"].as_str().unwrap(), "new_session").await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = common::post(&store, "/token/refresh", &json!({ "refresh_token": tokens["refresh_token"] })).await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn invalid_salt_is_rejected() {
+    let store = unthrottled_store().await;
+    common::register(&store, USER).await;
+
+    for new_salt_hex in &["00", "not hex, but 32 characters long!", ""] {
+        let mut body = change(json!({ "password_hash_hex": common::PASSWORD_HASH_HEX }), true);
+        body["new_salt_hex"] = json!(new_salt_hex);
+        let (status, _) = common::post(&store, "/change_password", &body).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{}", new_salt_hex);
+    }
+
+    let save_user = store.read_user(USER, false).await.unwrap();
+    assert_eq!(save_user.salt_hex, common::SALT_HEX);
+    assert_eq!(save_user.password_changed, None);
+    assert_eq!(save_user.tokens_not_before, None);
+}
+
+#[tokio::test]
+async fn credentials_and_revocation_are_written_together() {
+    for store in [common::file_store().await, common::sqlite_store().await] {
+        common::register(&store, USER).await;
+
+        store.write_user_credentials(USER, "hash", NEW_SALT_HEX, 100, false).await.unwrap();
+        let save_user = store.read_user(USER, false).await.unwrap();
+        assert_eq!((save_user.password_changed, save_user.tokens_not_before), (Some(100), None));
+
+        store.write_user_credentials(USER, "hash", NEW_SALT_HEX, 200, true).await.unwrap();
+        let save_user = store.read_user(USER, false).await.unwrap();
+        assert_eq!((save_user.password_changed, save_user.tokens_not_before), (Some(200), Some(200)));
+        assert_eq!(save_user.salt_hex, NEW_SALT_HEX);
+    }
+}
@@ -0,0 +1,227 @@
+#![allow(dead_code)]
+
+use std::ops::Deref;
+use std::sync::Arc;
+
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use tempfile::TempDir;
+use warp::http::StatusCode;
+
+use tiauth::config::{Config, ConfigRef};
+use tiauth::db::SqliteStore;
+use tiauth::files::FileStore;
+use tiauth::jwt::{self, JwtHeader};
+use tiauth::keys::{Keyring, KeyringRef};
+use tiauth::salt::{SaltSecret, SaltSecretRef};
+use tiauth::admin::{AdminRef, AdminToken};
+use tiauth::clients::{Client, Clients, ClientsRef};
+use tiauth::mail::{FileSender, Mail, MailerRef};
+use tiauth::store::StoreRef;
+
+pub const PASSWORD_HASH_HEX: &str = "4c2490ff0f247e20b7ac5925829eb99e0d201ae43ee12c67d58248a291f32bdd";
+pub const SALT_HEX: &str = "74c9e15a6b5c71b10cadbbd594f0f5b1";
+pub const ADMIN_TOKEN: &str = "test-admin-token-0123456789abcdef";
+pub const CLIENT_ID: &str = "test-client";
+/// Its SHA-256 hash is in the clients the routes are served with.
+pub const CLIENT_SECRET: &str = "test-client-secret";
+
+/// A file or SQLite store in a temporary directory, which is removed when this is dropped, together with
+/// the config, keyring, salt secret, admin token, clients and mail sender the routes are served
+/// with. Mails are written to the `mail` directory in it.
+pub struct TestStore {
+    pub store: StoreRef,
+    pub config: ConfigRef,
+    pub keyring: KeyringRef,
+    pub salt_secret: SaltSecretRef,
+    pub admin: AdminRef,
+    pub clients: ClientsRef,
+    pub mailer: MailerRef,
+    _dir: TempDir,
+}
+
+impl Deref for TestStore {
+    type Target = StoreRef;
+
+    fn deref(&self) -> &StoreRef {
+        &self.store
+    }
+}
+
+/// The default config with cheap Argon2 parameters, to keep the tests fast, and an issuer of its
+/// own, so that tokens of the tests have to take it from the config.
+pub fn test_config() -> Config {
+    Config {
+        issuer: "tiauth.test".to_owned(),
+        argon2_memory_kib: 1024,
+        argon2_iterations: 1,
+        ..Config::default()
+    }
+}
+
+pub async fn file_store() -> TestStore {
+    file_store_with(test_config()).await
+}
+
+pub async fn file_store_with(config: Config) -> TestStore {
+    let dir = tempfile::tempdir().unwrap();
+    let store: StoreRef = Arc::new(FileStore::new(dir.path()));
+
+    test_store(store, config, dir).await
+}
+
+pub async fn sqlite_store() -> TestStore {
+    sqlite_store_with(test_config()).await
+}
+
+/// A SQLite database in a temporary directory, migrated to the latest schema.
+pub async fn sqlite_store_with(config: Config) -> TestStore {
+    let dir = tempfile::tempdir().unwrap();
+    let url = format!("sqlite://{}", dir.path().join("test.sqlite").display());
+    let store: StoreRef = Arc::new(SqliteStore::connect(&url).await.unwrap());
+
+    test_store(store, config, dir).await
+}
+
+async fn test_store(store: StoreRef, config: Config, dir: TempDir) -> TestStore {
+    store.prepare().await.unwrap();
+    let keyring = Keyring::load(&store).await.unwrap();
+    keyring.rotate_if_due(&store, &config, unix_now()).await.unwrap();
+
+    TestStore {
+        store,
+        config: Arc::new(config),
+        keyring: Arc::new(keyring),
+        salt_secret: Arc::new(SaltSecret::random()),
+        admin: Arc::new(Some(AdminToken::new(ADMIN_TOKEN).unwrap())),
+        clients: Arc::new(Clients {
+            clients: vec![Client {
+                client_id: CLIENT_ID.to_owned(),
+                secret_sha256: hex::encode(Sha256::digest(CLIENT_SECRET.as_bytes()))
+            }]
+        }),
+        mailer: Arc::new(FileSender::new(&dir.path().join("mail"))),
+        _dir: dir
+    }
+}
+
+impl TestStore {
+    /// The temporary directory, which holds the files of a file store.
+    pub fn dir(&self) -> &std::path::Path {
+        self._dir.path()
+    }
+
+    /// Waits until at least `count` mails were sent, some are sent after replying, and returns
+    /// them.
+    pub async fn mails(&self, count: usize) -> Vec<Mail> {
+        for _ in 0..100 {
+            let mails = FileSender::read_mails(&self._dir.path().join("mail")).unwrap();
+            if mails.len() >= count {
+                return mails
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        panic!("expected {} mails", count);
+    }
+}
+
+/// The token in a mail, which is on a line of its own.
+pub fn mail_token(mail: &Mail) -> String {
+    mail.body.lines()
+        .find(|line| line.len() == 43 && !line.contains(' '))
+        .unwrap().to_owned()
+}
+
+/// The server routes on top of `store`.
+pub fn routes(store: &TestStore) -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    tiauth::routes(store.config.clone(), store.store.clone(), store.keyring.clone(), store.salt_secret.clone(),
+                   store.admin.clone(), store.clients.clone(), Some(store.mailer.clone()))
+}
+
+pub async fn post(store: &TestStore, path: &str, body: &Value) -> (StatusCode, Value) {
+    let res = warp::test::request()
+        .method("POST")
+        .path(path)
+        .json(body)
+        .reply(&routes(store)).await;
+    let value = serde_json::from_slice(res.body()).unwrap_or(Value::Null);
+
+    (res.status(), value)
+}
+
+pub async fn get(store: &TestStore, path: &str) -> (StatusCode, Value) {
+    let res = warp::test::request()
+        .method("GET")
+        .path(path)
+        .reply(&routes(store)).await;
+    let value = serde_json::from_slice(res.body()).unwrap_or(Value::Null);
+
+    (res.status(), value)
+}
+
+pub async fn register(store: &TestStore, user_hex: &str) {
+    let (status, _) = post(store, "/register", &json!({
+        "user_hex": user_hex,
+        "password_hash_hex": PASSWORD_HASH_HEX,
+        "salt_hex": SALT_HEX
+    })).await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+/// Logs in and returns the JWT.
+pub async fn login(store: &TestStore, user_hex: &str) -> String {
+    let (status, body) = post(store, "/login", &json!({
+        "user_hex": user_hex,
+        "password_hash_hex": PASSWORD_HASH_HEX
+    })).await;
+    assert_eq!(status, StatusCode::OK);
+
+    body["jwt"].as_str().unwrap().to_owned()
+}
+
+pub async fn new_claim(store: &TestStore, owner: &str, jwt: &str, anphd_id: &str) -> (StatusCode, Value) {
+    post(store, "/new_claim", &json!({
+        "origin": owner,
+        "anphd_id": anphd_id,
+        "uuid": format!("uuid-{}", anphd_id),
+        "writer_permission": 0,
+        "target_user_hex": owner,
+        "target_permission": 0,
+        "jwt": jwt
+    })).await
+}
+
+pub async fn modify_claims(store: &TestStore, writer: &str, jwt: &str, anphd_id: &str,
+                           targets: &[(&str, u16)]) -> (StatusCode, Value) {
+    let targets: Vec<Value> = targets.iter().map(|(target_user_hex, target_permission)| json!({
+        "target_user_hex": target_user_hex,
+        "target_permission": target_permission
+    })).collect();
+
+    post(store, "/modify_claims", &json!({
+        "origin": writer,
+        "anphd_id": anphd_id,
+        "writer": writer,
+        "uuid": format!("uuid-{}", anphd_id),
+        "targets": targets,
+        "jwt": jwt
+    })).await
+}
+
+pub async fn user_keypair(store: &TestStore, user_hex: &str) -> ed25519_dalek::Keypair {
+    let save_user = store.read_user(user_hex, true).await.unwrap();
+    let mut keypair_bytes = hex::decode(&save_user.secret_hex).unwrap();
+    keypair_bytes.extend(hex::decode(&save_user.public_hex).unwrap());
+
+    ed25519_dalek::Keypair::from_bytes(&keypair_bytes).unwrap()
+}
+
+/// Signs `payload` with the active server key, like the server does on login.
+pub fn sign_jwt(store: &TestStore, payload: &Value) -> String {
+    let (kid, keypair) = store.keyring.signing_key().unwrap();
+    jwt::sign(&keypair, &JwtHeader::eddsa(kid), payload).unwrap()
+}
+
+pub fn unix_now() -> u64 {
+    std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH).unwrap().as_secs()
+}
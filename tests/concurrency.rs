@@ -0,0 +1,80 @@
+mod common;
+
+use futures::future::join_all;
+use warp::http::StatusCode;
+
+const N: usize = 16;
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn parallel_new_claims_are_all_kept() {
+    let test_store = common::file_store().await;
+    let store = &test_store;
+    common::register(store, "owner").await;
+    let jwt = common::login(store, "owner").await;
+
+    let results = join_all((0..N).map(|i| {
+        let anphd_id = format!("res{}", i);
+        let jwt = jwt.clone();
+        async move { common::new_claim(store, "owner", &jwt, &anphd_id).await }
+    })).await;
+    for (status, body) in results {
+        assert_eq!(status, StatusCode::OK, "{}", body);
+    }
+
+    let claims = store.read_user_claims("owner").await.unwrap();
+    assert_eq!(claims.claims.len(), N);
+    let resources = store.read_resources().await.unwrap();
+    assert_eq!(resources.resources.len(), N);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn parallel_claim_modifications_are_all_kept() {
+    let test_store = common::file_store().await;
+    let store = &test_store;
+    common::register(store, "owner").await;
+    common::register(store, "target").await;
+    let jwt = common::login(store, "owner").await;
+    for i in 0..N {
+        let (status, body) = common::new_claim(store, "owner", &jwt, &format!("res{}", i)).await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+    }
+
+    let results = join_all((0..N).map(|i| {
+        let anphd_id = format!("res{}", i);
+        let jwt = jwt.clone();
+        async move { common::modify_claims(store, "owner", &jwt, &anphd_id, &[("target", 4500)]).await }
+    })).await;
+    for (status, body) in results {
+        assert_eq!(status, StatusCode::OK, "{}", body);
+    }
+
+    let claims = store.read_user_claims("target").await.unwrap();
+    assert_eq!(claims.claims.len(), N);
+    assert!(claims.claims.iter().all(|claim| claim.permission == 4500));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn parallel_user_updates_are_all_kept() {
+    let test_store = common::file_store().await;
+    let store = &test_store;
+    common::register(store, "user").await;
+
+    for round in 0..N as u64 {
+        let contact = format!("user{}@example.com", round);
+        let secret = format!("secret{}", round);
+        join_all(vec![
+            store.write_user_password("user", "hash"),
+            store.write_user_credentials("user", "hash", common::SALT_HEX, round, false),
+            store.write_tokens_not_before("user", round),
+            store.write_user_contact("user", Some(&contact), true),
+            store.write_user_secret("user", &secret),
+        ]).await.into_iter().collect::<Result<Vec<_>, _>>().unwrap();
+
+        let save_user = store.read_user("user", true).await.unwrap();
+        assert_eq!(save_user.password_hash_hex, "hash");
+        assert_eq!(save_user.password_changed, Some(round));
+        assert_eq!(save_user.tokens_not_before, Some(round));
+        assert_eq!(save_user.contact.as_deref(), Some(contact.as_str()));
+        assert_eq!(save_user.secret_hex, secret);
+    }
+}
@@ -0,0 +1,96 @@
+mod common;
+
+use serde_json::{json, Value};
+use warp::http::StatusCode;
+
+const USER: &str = "introspect_user";
+
+async fn introspect(store: &common::TestStore, credentials: Option<(&str, &str)>, token: &str) -> (StatusCode, Value) {
+    let mut request = warp::test::request()
+        .method("POST")
+        .path("/introspect")
+        .header("content-type", "application/x-www-form-urlencoded")
+        .body(serde_urlencoded::to_string(&[("token", token), ("token_type_hint", "access_token")]).unwrap());
+    if let Some((client_id, secret)) = credentials {
+        let encoded = base64::encode(format!("{}:{}", client_id, secret));
+        request = request.header("authorization", format!("Basic {}", encoded));
+    }
+    let res = request.reply(&common::routes(store)).await;
+
+    (res.status(), serde_json::from_slice(res.body()).unwrap_or(Value::Null))
+}
+
+async fn client_introspect(store: &common::TestStore, token: &str) -> Value {
+    let (status, body) = introspect(store, Some((common::CLIENT_ID, common::CLIENT_SECRET)), token).await;
+    assert_eq!(status, StatusCode::OK);
+
+    body
+}
+
+#[tokio::test]
+async fn active_token_returns_claims() {
+    let store = common::file_store().await;
+    common::register(&store, USER).await;
+    let jwt = common::login(&store, USER).await;
+
+    let body = client_introspect(&store, &jwt).await;
+    assert_eq!(body["active"], true);
+    assert_eq!(body["sub"], USER);
+    assert_eq!(body["iss"], store.config.issuer.as_str());
+    assert!(body["exp"].as_u64().unwrap() > common::unix_now());
+    assert_eq!(body["tipten_auth"], json!({ "claims": [] }));
+}
+
+#[tokio::test]
+async fn invalid_tokens_are_inactive() {
+    let store = common::file_store().await;
+    common::register(&store, USER).await;
+    let jwt = common::login(&store, USER).await;
+    let now = common::unix_now();
+    let expired = common::sign_jwt(&store, &json!({
+        "iss": store.config.issuer, "iat": now - 7200, "nbf": now - 7200, "exp": now - 3600,
+        "jti": "expired", "sub": USER, "tipten_auth": {"claims": []}
+    }));
+    let mut tampered = jwt.clone();
+    tampered.push('A');
+
+    for token in &[expired.as_str(), tampered.as_str(), "not a jwt"] {
+        assert_eq!(client_introspect(&store, token).await, json!({ "active": false }), "{}", token);
+    }
+
+    let (status, _) = common::post(&store, "/logout", &json!({ "user_hex": USER, "jwt": jwt })).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(client_introspect(&store, &jwt).await, json!({ "active": false }));
+}
+
+#[tokio::test]
+async fn introspection_requires_client_credentials() {
+    let store = common::file_store().await;
+    common::register(&store, USER).await;
+    let jwt = common::login(&store, USER).await;
+
+    let (status, _) = introspect(&store, None, &jwt).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = introspect(&store, Some((common::CLIENT_ID, "wrong-secret")), &jwt).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = introspect(&store, Some(("other-client", common::CLIENT_SECRET)), &jwt).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn tokens_rejected_by_the_api_are_inactive() {
+    let store = common::file_store().await;
+    common::register(&store, USER).await;
+    common::register(&store, "removed_user").await;
+    let ended = common::login(&store, USER).await;
+    let removed = common::login(&store, "removed_user").await;
+
+    store.write_tokens_not_before(USER, common::unix_now() + 1).await.unwrap();
+    std::fs::remove_file(store.dir().join("users/removed_user.json")).unwrap();
+
+    for (user_hex, token) in &[(USER, ended.as_str()), ("removed_user", removed.as_str())] {
+        assert_eq!(client_introspect(&store, token).await, json!({ "active": false }), "{}", user_hex);
+        let (status, _) = common::new_claim(&store, user_hex, token, "rejected").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED, "{}", user_hex);
+    }
+}
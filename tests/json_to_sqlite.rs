@@ -0,0 +1,195 @@
+use std::path::Path;
+use std::process::{Command, Output};
+
+use serde_json::{json, Value};
+
+use tiauth::db::{self, SqliteStore};
+use tiauth::defs;
+use tiauth::files::FileStore;
+use tiauth::store::Store;
+
+const USERS: [&str; 3] = ["alice", "bob", "carol"];
+
+fn user(user_hex: &str, salt_hex: &str) -> Value {
+    json!({
+        "user_hex": user_hex,
+        "password_hash_hex": format!("hash-{}", user_hex),
+        "salt_hex": salt_hex,
+        "secret_hex": format!("secret-{}", user_hex),
+        "public_hex": "ab".repeat(32)
+    })
+}
+
+fn write(path: &Path, value: &Value) {
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    std::fs::write(path, serde_json::to_vec(value).unwrap()).unwrap();
+}
+
+/// A resources dir with valid users, claims and resources, and users and claims that are rejected.
+fn populate(dir: &Path) {
+    for user_hex in USERS.iter() {
+        write(&dir.join("users").join(format!("{}.json", user_hex)), &user(user_hex, &"0".repeat(32)));
+        write(&dir.join("claims").join(format!("{}.json", user_hex)), &json!({
+            "claims": [
+                { "origin": "alice", "anphd_id": "res", "uuid": "uuid-res", "permission": 3500 },
+                { "origin": user_hex, "anphd_id": "own", "uuid": format!("uuid-{}", user_hex), "permission": 1000 }
+            ]
+        }));
+    }
+    write(&dir.join("users/short_salt.json"), &user("short_salt", "00"));
+    write(&dir.join("users/bad@user.json"), &user("bad@user", &"0".repeat(32)));
+    write(&dir.join("claims/missing.json"), &json!({ "claims": [] }));
+    write(&dir.join("resources.json"), &json!({ "resources": ["alice:res", "alice:own", "bob:own", "carol:own"] }));
+}
+
+fn import(dir: &Path, url: &str) -> Output {
+    let output = Command::new(env!("CARGO_BIN_EXE_json_to_sqlite"))
+        .arg(dir)
+        .arg(url)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    output
+}
+
+async fn count(sqlite: &SqliteStore, table: &str) -> i64 {
+    sqlx::query_scalar(&format!("SELECT COUNT(*) FROM {}", table))
+        .fetch_one(sqlite.pool()).await.unwrap()
+}
+
+/// Every row in SQLite equals the record in the resources dir.
+async fn assert_imported(files: &FileStore, sqlite: &SqliteStore, user_hexes: &[&str]) {
+    for user_hex in user_hexes {
+        let file_user = serde_json::to_value(files.read_user(user_hex, true).await.unwrap()).unwrap();
+        let sqlite_user = serde_json::to_value(sqlite.read_user(user_hex, true).await.unwrap()).unwrap();
+        assert_eq!(file_user, sqlite_user);
+        let file_claims = serde_json::to_value(files.read_user_claims(user_hex).await.unwrap()).unwrap();
+        let sqlite_claims = serde_json::to_value(sqlite.read_user_claims(user_hex).await.unwrap()).unwrap();
+        assert_eq!(file_claims, sqlite_claims);
+    }
+    assert_eq!(files.read_resources().await.unwrap().resources, sqlite.read_resources().await.unwrap().resources);
+}
+
+#[tokio::test]
+async fn imports_every_valid_row() {
+    let dir = tempfile::tempdir().unwrap();
+    let resources = dir.path().join("resources");
+    populate(&resources);
+    let url = format!("sqlite://{}", dir.path().join("import.sqlite").display());
+
+    let output = import(&resources, &url);
+    let report = String::from_utf8_lossy(&output.stdout);
+    assert!(report.contains("Imported 3 users, 6 claims and 4 resources."), "{}", report);
+    assert!(report.contains("user short_salt: salt_hex must have length 32"), "{}", report);
+    assert!(report.contains("user bad@user: user hex may only contain"), "{}", report);
+    assert!(report.contains("claims missing: no imported user"), "{}", report);
+
+    let files = FileStore::new(&resources);
+    let sqlite = SqliteStore::connect(&url).await.unwrap();
+    assert_imported(&files, &sqlite, &USERS).await;
+    assert_eq!(count(&sqlite, "user_auth").await, 3);
+    assert_eq!(count(&sqlite, "user_claims").await, 6);
+}
+
+#[tokio::test]
+async fn database_error_aborts_import() {
+    let dir = tempfile::tempdir().unwrap();
+    let resources = dir.path().join("resources");
+    populate(&resources);
+    let url = format!("sqlite://{}", dir.path().join("import.sqlite").display());
+    let sqlite = SqliteStore::connect(&url).await.unwrap();
+    sqlite.prepare().await.unwrap();
+    // Not a constraint of the schema, so it is not a rejected row but ends the import.
+    sqlx::query("CREATE TRIGGER fail_claims BEFORE INSERT ON user_claims BEGIN SELECT RAISE(ABORT, 'failing'); END")
+        .execute(sqlite.pool()).await.unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_json_to_sqlite"))
+        .arg(&resources)
+        .arg(&url)
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("nothing was written"));
+    assert_eq!(count(&sqlite, "user_auth").await, 0);
+}
+
+#[tokio::test]
+async fn second_run_and_conflicts_keep_existing_rows() {
+    let dir = tempfile::tempdir().unwrap();
+    let resources = dir.path().join("resources");
+    populate(&resources);
+    let url = format!("sqlite://{}", dir.path().join("import.sqlite").display());
+    let files = FileStore::new(&resources);
+
+    // A user that is already in the database with other data.
+    let sqlite = SqliteStore::connect(&url).await.unwrap();
+    sqlite.prepare().await.unwrap();
+    let mut conflicting: tiauth::defs::SaveUserJson = serde_json::from_value(user("carol", &"1".repeat(32))).unwrap();
+    conflicting.password_hash_hex = "existing".to_owned();
+    db::insert_user(&mut sqlite.pool().acquire().await.unwrap(), &conflicting).await.unwrap();
+
+    let report = String::from_utf8_lossy(&import(&resources, &url).stdout).into_owned();
+    assert!(report.contains("Imported 2 users, 4 claims and 4 resources."), "{}", report);
+    assert!(report.contains("user carol:"), "{}", report);
+    assert_eq!(sqlite.read_user("carol", true).await.unwrap().password_hash_hex, "existing");
+    assert!(sqlite.read_user_claims("carol").await.unwrap().claims.is_empty());
+
+    // Everything was imported already, so nothing changes.
+    let report = String::from_utf8_lossy(&import(&resources, &url).stdout).into_owned();
+    assert!(report.contains("Imported 0 users, 0 claims and 0 resources."), "{}", report);
+    assert_imported(&files, &sqlite, &["alice", "bob"]).await;
+    assert_eq!(count(&sqlite, "user_auth").await, 3);
+    assert_eq!(count(&sqlite, "user_claims").await, 4);
+}
+
+#[tokio::test]
+async fn imports_keys_tokens_and_counters() {
+    let dir = tempfile::tempdir().unwrap();
+    let resources = dir.path().join("resources");
+    populate(&resources);
+    let url = format!("sqlite://{}", dir.path().join("import.sqlite").display());
+    let files = FileStore::new(&resources);
+    files.prepare().await.unwrap();
+
+    let keys = vec![
+        defs::SigningKey { kid: "old".to_owned(), secret_hex: "01".repeat(32), public_hex: "02".repeat(32),
+            created: 100, expires: Some(300) },
+        defs::SigningKey { kid: "new".to_owned(), secret_hex: "03".repeat(32), public_hex: "04".repeat(32),
+            created: 200, expires: None },
+    ];
+    files.write_signing_keys(&keys).await.unwrap();
+    let salt_secret_hex = "05".repeat(32);
+    files.add_salt_secret(&salt_secret_hex).await.unwrap();
+    let revoked = defs::RevokedToken { jti: "revoked-jti".to_owned(), expires: 500 };
+    files.revoke_token(&revoked).await.unwrap();
+    let refresh_token = defs::RefreshToken { token_hash: "ab".repeat(32), family_id: "family".to_owned(),
+        user_hex: "alice".to_owned(), issued: 100, expires: 500, used: false };
+    files.write_refresh_token(&refresh_token).await.unwrap();
+    let attempts = defs::LoginAttempts { failures: 3, last_failure: 100, locked_until: 0 };
+    files.write_login_attempts("ip:::1", &attempts).await.unwrap();
+    let mail_token = defs::MailToken { token_hash: "cd".repeat(32), user_hex: "bob".to_owned(),
+        purpose: defs::MailTokenPurpose::PasswordReset, contact: "bob@example.com".to_owned(), issued: 100, expires: 500 };
+    files.write_mail_token(&mail_token).await.unwrap();
+
+    let report = String::from_utf8_lossy(&import(&resources, &url).stdout).into_owned();
+    assert!(report.contains("Imported 2 signing keys, 1 salt secrets, 1 revoked tokens, 1 refresh tokens, 1 login counters and 1 mail tokens."),
+            "{}", report);
+
+    let sqlite = SqliteStore::connect(&url).await.unwrap();
+    let imported_keys = sqlite.read_signing_keys().await.unwrap();
+    assert_eq!(serde_json::to_value(&imported_keys).unwrap(), serde_json::to_value(&keys).unwrap());
+    assert_eq!(sqlite.read_salt_secret().await.unwrap(), Some(salt_secret_hex));
+    assert_eq!(sqlite.read_revoked_tokens().await.unwrap(), vec![revoked]);
+    assert_eq!(sqlite.read_refresh_token(&refresh_token.token_hash).await.unwrap(), refresh_token);
+    assert_eq!(sqlite.read_login_attempts("ip:::1").await.unwrap(), attempts);
+    assert_eq!(sqlite.read_mail_token(&mail_token.token_hash).await.unwrap(), mail_token);
+
+    // The imported keys are not added a second time next to themselves.
+    let report = String::from_utf8_lossy(&import(&resources, &url).stdout).into_owned();
+    assert!(report.contains("Imported 0 signing keys, 0 salt secrets, 0 revoked tokens, 0 refresh tokens, 0 login counters and 0 mail tokens."),
+            "{}", report);
+    assert!(report.contains("signing key new: the database already has signing keys"), "{}", report);
+    assert!(report.contains("salt secret salt_secret.json:"), "{}", report);
+    assert_eq!(sqlite.read_signing_keys().await.unwrap().len(), 2);
+}
@@ -0,0 +1,78 @@
+mod common;
+
+use std::convert::TryFrom;
+
+use ed25519_dalek::{Keypair, PublicKey, SecretKey};
+use serde_json::{json, Value};
+
+use tiauth::jwt::{self, JwtHeader};
+
+// Test vectors from RFC 8037, appendix A.
+const RFC8037_D: &str = "nWGxne_9WmC6hEr0kuwsxERJxWl7MmkZcDusAxyuf2A";
+const RFC8037_X: &str = "11qYAYKxCrfVS_7TyWQHOg7hcvPapiMlrwIaaPcHURo";
+const RFC8037_THUMBPRINT: &str = "kPrK_qmxVWaYVA9wwBF6Iuo3vVzz7TxHCTwXBygrS4k";
+const RFC8037_PAYLOAD: &str = "Example of Ed25519 signing";
+const RFC8037_JWS: &str = "eyJhbGciOiJFZERTQSJ9.RXhhbXBsZSBvZiBFZDI1NTE5IHNpZ25pbmc.\
+hgyY0il_MGCjP0JzlnLWG1PPOt7-09PGcvMg3AIbQR6dWbhijcNR4ki4iylGjg5BhVsPt9g7sVvpAr_MuM0KAg";
+
+fn rfc8037_keypair() -> Keypair {
+    let secret = SecretKey::from_bytes(&base64_url::decode(RFC8037_D).unwrap()).unwrap();
+    let public = PublicKey::from(&secret);
+    assert_eq!(public.as_bytes().to_vec(), base64_url::decode(RFC8037_X).unwrap());
+
+    Keypair { secret, public }
+}
+
+fn decode_segment(segment: &str) -> Value {
+    serde_json::from_slice(&base64_url::decode(segment).unwrap()).unwrap()
+}
+
+#[test]
+fn key_id_is_rfc7638_thumbprint() {
+    assert_eq!(jwt::key_id(&rfc8037_keypair().public), RFC8037_THUMBPRINT);
+}
+
+#[test]
+fn signature_matches_rfc8037_vector() {
+    let header = JwtHeader { alg: jwt::ALG_EDDSA.to_owned(), typ: None, kid: None };
+    let header_json = serde_json::to_vec(&header).unwrap();
+    assert_eq!(header_json, br#"{"alg":"EdDSA"}"#);
+
+    let jws = jwt::sign_bytes(&rfc8037_keypair(), &header_json, RFC8037_PAYLOAD.as_bytes());
+    assert_eq!(jws, RFC8037_JWS);
+}
+
+#[test]
+fn signature_verifies_with_other_implementation() {
+    let keypair = rfc8037_keypair();
+    let header = JwtHeader::eddsa(jwt::key_id(&keypair.public));
+    let token = jwt::sign(&keypair, &header, &json!({"sub": "someone"})).unwrap();
+
+    let segments: Vec<&str> = token.split('.').collect();
+    assert_eq!(segments.len(), 3);
+    let signature = base64_url::decode(segments[2]).unwrap();
+    let signature = ed25519_zebra::Signature::try_from(signature.as_slice()).unwrap();
+    let verification_key = ed25519_zebra::VerificationKey::try_from(keypair.public.to_bytes()).unwrap();
+    let message = format!("{}.{}", segments[0], segments[1]);
+    verification_key.verify(&signature, message.as_bytes()).unwrap();
+}
+
+#[tokio::test]
+async fn login_token_has_compact_eddsa_header() {
+    let test_store = common::file_store().await;
+    let store = &test_store;
+    common::register(store, "someone").await;
+    let token = common::login(store, "someone").await;
+
+    let segments: Vec<&str> = token.split('.').collect();
+    assert_eq!(segments.len(), 3);
+
+    let (kid, _) = store.keyring.signing_key().unwrap();
+    let header_json = base64_url::decode(segments[0]).unwrap();
+    let expected = format!(r#"{{"alg":"EdDSA","typ":"JWT","kid":"{}"}}"#, kid);
+    assert_eq!(String::from_utf8(header_json).unwrap(), expected);
+
+    let payload_json = String::from_utf8(base64_url::decode(segments[1]).unwrap()).unwrap();
+    assert!(!payload_json.contains('\n') && !payload_json.contains(": "), "{}", payload_json);
+    assert_eq!(decode_segment(segments[1])["sub"], "someone");
+}
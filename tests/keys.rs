@@ -0,0 +1,141 @@
+mod common;
+
+use serde_json::json;
+use warp::http::StatusCode;
+
+use tiauth::config::Config;
+use tiauth::jwt::{self, JwtHeader};
+use tiauth::keys::Keyring;
+
+fn token_kid(token: &str) -> String {
+    let header: JwtHeader = serde_json::from_slice(&base64_url::decode(token.split('.').next().unwrap()).unwrap()).unwrap();
+    header.kid.unwrap()
+}
+
+#[tokio::test]
+async fn keys_are_persisted() {
+    let test_store = common::file_store().await;
+    let store = &test_store;
+    let (kid, _) = store.keyring.signing_key().unwrap();
+
+    let keyring = Keyring::load(store).await.unwrap();
+    assert!(!keyring.rotate_if_due(store, &store.config, common::unix_now()).await.unwrap());
+    assert_eq!(keyring.signing_key().unwrap().0, kid);
+}
+
+#[tokio::test]
+async fn tokens_of_retired_keys_are_valid_until_they_expire() {
+    let test_store = common::file_store().await;
+    let store = &test_store;
+    common::register(store, "owner").await;
+    let old_jwt = common::login(store, "owner").await;
+
+    let now = common::unix_now();
+    store.keyring.rotate(store, &store.config, now).await.unwrap();
+    let new_jwt = common::login(store, "owner").await;
+    assert_ne!(token_kid(&old_jwt), token_kid(&new_jwt));
+    let (status, body) = common::new_claim(store, "owner", &old_jwt, "res1").await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+
+    // Once every token it signed has expired, the retired key is dropped.
+    let after_expiry = now + store.config.token_lifetime + store.config.clock_skew;
+    assert!(!store.keyring.rotate_if_due(store, &store.config, after_expiry).await.unwrap());
+    assert_eq!(store.read_signing_keys().await.unwrap().len(), 1);
+    let (status, body) = common::new_claim(store, "owner", &old_jwt, "res2").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST, "{}", body);
+    let (status, body) = common::new_claim(store, "owner", &new_jwt, "res2").await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+}
+
+#[tokio::test]
+async fn active_key_is_rotated_when_due() {
+    let test_store = common::file_store().await;
+    let store = &test_store;
+    let (kid, _) = store.keyring.signing_key().unwrap();
+
+    let due = common::unix_now() + store.config.key_rotation;
+    assert!(store.keyring.rotate_if_due(store, &store.config, due).await.unwrap());
+    assert_ne!(store.keyring.signing_key().unwrap().0, kid);
+    let keys = store.read_signing_keys().await.unwrap();
+    assert_eq!(keys.len(), 2);
+    assert_eq!(keys.iter().filter(|key| key.expires.is_none()).count(), 1);
+}
+
+#[tokio::test]
+async fn user_keys_are_used_in_compatibility_mode() {
+    let test_store = common::file_store_with(Config { sign_with_user_keys: true, ..common::test_config() }).await;
+    let store = &test_store;
+    common::register(store, "owner").await;
+    let user_keypair = common::user_keypair(store, "owner").await;
+
+    let jwt = common::login(store, "owner").await;
+    assert_eq!(token_kid(&jwt), jwt::key_id(&user_keypair.public));
+    let (status, body) = common::new_claim(store, "owner", &jwt, "res1").await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+
+    // Tokens signed with the server keys are still accepted.
+    let now = common::unix_now();
+    let server_jwt = common::sign_jwt(store, &json!({
+        "iss": store.config.issuer, "iat": now, "nbf": now, "exp": now + 60,
+        "jti": "a", "sub": "owner", "tipten_auth": {"claims": []}
+    }));
+    let (status, body) = common::new_claim(store, "owner", &server_jwt, "res2").await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+}
+
+#[tokio::test]
+async fn user_keys_are_accepted_while_signing_with_server_keys() {
+    let test_store = common::file_store_with(Config { accept_user_keys: true, ..common::test_config() }).await;
+    let store = &test_store;
+    common::register(store, "owner").await;
+    let user_keypair = common::user_keypair(store, "owner").await;
+
+    let jwt = common::login(store, "owner").await;
+    assert_eq!(token_kid(&jwt), store.keyring.signing_key().unwrap().0);
+
+    // Tokens signed with the key of the user before the switch are still accepted.
+    let now = common::unix_now();
+    let user_jwt = jwt::sign(&user_keypair, &JwtHeader::eddsa(jwt::key_id(&user_keypair.public)), &json!({
+        "iss": store.config.issuer, "iat": now, "nbf": now, "exp": now + 60,
+        "jti": "a", "sub": "owner", "tipten_auth": {"claims": []}
+    })).unwrap();
+    let (status, body) = common::new_claim(store, "owner", &user_jwt, "res1").await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+}
+
+#[tokio::test]
+async fn jwks_publishes_valid_keys() {
+    let test_store = common::file_store().await;
+    let store = &test_store;
+    let (old_kid, old_keypair) = store.keyring.signing_key().unwrap();
+    store.keyring.rotate(store, &store.config, common::unix_now()).await.unwrap();
+    let (new_kid, _) = store.keyring.signing_key().unwrap();
+
+    let res = warp::test::request()
+        .method("GET")
+        .path("/.well-known/jwks.json")
+        .reply(&common::routes(store)).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.headers()["cache-control"], "public, max-age=300");
+
+    let jwk_set: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+    let keys = jwk_set["keys"].as_array().unwrap();
+    assert_eq!(keys.len(), 2);
+    let old_jwk = keys.iter().find(|jwk| jwk["kid"] == old_kid.as_str()).unwrap();
+    assert_eq!(old_jwk, &json!({
+        "kty": "OKP",
+        "crv": "Ed25519",
+        "x": base64_url::encode(old_keypair.public.as_bytes()),
+        "kid": old_kid,
+        "alg": "EdDSA",
+        "use": "sig"
+    }));
+    assert!(keys.iter().any(|jwk| jwk["kid"] == new_kid.as_str()));
+
+    // Keys that expired are no longer published.
+    let after_expiry = common::unix_now() + store.config.token_lifetime + store.config.clock_skew;
+    store.keyring.rotate_if_due(store, &store.config, after_expiry).await.unwrap();
+    let jwk_set = store.keyring.jwk_set(after_expiry);
+    assert_eq!(jwk_set.keys.len(), 1);
+    assert_eq!(jwk_set.keys[0].kid, new_kid);
+}
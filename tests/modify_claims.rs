@@ -0,0 +1,66 @@
+mod common;
+
+use serde_json::{json, Value};
+use warp::http::StatusCode;
+
+
+async fn modify_all_or_nothing(store: &common::TestStore, jwt: &str, targets: &[(&str, u16)]) -> (StatusCode, Value) {
+    let targets: Vec<Value> = targets.iter().map(|(target_user_hex, target_permission)| json!({
+        "target_user_hex": target_user_hex,
+        "target_permission": target_permission
+    })).collect();
+
+    common::post(store, "/modify_claims", &json!({
+        "origin": "owner",
+        "anphd_id": "res",
+        "writer": "owner",
+        "uuid": "uuid-res",
+        "targets": targets,
+        "jwt": jwt,
+        "all_or_nothing": true
+    })).await
+}
+
+#[tokio::test]
+async fn all_or_nothing_writes_nothing_if_a_target_is_invalid() {
+    let test_store = common::file_store().await;
+    let store = &test_store;
+    common::register(store, "owner").await;
+    common::register(store, "target").await;
+    let jwt = common::login(store, "owner").await;
+    let (status, _) = common::new_claim(store, "owner", &jwt, "res").await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (status, body) = modify_all_or_nothing(store, &jwt, &[("target", 4500), ("missing", 4500)]).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST, "{}", body);
+    let message = body["message"].as_str().unwrap();
+    assert!(message.contains(r#""committed":false"#), "{}", message);
+    assert!(message.contains("Target user claims do not exist"), "{}", message);
+
+    let claims = store.read_user_claims("target").await.unwrap();
+    assert!(claims.claims.is_empty());
+}
+
+#[tokio::test]
+async fn all_or_nothing_writes_all_valid_targets() {
+    let test_store = common::file_store().await;
+    let store = &test_store;
+    common::register(store, "owner").await;
+    common::register(store, "target1").await;
+    common::register(store, "target2").await;
+    let jwt = common::login(store, "owner").await;
+    let (status, _) = common::new_claim(store, "owner", &jwt, "res").await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (status, body) = modify_all_or_nothing(store, &jwt, &[("target1", 4500), ("target2", 2500)]).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["committed"], true);
+    assert_eq!(body["targets"].as_array().unwrap().len(), 2);
+    assert!(body["targets"].as_array().unwrap().iter().all(|t| t["written"] == true));
+
+    for (target, permission) in &[("target1", 4500), ("target2", 2500)] {
+        let claims = store.read_user_claims(target).await.unwrap();
+        assert_eq!(claims.claims.len(), 1);
+        assert_eq!(claims.claims[0].permission, *permission);
+    }
+}
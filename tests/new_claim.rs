@@ -0,0 +1,105 @@
+mod common;
+
+use serde_json::json;
+use sqlx::{Connection, SqliteConnection};
+use warp::http::StatusCode;
+
+use tiauth::defs::{Tiauth, UserClaim};
+
+#[tokio::test]
+async fn duplicate_resource_is_rejected_without_writing() {
+    let test_store = common::file_store().await;
+    let store = &test_store;
+    common::register(store, "owner").await;
+    let jwt = common::login(store, "owner").await;
+    let (status, _) = common::new_claim(store, "owner", &jwt, "res").await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (status, body) = common::post(store, "/new_claim", &json!({
+        "origin": "owner",
+        "anphd_id": "res",
+        "uuid": "other-uuid",
+        "writer_permission": 0,
+        "target_user_hex": "owner",
+        "target_permission": 2500,
+        "jwt": jwt
+    })).await;
+    assert_eq!(status, StatusCode::CONFLICT, "{}", body);
+
+    let resources = store.read_resources().await.unwrap();
+    assert_eq!(resources.resources, vec!["owner:res".to_owned()]);
+    let claims = store.read_user_claims("owner").await.unwrap();
+    assert_eq!(claims.claims.len(), 1);
+    assert_eq!(claims.claims[0].uuid, "uuid-res");
+    assert_eq!(claims.claims[0].permission, 0);
+}
+
+#[tokio::test]
+async fn existing_owner_claim_is_rejected_without_writing() {
+    let test_store = common::file_store().await;
+    let store = &test_store;
+    common::register(store, "owner").await;
+    let jwt = common::login(store, "owner").await;
+    // A claim to a resource that is missing from the resource index
+    store.write_user_claims("owner", &Tiauth {
+        claims: vec![UserClaim {
+            origin: "owner".to_owned(),
+            anphd_id: "res".to_owned(),
+            uuid: "old-uuid".to_owned(),
+            permission: 1500
+        }]
+    }).await.unwrap();
+
+    let (status, body) = common::new_claim(store, "owner", &jwt, "res").await;
+    assert_eq!(status, StatusCode::CONFLICT, "{}", body);
+
+    let resources = store.read_resources().await.unwrap();
+    assert!(resources.resources.is_empty());
+    let claims = store.read_user_claims("owner").await.unwrap();
+    assert_eq!(claims.claims.len(), 1);
+    assert_eq!(claims.claims[0].uuid, "old-uuid");
+    assert_eq!(claims.claims[0].permission, 1500);
+}
+
+/// Makes every statement of `kind` on `table` of the SQLite store fail.
+async fn fail_writes(store: &common::TestStore, table: &str, kind: &str) {
+    let url = format!("sqlite://{}", store.dir().join("test.sqlite").display());
+    let mut conn = SqliteConnection::connect(&url).await.unwrap();
+    sqlx::query(&format!("CREATE TRIGGER fail_{0}_{1} BEFORE {1} ON {0} BEGIN SELECT RAISE(ABORT, 'failing'); END",
+                         table, kind))
+        .execute(&mut conn).await.unwrap();
+}
+
+#[tokio::test]
+async fn failed_resource_write_leaves_claims_unchanged() {
+    let test_store = common::sqlite_store().await;
+    let store = &test_store;
+    common::register(store, "owner").await;
+    let jwt = common::login(store, "owner").await;
+    let (status, _) = common::new_claim(store, "owner", &jwt, "res").await;
+    assert_eq!(status, StatusCode::OK);
+    fail_writes(store, "resources", "INSERT").await;
+
+    let (status, body) = common::new_claim(store, "owner", &jwt, "other").await;
+    assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR, "{}", body);
+
+    let claims = store.read_user_claims("owner").await.unwrap();
+    assert_eq!(claims.claims.len(), 1);
+    assert_eq!(claims.claims[0].anphd_id, "res");
+    assert_eq!(store.read_resources().await.unwrap().resources, vec!["owner:res".to_owned()]);
+}
+
+#[tokio::test]
+async fn failed_claims_write_removes_resource() {
+    let test_store = common::sqlite_store().await;
+    let store = &test_store;
+    common::register(store, "owner").await;
+    let jwt = common::login(store, "owner").await;
+    fail_writes(store, "user_claims", "INSERT").await;
+
+    let (status, body) = common::new_claim(store, "owner", &jwt, "res").await;
+    assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR, "{}", body);
+
+    assert!(store.read_user_claims("owner").await.unwrap().claims.is_empty());
+    assert!(store.read_resources().await.unwrap().resources.is_empty());
+}
@@ -0,0 +1,99 @@
+mod common;
+
+use std::sync::Arc;
+
+use serde_json::json;
+use warp::http::StatusCode;
+
+use tiauth::password;
+
+const USER: &str = "password_user";
+
+async fn stored_hash(store: &common::TestStore) -> String {
+    store.read_user(USER, true).await.unwrap().password_hash_hex
+}
+
+#[tokio::test]
+async fn register_stores_argon2id_hash() {
+    let store = common::file_store().await;
+    common::register(&store, USER).await;
+
+    let stored = stored_hash(&store).await;
+    assert!(stored.starts_with("$argon2id$v=19$m=1024,t=1,p=1$"), "{}", stored);
+    assert!(!stored.contains(common::PASSWORD_HASH_HEX));
+    common::login(&store, USER).await;
+}
+
+#[tokio::test]
+async fn wrong_password_is_rejected() {
+    let store = common::file_store().await;
+    common::register(&store, USER).await;
+
+    let (status, _) = common::post(&store, "/login", &json!({
+        "user_hex": USER,
+        "password_hash_hex": "00".repeat(32)
+    })).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn legacy_record_is_rehashed_on_login() {
+    let store = common::file_store().await;
+    common::register(&store, USER).await;
+    store.write_user_password(USER, common::PASSWORD_HASH_HEX).await.unwrap();
+
+    common::login(&store, USER).await;
+    let stored = stored_hash(&store).await;
+    assert!(stored.starts_with("$argon2id$"), "{}", stored);
+    common::login(&store, USER).await;
+}
+
+#[tokio::test]
+async fn changed_parameters_are_rehashed_on_login() {
+    let store = common::file_store().await;
+    common::register(&store, USER).await;
+    let old_config = Arc::new(tiauth::config::Config { argon2_memory_kib: 2048, ..common::test_config() });
+    let old_hash = password::hash(&old_config, common::PASSWORD_HASH_HEX).await.unwrap();
+    store.write_user_password(USER, &old_hash).await.unwrap();
+
+    common::login(&store, USER).await;
+    let stored = stored_hash(&store).await;
+    assert_ne!(stored, old_hash);
+    assert!(stored.starts_with("$argon2id$v=19$m=1024,t=1,p=1$"), "{}", stored);
+}
+
+#[tokio::test]
+async fn unknown_user_and_wrong_password_get_same_response() {
+    let store = common::file_store().await;
+    common::register(&store, USER).await;
+
+    let wrong_password = common::post(&store, "/login", &json!({
+        "user_hex": USER,
+        "password_hash_hex": "00".repeat(32)
+    })).await;
+    let unknown_user = common::post(&store, "/login", &json!({
+        "user_hex": "unknown_user",
+        "password_hash_hex": common::PASSWORD_HASH_HEX
+    })).await;
+    assert_eq!(wrong_password.0, StatusCode::BAD_REQUEST);
+    assert_eq!(wrong_password, unknown_user);
+}
+
+#[tokio::test]
+async fn wrong_password_for_legacy_record_is_rejected_like_unknown_user() {
+    let store = common::file_store().await;
+    common::register(&store, USER).await;
+    store.write_user_password(USER, common::PASSWORD_HASH_HEX).await.unwrap();
+
+    let wrong_password = common::post(&store, "/login", &json!({
+        "user_hex": USER,
+        "password_hash_hex": "00".repeat(32)
+    })).await;
+    let unknown_user = common::post(&store, "/login", &json!({
+        "user_hex": "unknown_user",
+        "password_hash_hex": "00".repeat(32)
+    })).await;
+    assert_eq!(wrong_password.0, StatusCode::BAD_REQUEST);
+    assert_eq!(wrong_password, unknown_user);
+    assert_eq!(stored_hash(&store).await, common::PASSWORD_HASH_HEX);
+}
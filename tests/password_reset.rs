@@ -0,0 +1,236 @@
+mod common;
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use serde_json::{json, Value};
+use warp::http::StatusCode;
+
+use tiauth::config::Config;
+use tiauth::defs::{MailToken, MailTokenPurpose};
+use tiauth::refresh;
+
+const USER: &str = "reset_user";
+const CONTACT: &str = "reset_user@example.com";
+const NEW_PASSWORD_HASH_HEX: &str = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+const NEW_SALT_HEX: &str = "0123456789abcdef0123456789abcdef";
+
+async fn set_contact(store: &common::TestStore, contact: &str) -> StatusCode {
+    let jwt = common::login(store, USER).await;
+    let (status, _) = common::post(store, "/contact", &json!({ "user_hex": USER, "jwt": jwt, "contact": contact })).await;
+
+    status
+}
+
+/// Registers the user with a verified contact address, after which one mail was sent.
+async fn user_with_contact(store: &common::TestStore) {
+    common::register(store, USER).await;
+    assert_eq!(set_contact(store, CONTACT).await, StatusCode::OK);
+    let token = common::mail_token(&store.mails(1).await[0]);
+    let (status, _) = common::post(store, "/contact/verify", &json!({ "token": token })).await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+async fn request_reset(store: &common::TestStore, user_hex: &str) -> (StatusCode, Value) {
+    common::post(store, "/password_reset/request", &json!({ "user_hex": user_hex })).await
+}
+
+async fn confirm_reset(store: &common::TestStore, token: &str) -> StatusCode {
+    let (status, _) = common::post(store, "/password_reset/confirm", &json!({
+        "token": token,
+        "new_password_hash_hex": NEW_PASSWORD_HASH_HEX,
+        "new_salt_hex": NEW_SALT_HEX
+    })).await;
+
+    status
+}
+
+#[tokio::test]
+async fn contact_must_be_verified() {
+    let store = common::file_store().await;
+    common::register(&store, USER).await;
+    assert_eq!(set_contact(&store, "not an address").await, StatusCode::BAD_REQUEST);
+    assert_eq!(set_contact(&store, CONTACT).await, StatusCode::OK);
+
+    let mails = store.mails(1).await;
+    assert_eq!(mails[0].to, CONTACT);
+    // The address is only stored once it is verified.
+    let save_user = store.read_user(USER, false).await.unwrap();
+    assert_eq!(save_user.contact, None);
+    assert!(!save_user.contact_verified);
+
+    let (status, _) = request_reset(&store, USER).await;
+    assert_eq!(status, StatusCode::OK);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(store.mails(1).await.len(), 1);
+
+    let (status, _) = common::post(&store, "/contact/verify", &json!({ "token": common::mail_token(&mails[0]) })).await;
+    assert_eq!(status, StatusCode::OK);
+    let save_user = store.read_user(USER, false).await.unwrap();
+    assert_eq!(save_user.contact.as_deref(), Some(CONTACT));
+    assert!(save_user.contact_verified);
+}
+
+#[tokio::test]
+async fn reset_sets_new_credentials() {
+    let store = common::file_store().await;
+    user_with_contact(&store).await;
+    let jwt = common::login(&store, USER).await;
+    // Token times are in seconds, only tokens of earlier seconds are rejected.
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+
+    let (status, _) = request_reset(&store, USER).await;
+    assert_eq!(status, StatusCode::OK);
+    let mail = store.mails(2).await.pop().unwrap();
+    assert_eq!(mail.to, CONTACT);
+    let token = common::mail_token(&mail);
+    assert_eq!(confirm_reset(&store, &token).await, StatusCode::OK);
+    assert_eq!(confirm_reset(&store, &token).await, StatusCode::UNAUTHORIZED);
+
+    assert_eq!(store.read_user(USER, false).await.unwrap().salt_hex, NEW_SALT_HEX);
+    let (status, _) = common::new_claim(&store, USER, &jwt, "old_session").await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = common::post(&store, "/login", &json!({
+        "user_hex": USER,
+        "password_hash_hex": NEW_PASSWORD_HASH_HEX
+    })).await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn unknown_user_gets_same_reply() {
+    let store = common::file_store().await;
+    common::register(&store, USER).await;
+
+    let known = request_reset(&store, USER).await;
+    let unknown = request_reset(&store, "unknown_reset_user").await;
+    assert_eq!(known, unknown);
+    assert_eq!(known.0, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn unverified_contact_keeps_verified_one() {
+    let store = common::file_store().await;
+    user_with_contact(&store).await;
+
+    assert_eq!(set_contact(&store, "typo@example.com").await, StatusCode::OK);
+    store.mails(2).await;
+    let save_user = store.read_user(USER, false).await.unwrap();
+    assert_eq!(save_user.contact.as_deref(), Some(CONTACT));
+    assert!(save_user.contact_verified);
+
+    request_reset(&store, USER).await;
+    let mail = store.mails(3).await.pop().unwrap();
+    assert_eq!(mail.to, CONTACT);
+    assert_eq!(confirm_reset(&store, &common::mail_token(&mail)).await, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn token_is_bound_to_contact() {
+    let store = common::file_store().await;
+    user_with_contact(&store).await;
+    request_reset(&store, USER).await;
+    let token = common::mail_token(&store.mails(2).await[1]);
+
+    assert_eq!(set_contact(&store, "other@example.com").await, StatusCode::OK);
+    let verify_token = common::mail_token(&store.mails(3).await[2]);
+    let (status, _) = common::post(&store, "/contact/verify", &json!({ "token": verify_token })).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(confirm_reset(&store, &token).await, StatusCode::UNAUTHORIZED);
+    assert_eq!(confirm_reset(&store, "unknown token").await, StatusCode::UNAUTHORIZED);
+    assert_eq!(store.read_user(USER, false).await.unwrap().salt_hex, common::SALT_HEX);
+}
+
+#[tokio::test]
+async fn expired_token_is_rejected() {
+    let store = common::file_store_with(Config { mail_token_lifetime: 0, ..common::test_config() }).await;
+    common::register(&store, USER).await;
+    assert_eq!(set_contact(&store, CONTACT).await, StatusCode::OK);
+    let token = common::mail_token(&store.mails(1).await[0]);
+
+    let (status, body) = common::post(&store, "/contact/verify", &json!({ "token": token })).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert!(body["message"].as_str().unwrap().starts_with("Expired Reject"));
+    assert!(!store.read_user(USER, false).await.unwrap().contact_verified);
+    assert_eq!(store.prune_mail_tokens(common::unix_now()).await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn no_mailer_disables_requests() {
+    let store = common::file_store().await;
+    common::register(&store, USER).await;
+    let jwt = common::login(&store, USER).await;
+    let routes = tiauth::routes(store.config.clone(), store.store.clone(), store.keyring.clone(),
+                                store.salt_secret.clone(), store.admin.clone(), store.clients.clone(), None);
+
+    for (path, body) in [
+        ("/contact", json!({ "user_hex": USER, "jwt": jwt, "contact": CONTACT })),
+        ("/password_reset/request", json!({ "user_hex": USER }))
+    ] {
+        let res = warp::test::request()
+            .method("POST")
+            .path(path)
+            .json(&body)
+            .reply(&routes).await;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+    assert_eq!(store.read_user(USER, false).await.unwrap().contact, None);
+}
+
+async fn request_reset_from(store: &common::TestStore, remote: &str, user_hex: &str) -> StatusCode {
+    warp::test::request()
+        .method("POST")
+        .path("/password_reset/request")
+        .remote_addr(remote.parse::<SocketAddr>().unwrap())
+        .json(&json!({ "user_hex": user_hex }))
+        .reply(&common::routes(store)).await
+        .status()
+}
+
+#[tokio::test]
+async fn requests_are_throttled_per_address() {
+    let store = common::file_store_with(Config { login_backoff: 60, ..common::test_config() }).await;
+
+    assert_eq!(request_reset_from(&store, "192.0.2.1:1234", "unknown_reset_user").await, StatusCode::OK);
+    assert_eq!(request_reset_from(&store, "192.0.2.1:1234", USER).await, StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(request_reset_from(&store, "192.0.2.2:1234", USER).await, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn mails_are_throttled_per_user() {
+    let store = common::file_store_with(Config { login_backoff: 60, ..common::test_config() }).await;
+    user_with_contact(&store).await;
+
+    assert_eq!(request_reset_from(&store, "192.0.2.1:1234", USER).await, StatusCode::OK);
+    store.mails(2).await;
+    // The reply is the same, but no further mail is sent.
+    assert_eq!(request_reset_from(&store, "192.0.2.2:1234", USER).await, StatusCode::OK);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(store.mails(2).await.len(), 2);
+}
+
+#[tokio::test]
+async fn token_of_same_second_as_password_change_is_rejected() {
+    let store = common::file_store().await;
+    user_with_contact(&store).await;
+    request_reset(&store, USER).await;
+    let token = common::mail_token(&store.mails(2).await[1]);
+    assert_eq!(confirm_reset(&store, &token).await, StatusCode::OK);
+    let changed = store.read_user(USER, false).await.unwrap().password_changed.unwrap();
+
+    let token = "same second token";
+    store.write_mail_token(&MailToken {
+        token_hash: refresh::token_hash(token),
+        user_hex: USER.to_owned(),
+        purpose: MailTokenPurpose::PasswordReset,
+        contact: CONTACT.to_owned(),
+        issued: changed,
+        expires: changed + 3600
+    }).await.unwrap();
+    assert_eq!(confirm_reset(&store, token).await, StatusCode::UNAUTHORIZED);
+
+    // A token requested right after the change is dated after it.
+    request_reset(&store, USER).await;
+    let token = common::mail_token(&store.mails(3).await[2]);
+    assert_eq!(confirm_reset(&store, &token).await, StatusCode::OK);
+}
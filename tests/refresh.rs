@@ -0,0 +1,77 @@
+mod common;
+
+use serde_json::{json, Value};
+use warp::http::StatusCode;
+
+use tiauth::config::Config;
+use tiauth::refresh;
+
+const USER: &str = "refresh_user";
+
+async fn login(store: &common::TestStore) -> Value {
+    let (status, body) = common::post(store, "/login", &json!({
+        "user_hex": USER,
+        "password_hash_hex": common::PASSWORD_HASH_HEX
+    })).await;
+    assert_eq!(status, StatusCode::OK);
+
+    body
+}
+
+async fn refresh(store: &common::TestStore, refresh_token: &Value) -> (StatusCode, Value) {
+    common::post(store, "/token/refresh", &json!({ "refresh_token": refresh_token })).await
+}
+
+#[tokio::test]
+async fn refresh_rotates_token() {
+    let store = common::file_store().await;
+    common::register(&store, USER).await;
+    let tokens = login(&store).await;
+
+    let (status, refreshed) = refresh(&store, &tokens["refresh_token"]).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_ne!(refreshed["refresh_token"], tokens["refresh_token"]);
+    assert_ne!(refreshed["jwt"], tokens["jwt"]);
+
+    let old = store.read_refresh_token(&refresh::token_hash(tokens["refresh_token"].as_str().unwrap())).await.unwrap();
+    let new = store.read_refresh_token(&refresh::token_hash(refreshed["refresh_token"].as_str().unwrap())).await.unwrap();
+    assert!(old.used);
+    assert!(!new.used);
+    assert_eq!(old.family_id, new.family_id);
+
+    let (status, _) = common::new_claim(&store, USER, refreshed["jwt"].as_str().unwrap(), "refreshed").await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn reused_token_revokes_family() {
+    let store = common::file_store().await;
+    common::register(&store, USER).await;
+    let tokens = login(&store).await;
+    let other_login = login(&store).await;
+
+    let (_, refreshed) = refresh(&store, &tokens["refresh_token"]).await;
+    let (status, _) = refresh(&store, &tokens["refresh_token"]).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let (status, _) = refresh(&store, &refreshed["refresh_token"]).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = refresh(&store, &other_login["refresh_token"]).await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn expired_or_unknown_token_is_rejected() {
+    let store = common::file_store_with(Config { refresh_token_lifetime: 0, ..common::test_config() }).await;
+    common::register(&store, USER).await;
+    let tokens = login(&store).await;
+
+    let (status, body) = refresh(&store, &tokens["refresh_token"]).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert!(body["message"].as_str().unwrap().starts_with("Expired Reject"), "{}", body);
+
+    let (status, _) = refresh(&store, &json!("not a refresh token")).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    assert_eq!(store.prune_refresh_tokens(common::unix_now()).await.unwrap(), 1);
+}
@@ -0,0 +1,34 @@
+mod common;
+
+use serde_json::Value;
+use warp::http::StatusCode;
+
+#[tokio::test]
+async fn warp_rejections_get_cors_replies() {
+    let store = common::file_store().await;
+
+    let res = warp::test::request()
+        .method("GET")
+        .path("/login")
+        .header("origin", "https://example.com")
+        .reply(&common::routes(&store)).await;
+
+    assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED);
+    assert_eq!(res.headers()["access-control-allow-origin"], "https://example.com");
+    let body: Value = serde_json::from_slice(res.body()).unwrap();
+    assert_eq!(body["code"], 405);
+}
+
+#[tokio::test]
+async fn malformed_body_is_bad_request() {
+    let store = common::file_store().await;
+
+    let res = warp::test::request()
+        .method("POST")
+        .path("/login")
+        .header("content-type", "application/json")
+        .body("{")
+        .reply(&common::routes(&store)).await;
+
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+}
@@ -0,0 +1,119 @@
+mod common;
+
+use serde_json::{json, Value};
+use warp::http::StatusCode;
+
+use tiauth::defs::RevokedToken;
+use tiauth::files::FileStore;
+use tiauth::jwt;
+use tiauth::store::Store;
+
+const USER: &str = "revoke_user";
+
+fn jti(jwt: &str) -> String {
+    let (_, payload, _) = jwt::split(jwt).unwrap();
+    let claims: Value = serde_json::from_slice(&base64_url::decode(payload).unwrap()).unwrap();
+
+    claims["jti"].as_str().unwrap().to_owned()
+}
+
+async fn admin_revoke(store: &common::TestStore, authorization: Option<&str>, body: &Value) -> StatusCode {
+    let mut request = warp::test::request()
+        .method("POST")
+        .path("/revoke")
+        .json(body);
+    if let Some(authorization) = authorization {
+        request = request.header("authorization", authorization);
+    }
+
+    request.reply(&common::routes(store)).await.status()
+}
+
+#[tokio::test]
+async fn logout_revokes_token() {
+    let store = common::file_store().await;
+    common::register(&store, USER).await;
+    let jwt = common::login(&store, USER).await;
+    let other_jwt = common::login(&store, USER).await;
+
+    let (status, _) = common::post(&store, "/logout", &json!({ "user_hex": USER, "jwt": jwt })).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (status, body) = common::new_claim(&store, USER, &jwt, "logged_out").await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert!(body["message"].as_str().unwrap().contains("revoked"), "{}", body);
+    let (status, _) = common::new_claim(&store, USER, &other_jwt, "logged_in").await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn logout_revokes_refresh_tokens() {
+    let store = common::file_store().await;
+    common::register(&store, USER).await;
+    let (_, tokens) = common::post(&store, "/login", &json!({
+        "user_hex": USER,
+        "password_hash_hex": common::PASSWORD_HASH_HEX
+    })).await;
+
+    let (status, _) = common::post(&store, "/logout", &json!({
+        "user_hex": USER,
+        "jwt": tokens["jwt"],
+        "refresh_token": tokens["refresh_token"]
+    })).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (status, _) = common::post(&store, "/token/refresh", &json!({ "refresh_token": tokens["refresh_token"] })).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn admin_revoke_requires_admin_token() {
+    let store = common::file_store().await;
+    common::register(&store, USER).await;
+    let jwt = common::login(&store, USER).await;
+    let body = json!({ "jti": jti(&jwt) });
+
+    assert_eq!(admin_revoke(&store, None, &body).await, StatusCode::UNAUTHORIZED);
+    assert_eq!(admin_revoke(&store, Some("Bearer wrong-admin-token-0123456789abcdef"), &body).await, StatusCode::UNAUTHORIZED);
+    let (status, _) = common::new_claim(&store, USER, &jwt, "not_revoked").await;
+    assert_eq!(status, StatusCode::OK);
+
+    let authorization = format!("Bearer {}", common::ADMIN_TOKEN);
+    assert_eq!(admin_revoke(&store, Some(&authorization), &body).await, StatusCode::OK);
+    let (status, _) = common::new_claim(&store, USER, &jwt, "revoked").await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn feed_lists_unexpired_revocations() {
+    let store = common::file_store().await;
+    let now = common::unix_now();
+    store.revoke_token(&RevokedToken { jti: "expired".to_owned(), expires: now - 1 }).await.unwrap();
+    store.revoke_token(&RevokedToken { jti: "current".to_owned(), expires: now + 600 }).await.unwrap();
+
+    let res = warp::test::request()
+        .path("/revoked")
+        .reply(&common::routes(&store)).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert!(res.headers()["cache-control"].to_str().unwrap().contains("max-age"));
+    let feed: Value = serde_json::from_slice(res.body()).unwrap();
+    assert_eq!(feed["revoked"], json!([{ "jti": "current", "expires": now + 600 }]));
+
+    assert_eq!(store.prune_revoked_tokens(now).await.unwrap(), 1);
+    assert_eq!(store.read_revoked_tokens().await.unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn cached_revocations_follow_writes() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = FileStore::new(dir.path());
+    let now = common::unix_now();
+
+    assert!(!store.is_token_revoked("token").await.unwrap());
+    store.revoke_token(&RevokedToken { jti: "token".to_owned(), expires: now - 1 }).await.unwrap();
+    assert!(store.is_token_revoked("token").await.unwrap());
+    assert!(FileStore::new(dir.path()).is_token_revoked("token").await.unwrap());
+
+    assert_eq!(store.prune_revoked_tokens(now).await.unwrap(), 1);
+    assert!(!store.is_token_revoked("token").await.unwrap());
+}
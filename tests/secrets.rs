@@ -0,0 +1,128 @@
+mod common;
+
+use std::sync::Arc;
+
+use tiauth::config::Config;
+use tiauth::files::FileStore;
+use tiauth::keys::Keyring;
+use tiauth::salt::SaltSecret;
+use tiauth::secrets::{self, MasterKey, SealedStore, SecretOwner};
+use tiauth::store::{Store, StoreRef};
+
+async fn raw_store(dir: &tempfile::TempDir) -> StoreRef {
+    let store: StoreRef = Arc::new(FileStore::new(dir.path()));
+    store.prepare().await.unwrap();
+
+    store
+}
+
+async fn register(store: &StoreRef, user_hex: &str) {
+    let user_json = serde_json::from_value(serde_json::json!({
+        "user_hex": user_hex,
+        "password_hash_hex": common::PASSWORD_HASH_HEX,
+        "salt_hex": common::SALT_HEX
+    })).unwrap();
+    store.register_user(&user_json, "ab".repeat(32), "cd".repeat(32)).await.unwrap();
+}
+
+async fn sealed_store(raw: &StoreRef, master_key: MasterKey) -> StoreRef {
+    let store: StoreRef = Arc::new(SealedStore::new(raw.clone(), master_key));
+    let keyring = Keyring::load(&store).await.unwrap();
+    keyring.rotate_if_due(&store, &Config::default(), common::unix_now()).await.unwrap();
+
+    store
+}
+
+#[test]
+fn sealed_secret_opens_only_with_its_key() {
+    let master_key = MasterKey::from_hex(&MasterKey::generate_hex()).unwrap();
+    let other_key = MasterKey::from_hex(&MasterKey::generate_hex()).unwrap();
+    let secret_hex = "cd".repeat(32);
+    let owner = SecretOwner::User("someone");
+
+    let sealed = master_key.seal(owner, &secret_hex).unwrap();
+    assert!(secrets::is_sealed(&sealed));
+    assert!(!sealed.contains(&secret_hex));
+    assert_eq!(secrets::sealed_key_id(&sealed), Some(master_key.id()));
+    assert_eq!(master_key.open(owner, &sealed).unwrap(), secret_hex);
+    assert!(other_key.open(owner, &sealed).is_err());
+
+    let rewrapped = master_key.rewrap(&other_key, owner, &sealed).unwrap();
+    assert_eq!(other_key.open(owner, &rewrapped).unwrap(), secret_hex);
+    assert!(master_key.open(owner, &rewrapped).is_err());
+}
+
+#[test]
+fn sealed_secret_opens_only_for_its_owner() {
+    let master_key = MasterKey::from_hex(&MasterKey::generate_hex()).unwrap();
+    let sealed = master_key.seal(SecretOwner::User("someone"), &"cd".repeat(32)).unwrap();
+
+    assert!(master_key.open(SecretOwner::User("someone_else"), &sealed).is_err());
+    assert!(master_key.open(SecretOwner::SigningKey("someone"), &sealed).is_err());
+    assert!(master_key.rewrap(&master_key, SecretOwner::User("someone_else"), &sealed).is_err());
+}
+
+#[tokio::test]
+async fn secrets_are_stored_encrypted() {
+    let dir = tempfile::tempdir().unwrap();
+    let raw = raw_store(&dir).await;
+    let master_key_hex = MasterKey::generate_hex();
+    let store = sealed_store(&raw, MasterKey::from_hex(&master_key_hex).unwrap()).await;
+    register(&store, "someone").await;
+
+    let raw_user = raw.read_user("someone", true).await.unwrap();
+    assert!(secrets::is_sealed(&raw_user.secret_hex));
+    assert_eq!(store.read_user("someone", true).await.unwrap().secret_hex, "cd".repeat(32));
+    let raw_keys = raw.read_signing_keys().await.unwrap();
+    assert!(raw_keys.iter().all(|key| secrets::is_sealed(&key.secret_hex)));
+    let keys = store.read_signing_keys().await.unwrap();
+    assert!(keys.iter().all(|key| key.secret_hex.len() == 64));
+    let salt_secret = SaltSecret::load_or_create(&store).await.unwrap();
+    assert!(secrets::is_sealed(&raw.read_salt_secret().await.unwrap().unwrap()));
+    assert_eq!(SaltSecret::load_or_create(&store).await.unwrap().fake_salt_hex("user"), salt_secret.fake_salt_hex("user"));
+
+    // The server refuses to start without the key or with another one.
+    assert!(secrets::check_secrets(&raw, None).await.is_err());
+    let other_key = MasterKey::from_hex(&MasterKey::generate_hex()).unwrap();
+    assert!(secrets::check_secrets(&raw, Some(&other_key)).await.is_err());
+    let master_key = MasterKey::from_hex(&master_key_hex).unwrap();
+    assert_eq!(secrets::check_secrets(&raw, Some(&master_key)).await.unwrap(), 0);
+
+    // A secret copied to another user in the store does not open.
+    register(&store, "other").await;
+    raw.write_user_secret("other", &raw_user.secret_hex).await.unwrap();
+    assert!(store.read_user("other", true).await.is_err());
+    assert!(store.read_user("someone", true).await.is_ok());
+}
+
+#[tokio::test]
+async fn rotation_encrypts_and_rewraps_secrets() {
+    let dir = tempfile::tempdir().unwrap();
+    let raw = raw_store(&dir).await;
+    register(&raw, "plain").await;
+
+    // Secrets that were stored before a master key was configured are encrypted.
+    let first_key_hex = MasterKey::generate_hex();
+    let first_key = MasterKey::from_hex(&first_key_hex).unwrap();
+    assert_eq!(secrets::check_secrets(&raw, Some(&first_key)).await.unwrap(), 1);
+    assert_eq!(secrets::rotate_master_key(&raw, None, &first_key).await.unwrap(), 1);
+    let store = sealed_store(&raw, first_key).await;
+    register(&store, "sealed").await;
+    let fake_salt_hex = SaltSecret::load_or_create(&store).await.unwrap().fake_salt_hex("user");
+
+    let first_key = MasterKey::from_hex(&first_key_hex).unwrap();
+    let second_key = MasterKey::from_hex(&MasterKey::generate_hex()).unwrap();
+    // Two users, the server signing key and the salt secret.
+    assert_eq!(secrets::rotate_master_key(&raw, Some(&first_key), &second_key).await.unwrap(), 4);
+    // Running it again completes an interrupted rotation, here there is nothing left to do.
+    assert_eq!(secrets::rotate_master_key(&raw, Some(&first_key), &second_key).await.unwrap(), 0);
+
+    assert!(secrets::check_secrets(&raw, Some(&first_key)).await.is_err());
+    assert_eq!(secrets::check_secrets(&raw, Some(&second_key)).await.unwrap(), 0);
+    let store = SealedStore::new(raw.clone(), second_key);
+    assert_eq!(store.read_user("plain", true).await.unwrap().secret_hex, "cd".repeat(32));
+    assert_eq!(store.read_user("sealed", true).await.unwrap().secret_hex, "cd".repeat(32));
+    let store: StoreRef = Arc::new(store);
+    assert!(Keyring::load(&store).await.is_ok());
+    assert_eq!(SaltSecret::load_or_create(&store).await.unwrap().fake_salt_hex("user"), fake_salt_hex);
+}
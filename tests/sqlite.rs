@@ -0,0 +1,98 @@
+mod common;
+
+use serde_json::json;
+use warp::http::StatusCode;
+
+use tiauth::db::SqliteStore;
+use tiauth::defs::{Tiauth, UserClaim};
+use tiauth::store::{StoreError, StoreErrors};
+
+#[tokio::test]
+async fn register_login_and_claims() {
+    let store = common::sqlite_store().await;
+    common::register(&store, "owner").await;
+    common::register(&store, "reader").await;
+    let (status, _) = common::post(&store, "/register", &json!({
+        "user_hex": "owner",
+        "password_hash_hex": common::PASSWORD_HASH_HEX,
+        "salt_hex": common::SALT_HEX
+    })).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+
+    let jwt = common::login(&store, "owner").await;
+    let (status, body) = common::new_claim(&store, "owner", &jwt, "res").await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    let (status, body) = common::modify_claims(&store, "owner", &jwt, "res", &[("reader", 1000)]).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+
+    assert_eq!(store.read_resources().await.unwrap().resources, vec!["owner:res".to_owned()]);
+    let claims = store.read_user_claims("reader").await.unwrap();
+    assert_eq!(claims.claims.len(), 1);
+    assert_eq!((claims.claims[0].uuid.as_str(), claims.claims[0].permission), ("uuid-res", 1000));
+}
+
+#[tokio::test]
+async fn refresh_logout_and_throttle() {
+    let store = common::sqlite_store().await;
+    common::register(&store, "user").await;
+    let (_, tokens) = common::post(&store, "/login", &json!({
+        "user_hex": "user", "password_hash_hex": common::PASSWORD_HASH_HEX
+    })).await;
+
+    let (status, refreshed) = common::post(&store, "/token/refresh", &json!({ "refresh_token": tokens["refresh_token"] })).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = common::post(&store, "/token/refresh", &json!({ "refresh_token": tokens["refresh_token"] })).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = common::post(&store, "/token/refresh", &json!({ "refresh_token": refreshed["refresh_token"] })).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let jwt = refreshed["jwt"].as_str().unwrap();
+    let (status, _) = common::post(&store, "/logout", &json!({ "user_hex": "user", "jwt": jwt })).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = common::new_claim(&store, "user", jwt, "res").await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let (status, _) = common::post(&store, "/login", &json!({ "user_hex": "user", "password_hash_hex": "00".repeat(32) })).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(store.read_login_attempts("user:user").await.unwrap().failures, 1);
+}
+
+#[tokio::test]
+async fn constraint_errors_are_mapped() {
+    let store = common::sqlite_store().await;
+    common::register(&store, "user").await;
+    let claim = |user_hex: &str| (user_hex.to_owned(), Tiauth {
+        claims: vec![UserClaim {
+            origin: "user".to_owned(),
+            anphd_id: "res".to_owned(),
+            uuid: "uuid".to_owned(),
+            permission: 0
+        }]
+    });
+
+    // Foreign key: claims of a user that does not exist, nothing is written.
+    let e = store.write_many_user_claims(&[claim("user"), claim("unknown")]).await.unwrap_err();
+    assert!(matches!(e.error_type, StoreErrors::NonExistent), "{}", e);
+    assert!(store.read_user_claims("user").await.unwrap().claims.is_empty());
+    // Primary key
+    store.add_resource("user:res").await.unwrap();
+    let e = store.add_resource("user:res").await.unwrap_err();
+    assert!(matches!(e.error_type, StoreErrors::AlreadyExists), "{}", e);
+    // Check
+    let e = store.write_user_credentials("user", "hash", "too short", 0, false).await.unwrap_err();
+    assert!(matches!(e.error_type, StoreErrors::Invalid), "{}", e);
+    // Missing rows
+    let e = store.read_user("unknown", false).await.unwrap_err();
+    assert!(matches!(e.error_type, StoreErrors::NonExistent), "{}", e);
+}
+
+#[tokio::test]
+async fn unique_violation_is_already_exists() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = SqliteStore::connect(&format!("sqlite://{}", dir.path().join("unique.sqlite").display())).await.unwrap();
+    sqlx::query("CREATE TABLE t (id INTEGER PRIMARY KEY, name TEXT UNIQUE)").execute(store.pool()).await.unwrap();
+    sqlx::query("INSERT INTO t (name) VALUES ('a')").execute(store.pool()).await.unwrap();
+
+    let e = sqlx::query("INSERT INTO t (name) VALUES ('a')").execute(store.pool()).await.unwrap_err();
+    assert!(matches!(StoreError::from(e).error_type, StoreErrors::AlreadyExists));
+}
@@ -0,0 +1,153 @@
+mod common;
+
+use std::net::SocketAddr;
+
+use serde_json::{json, Value};
+use warp::http::StatusCode;
+
+use tiauth::config::Config;
+use tiauth::throttle;
+
+const USER: &str = "throttle_user";
+const WRONG_PASSWORD_HASH_HEX: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+async fn throttled_store(login_backoff: u64) -> common::TestStore {
+    let store = common::file_store_with(Config {
+        login_max_failures: 3,
+        login_max_failures_ip: 4,
+        login_backoff,
+        ..common::test_config()
+    }).await;
+    common::register(&store, USER).await;
+
+    store
+}
+
+async fn login_from(store: &common::TestStore, remote: &str, user_hex: &str, password_hash_hex: &str) -> (StatusCode, Value) {
+    let res = warp::test::request()
+        .method("POST")
+        .path("/login")
+        .remote_addr(remote.parse::<SocketAddr>().unwrap())
+        .json(&json!({
+            "user_hex": user_hex,
+            "password_hash_hex": password_hash_hex
+        }))
+        .reply(&common::routes(store)).await;
+
+    (res.status(), serde_json::from_slice(res.body()).unwrap_or(Value::Null))
+}
+
+async fn login(store: &common::TestStore, password_hash_hex: &str) -> StatusCode {
+    login_from(store, "192.0.2.1:1234", USER, password_hash_hex).await.0
+}
+
+#[tokio::test]
+async fn user_is_locked_after_max_failures() {
+    let store = throttled_store(0).await;
+
+    for _ in 0..3 {
+        assert_eq!(login(&store, WRONG_PASSWORD_HASH_HEX).await, StatusCode::BAD_REQUEST);
+    }
+    let (status, body) = login_from(&store, "192.0.2.1:1234", USER, common::PASSWORD_HASH_HEX).await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    assert!(body["message"].as_str().unwrap().starts_with("Too Many Requests Reject"), "{}", body);
+}
+
+#[tokio::test]
+async fn failure_requires_backoff() {
+    let store = throttled_store(60).await;
+
+    assert_eq!(login(&store, WRONG_PASSWORD_HASH_HEX).await, StatusCode::BAD_REQUEST);
+    assert_eq!(login(&store, common::PASSWORD_HASH_HEX).await, StatusCode::TOO_MANY_REQUESTS);
+}
+
+#[tokio::test]
+async fn throttled_reply_has_retry_after() {
+    let store = throttled_store(60).await;
+
+    assert_eq!(login(&store, WRONG_PASSWORD_HASH_HEX).await, StatusCode::BAD_REQUEST);
+    let res = warp::test::request()
+        .method("POST")
+        .path("/login")
+        .remote_addr("192.0.2.1:1234".parse::<SocketAddr>().unwrap())
+        .json(&json!({
+            "user_hex": USER,
+            "password_hash_hex": common::PASSWORD_HASH_HEX
+        }))
+        .reply(&common::routes(&store)).await;
+
+    assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+    let retry_after: u64 = res.headers()["retry-after"].to_str().unwrap().parse().unwrap();
+    assert!(retry_after > 0 && retry_after <= 60, "{}", retry_after);
+}
+
+#[tokio::test]
+async fn success_clears_user_failures() {
+    let store = throttled_store(0).await;
+
+    for _ in 0..2 {
+        assert_eq!(login(&store, WRONG_PASSWORD_HASH_HEX).await, StatusCode::BAD_REQUEST);
+    }
+    assert_eq!(login(&store, common::PASSWORD_HASH_HEX).await, StatusCode::OK);
+    assert_eq!(login(&store, WRONG_PASSWORD_HASH_HEX).await, StatusCode::BAD_REQUEST);
+    assert_eq!(login(&store, common::PASSWORD_HASH_HEX).await, StatusCode::OK);
+
+    let attempts = store.read_login_attempts(&throttle::user_key(USER)).await.unwrap();
+    assert_eq!(attempts.failures, 0);
+}
+
+#[tokio::test]
+async fn address_is_locked_across_users() {
+    let store = throttled_store(0).await;
+
+    for i in 0..4 {
+        let (status, _) = login_from(&store, "192.0.2.2:1234", &format!("other_user_{}", i), WRONG_PASSWORD_HASH_HEX).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+    let (status, _) = login_from(&store, "192.0.2.2:1234", USER, common::PASSWORD_HASH_HEX).await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    let (status, _) = login_from(&store, "192.0.2.3:1234", USER, common::PASSWORD_HASH_HEX).await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn unlock_lifts_lockout() {
+    let store = throttled_store(0).await;
+
+    for _ in 0..3 {
+        assert_eq!(login(&store, WRONG_PASSWORD_HASH_HEX).await, StatusCode::BAD_REQUEST);
+    }
+    assert_eq!(login(&store, common::PASSWORD_HASH_HEX).await, StatusCode::TOO_MANY_REQUESTS);
+
+    throttle::unlock(&store, &throttle::user_key(USER)).await.unwrap();
+    assert_eq!(login(&store, common::PASSWORD_HASH_HEX).await, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn unknown_users_are_only_counted_per_address() {
+    let store = throttled_store(0).await;
+
+    let (status, _) = login_from(&store, "192.0.2.4:1234", "unknown_user", WRONG_PASSWORD_HASH_HEX).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let attempts = store.read_login_attempts(&throttle::user_key("unknown_user")).await.unwrap();
+    assert_eq!(attempts, Default::default());
+    let attempts = store.read_login_attempts(&throttle::ip_key("192.0.2.4".parse().unwrap())).await.unwrap();
+    assert_eq!(attempts.failures, 1);
+}
+
+#[tokio::test]
+async fn forgotten_counters_are_pruned() {
+    let store = throttled_store(0).await;
+    for _ in 0..3 {
+        assert_eq!(login(&store, WRONG_PASSWORD_HASH_HEX).await, StatusCode::BAD_REQUEST);
+    }
+    assert_eq!(login(&store, common::PASSWORD_HASH_HEX).await, StatusCode::TOO_MANY_REQUESTS);
+
+    let now = common::unix_now();
+    let lockout = store.config.login_lockout;
+    assert_eq!(store.prune_login_attempts(now, lockout).await.unwrap(), 0);
+    // The counters of the user and of its address.
+    assert_eq!(store.prune_login_attempts(now + lockout, lockout).await.unwrap(), 2);
+    let attempts = store.read_login_attempts(&throttle::user_key(USER)).await.unwrap();
+    assert_eq!(attempts, Default::default());
+}
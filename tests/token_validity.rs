@@ -0,0 +1,67 @@
+mod common;
+
+use serde_json::json;
+use warp::http::StatusCode;
+
+/// Signs the payload made for the issuer of the store and creates a claim with it.
+async fn new_claim_with<F>(payload: F) -> (StatusCode, serde_json::Value)
+    where F: FnOnce(&str) -> serde_json::Value {
+    let test_store = common::file_store().await;
+    let store = &test_store;
+    common::register(store, "owner").await;
+    let jwt = common::sign_jwt(store, &payload(&store.config.issuer));
+
+    common::new_claim(store, "owner", &jwt, "res").await
+}
+
+#[tokio::test]
+async fn login_token_is_accepted() {
+    let test_store = common::file_store().await;
+    let store = &test_store;
+    common::register(store, "owner").await;
+    let jwt = common::login(store, "owner").await;
+
+    let (status, body) = common::new_claim(store, "owner", &jwt, "res").await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+}
+
+#[tokio::test]
+async fn expired_token_is_rejected() {
+    let now = common::unix_now();
+    let (status, body) = new_claim_with(|iss| json!({
+        "iss": iss, "iat": now - 7200, "nbf": now - 7200, "exp": now - 3600,
+        "jti": "a", "sub": "owner", "tipten_auth": {"claims": []}
+    })).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert!(body["message"].as_str().unwrap().starts_with("Expired Reject"), "{}", body);
+}
+
+#[tokio::test]
+async fn token_not_valid_yet_is_rejected() {
+    let now = common::unix_now();
+    let (status, body) = new_claim_with(|iss| json!({
+        "iss": iss, "iat": now, "nbf": now + 3600, "exp": now + 7200,
+        "jti": "a", "sub": "owner", "tipten_auth": {"claims": []}
+    })).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert!(body["message"].as_str().unwrap().starts_with("Expired Reject"), "{}", body);
+}
+
+#[tokio::test]
+async fn clock_skew_is_tolerated() {
+    let now = common::unix_now();
+    let (status, body) = new_claim_with(|iss| json!({
+        "iss": iss, "iat": now - 30, "nbf": now + 10, "exp": now - 10,
+        "jti": "a", "sub": "owner", "tipten_auth": {"claims": []}
+    })).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+}
+
+#[tokio::test]
+async fn token_without_expiry_is_rejected() {
+    let now = common::unix_now();
+    let (status, _) = new_claim_with(|iss| json!({
+        "iss": iss, "iat": now, "sub": "owner", "tipten_auth": {"claims": []}
+    })).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
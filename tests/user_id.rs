@@ -0,0 +1,47 @@
+mod common;
+
+use serde_json::json;
+use warp::http::StatusCode;
+
+#[tokio::test]
+async fn register_rejects_path_traversal() {
+    let test_store = common::file_store().await;
+    let store = &test_store;
+
+    for user_hex in &["../escape", "a/b", "", "a.json"] {
+        let (status, body) = common::post(store, "/register", &json!({
+            "user_hex": user_hex,
+            "password_hash_hex": common::PASSWORD_HASH_HEX,
+            "salt_hex": common::SALT_HEX
+        })).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{}", user_hex);
+        assert!(body["message"].as_str().unwrap().starts_with("Decode External Reject"), "{}", body);
+    }
+}
+
+#[tokio::test]
+async fn user_salt_rejects_path_traversal() {
+    let test_store = common::file_store().await;
+
+    let res = warp::test::request()
+        .path("/user_salt?user_hex=..%2Fusers%2Fowner")
+        .reply(&common::routes(&test_store)).await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+    let res = warp::test::request()
+        .path(&format!("/user_salt?user_hex={}", "a".repeat(1000)))
+        .reply(&common::routes(&test_store)).await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn valid_user_hex_is_accepted() {
+    let test_store = common::file_store().await;
+    let store = &test_store;
+    common::register(store, "61-61-70").await;
+
+    let res = warp::test::request()
+        .path("/user_salt?user_hex=61-61-70")
+        .reply(&common::routes(store)).await;
+    assert_eq!(res.status(), StatusCode::OK);
+}
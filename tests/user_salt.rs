@@ -0,0 +1,54 @@
+mod common;
+
+use warp::http::StatusCode;
+
+use tiauth::salt::SaltSecret;
+
+async fn user_salt(store: &common::TestStore, user_hex: &str) -> String {
+    let (status, body) = common::get(store, &format!("/user_salt?user_hex={}", user_hex)).await;
+    assert_eq!(status, StatusCode::OK);
+
+    body["salt_hex"].as_str().unwrap().to_owned()
+}
+
+#[tokio::test]
+async fn known_user_gets_its_salt() {
+    let store = common::file_store().await;
+    common::register(&store, "salt_user").await;
+
+    assert_eq!(user_salt(&store, "salt_user").await, common::SALT_HEX);
+}
+
+#[tokio::test]
+async fn unknown_user_gets_stable_pseudo_salt() {
+    let store = common::file_store().await;
+
+    let salt_hex = user_salt(&store, "unknown_user").await;
+    assert_eq!(salt_hex.len(), common::SALT_HEX.len());
+    assert!(hex::decode(&salt_hex).is_ok());
+    assert_eq!(user_salt(&store, "unknown_user").await, salt_hex);
+    assert_ne!(user_salt(&store, "other_unknown_user").await, salt_hex);
+    assert_eq!(salt_hex, store.salt_secret.fake_salt_hex("unknown_user"));
+}
+
+#[test]
+fn pseudo_salt_depends_on_secret() {
+    let secret_hex = SaltSecret::generate_hex();
+    let salt_secret = SaltSecret::from_hex(&secret_hex).unwrap();
+
+    assert_eq!(SaltSecret::from_hex(&secret_hex).unwrap().fake_salt_hex("user"), salt_secret.fake_salt_hex("user"));
+    assert_ne!(SaltSecret::random().fake_salt_hex("user"), salt_secret.fake_salt_hex("user"));
+    assert!(SaltSecret::from_hex("abcd").is_err());
+}
+
+#[tokio::test]
+async fn generated_secret_survives_restarts() {
+    for store in [common::file_store().await, common::sqlite_store().await] {
+        let config = common::test_config();
+        let salt_secret = tiauth::prepare_salt_secret(&config, &store.store).await.unwrap();
+        assert!(store.read_salt_secret().await.unwrap().is_some());
+
+        let restarted = tiauth::prepare_salt_secret(&config, &store.store).await.unwrap();
+        assert_eq!(restarted.fake_salt_hex("unknown_user"), salt_secret.fake_salt_hex("unknown_user"));
+    }
+}
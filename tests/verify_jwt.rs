@@ -0,0 +1,105 @@
+mod common;
+
+use serde_json::{json, Value};
+use warp::http::StatusCode;
+
+use tiauth::config::Config;
+use tiauth::jwt::{self, JwtHeader};
+
+fn payload(store: &common::TestStore, sub: &str) -> Value {
+    let now = common::unix_now();
+    json!({
+        "iss": store.config.issuer, "iat": now, "nbf": now, "exp": now + 60,
+        "jti": "a", "sub": sub, "tipten_auth": {"claims": []}
+    })
+}
+
+async fn assert_rejected(jwt: &str, store: &common::TestStore, expected: StatusCode) {
+    let (status, body) = common::new_claim(store, "owner", jwt, "res").await;
+    assert_eq!(status, expected, "{}", body);
+    assert!(store.read_resources().await.unwrap().resources.is_empty());
+}
+
+#[tokio::test]
+async fn extra_segments_are_rejected() {
+    let test_store = common::file_store().await;
+    let store = &test_store;
+    common::register(store, "owner").await;
+    let jwt = common::login(store, "owner").await;
+
+    let (header, rest) = jwt.split_once('.').unwrap();
+    let (payload, signature) = rest.split_once('.').unwrap();
+    // The signature was previously taken as the concatenation of every segment after the second.
+    let (first, second) = signature.split_at(signature.len() / 2);
+    assert_rejected(&format!("{}.{}.{}.{}", header, payload, first, second), store, StatusCode::BAD_REQUEST).await;
+    assert_rejected(&format!("{}.{}", header, payload), store, StatusCode::BAD_REQUEST).await;
+}
+
+#[tokio::test]
+async fn other_algorithms_are_rejected() {
+    let test_store = common::file_store().await;
+    let store = &test_store;
+    common::register(store, "owner").await;
+    let (kid, keypair) = store.keyring.signing_key().unwrap();
+
+    for alg in &["none", "HS256", "ED25519"] {
+        let header = JwtHeader { alg: alg.to_string(), typ: Some("JWT".to_owned()), kid: Some(kid.clone()) };
+        let token = jwt::sign(&keypair, &header, &payload(store, "owner")).unwrap();
+        assert_rejected(&token, store, StatusCode::BAD_REQUEST).await;
+    }
+}
+
+#[tokio::test]
+async fn unknown_key_id_is_rejected() {
+    let test_store = common::file_store().await;
+    let store = &test_store;
+    common::register(store, "owner").await;
+    let (_, keypair) = store.keyring.signing_key().unwrap();
+    let user_keypair = common::user_keypair(store, "owner").await;
+
+    // Keys of users are only accepted in the compatibility mode.
+    for kid in &[None, Some("unknown".to_owned()), Some(jwt::key_id(&user_keypair.public))] {
+        let header = JwtHeader { alg: jwt::ALG_EDDSA.to_owned(), typ: Some("JWT".to_owned()), kid: kid.clone() };
+        let token = jwt::sign(&keypair, &header, &payload(store, "owner")).unwrap();
+        assert_rejected(&token, store, StatusCode::BAD_REQUEST).await;
+    }
+}
+
+#[tokio::test]
+async fn token_about_other_user_is_rejected() {
+    let test_store = common::file_store().await;
+    let store = &test_store;
+    common::register(store, "owner").await;
+    let token = common::sign_jwt(store, &payload(store, "other"));
+
+    assert_rejected(&token, store, StatusCode::UNAUTHORIZED).await;
+}
+
+#[tokio::test]
+async fn tokens_carry_configured_issuer() {
+    let test_store = common::file_store_with(Config { issuer: "issuer.example".to_owned(), ..common::test_config() }).await;
+    let store = &test_store;
+    common::register(store, "owner").await;
+    let jwt = common::login(store, "owner").await;
+
+    let (_, payload, _) = jwt::split(&jwt).unwrap();
+    let claims: Value = serde_json::from_slice(&base64_url::decode(payload).unwrap()).unwrap();
+    assert_eq!(claims["iss"], "issuer.example");
+    let (status, body) = common::new_claim(store, "owner", &jwt, "res").await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+}
+
+#[tokio::test]
+async fn foreign_issuer_is_rejected() {
+    let test_store = common::file_store().await;
+    let store = &test_store;
+    common::register(store, "owner").await;
+    let mut foreign = payload(store, "owner");
+    foreign["iss"] = json!("issuer.example");
+    let token = common::sign_jwt(store, &foreign);
+
+    let (status, body) = common::new_claim(store, "owner", &token, "res").await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED, "{}", body);
+    assert!(body["message"].as_str().unwrap().contains("another issuer"), "{}", body);
+    assert!(store.read_resources().await.unwrap().resources.is_empty());
+}